@@ -0,0 +1,108 @@
+//! Publish and consume the same corpus with every compression mode and compare them.
+//!
+//! Needs an nsqd on 127.0.0.1:4150. Run with `cargo run --release --example compression_bench`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use nsq_in_rust::{
+    command::Command,
+    config::{Compress, Config},
+    conn::{ConnectionStats, Response},
+    Connection,
+    Error,
+    Producer,
+};
+
+const MESSAGES: usize = 10_000;
+const MAX_IN_FLIGHT: u64 = 100;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt::init();
+    let addr: SocketAddr = "127.0.0.1:4150".parse().unwrap();
+    let corpus = corpus();
+    let payload_bytes: usize = corpus.iter().map(Vec::len).sum();
+
+    let modes = [
+        ("none", Compress::Disabled),
+        ("snappy", Compress::Snappy),
+        ("deflate-1", Compress::Deflate{ level: 1 }),
+        ("deflate-6", Compress::Deflate{ level: 6 }),
+        ("deflate-9", Compress::Deflate{ level: 9 }),
+    ];
+
+    println!("{} messages, {} payload bytes", MESSAGES, payload_bytes);
+    println!("{:<10} {:>12} {:>12} {:>10} {:>10} {:>12} {:>12}",
+        "mode", "pub msg/s", "pub MB/s", "p50 ms", "p99 ms", "sub msg/s", "sub wire MB");
+    for (name, compress) in modes {
+        let config = Config { compress, ..Default::default() };
+        let topic = format!("compression_bench_{}", name);
+
+        let (elapsed, mut latencies) = publish(addr, &config, &topic, &corpus).await?;
+        let (consumed, stats) = consume(addr, &config, &topic).await?;
+
+        latencies.sort();
+        println!("{:<10} {:>12.0} {:>12.2} {:>10.2} {:>10.2} {:>12.0} {:>12.2}",
+            name,
+            MESSAGES as f64 / elapsed.as_secs_f64(),
+            payload_bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0,
+            percentile(&latencies, 0.50),
+            percentile(&latencies, 0.99),
+            MESSAGES as f64 / consumed.as_secs_f64(),
+            stats.bytes_read as f64 / 1_000_000.0,
+        );
+    }
+    Ok(())
+}
+
+/// Log-like lines, compressible but not trivially so.
+fn corpus() -> Vec<Vec<u8>> {
+    (0..MESSAGES).map(|i| {
+        format!(
+            r#"{{"seq":{},"level":"info","service":"checkout-{}","msg":"order {} processed","items":{},"total":{}.{:02}}}"#,
+            i, i % 7, i * 7919 % 100_003, i % 13, i * 31 % 1000, i % 100,
+        ).into_bytes()
+    }).collect()
+}
+
+async fn publish(addr: SocketAddr, config: &Config, topic: &str, corpus: &[Vec<u8>])
+    -> Result<(Duration, Vec<Duration>), Error>
+{
+    let mut producer = Producer::connect(addr, config).await?;
+    let mut latencies = Vec::with_capacity(corpus.len());
+    let start = Instant::now();
+    for msg in corpus {
+        let sent = Instant::now();
+        producer.publish(topic, msg.clone()).await?;
+        latencies.push(sent.elapsed());
+    }
+    Ok((start.elapsed(), latencies))
+}
+
+/// Time to receive the corpus, and the stats of the connection it was received on.
+async fn consume(addr: SocketAddr, config: &Config, topic: &str) -> Result<(Duration, ConnectionStats), Error> {
+    let mut conn = Connection::connect(addr, config).await?;
+    conn.send(Command::Sub(topic.to_string(), "bench".to_string())).await?;
+    conn.send(Command::Rdy(MAX_IN_FLIGHT)).await?;
+
+    let start = Instant::now();
+    let mut received = 0;
+    while received < MESSAGES {
+        match conn.receive().await? {
+            Response::Msg(msg) => {
+                conn.send(Command::Fin(msg.message_id)).await?;
+                received += 1;
+            }
+            Response::Ok | Response::Invalid { .. } => continue,
+            Response::Err(e) => return Err(e.into()),
+            Response::CloseWait => return Err(Error::UnknownError("closed by nsqd".into())),
+        }
+    }
+    Ok((start.elapsed(), conn.stats()))
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    let idx = ((sorted.len() as f64 * p) as usize).min(sorted.len() - 1);
+    sorted[idx].as_secs_f64() * 1000.0
+}
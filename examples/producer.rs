@@ -1,5 +1,3 @@
-use std::net::SocketAddr;
-
 use futures::prelude::*;
 use nsq_in_rust::{
     Producer,
@@ -21,7 +19,7 @@ async fn main() -> Result<(), Error> {
         compress: Compress::Deflate{ level: 6 },
         ..Default::default()
     };
-    let conn = Connection::connect("127.0.0.1:4150".parse::<SocketAddr>().unwrap(), &config).await?;
+    let conn = Connection::connect("127.0.0.1:4150", &config).await?;
     let mut producer: Producer = conn.into();
     let topic = "foo";
 
@@ -1,21 +1,10 @@
-use std::net::{
-    SocketAddr,
-    ToSocketAddrs,
-};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
 use tracing::{warn, info};
 use futures::{
-    future::{
-        FutureExt,
-        TryFutureExt,
-    },
-    stream::{
-        self,
-        StreamExt,
-        TryStreamExt,
-    }
+    future::FutureExt,
+    stream::StreamExt,
 };
 use tower::{
     Service, ServiceExt, MakeService,
@@ -24,11 +13,12 @@ use tower::{
 use tokio_tower::pipeline::client::Client;
 
 use nsq_in_rust::{
-    config::Config,
+    config::{Config, ToAddrs},
+    discovery::{DiscoveryEvent, DiscoveryPoller},
     Connection,
+    Lookup,
     producer::PublishProducer,
 };
-use nsq_in_rust::{Lookup, lookup::Producer as LookupProducer};
 
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
@@ -36,39 +26,30 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sy
 
     let nsq_config = Arc::new(Config::default());
 
-    // create a lookup `Service`, which can be used to discover the nsqd brokers
-    let mut lookup_service = tower::service_fn(|endpointer| {
-        fetch_topics_match(endpointer, "smart")
-    });
-
-    let lookups = lookup_service.ready().await?
-        .call("http://127.0.0.1:4161").await?;
-
-    info!("Matched topics info: {:?}", lookups);
-
-    let brokers = lookups.iter().map(|p| {
-        p.broadcast_address.parse::<std::net::IpAddr>()
-            .map_err(anyhow::Error::from)
-            .and_then(|addr| (addr, p.tcp_port).try_into().map_err(From::from))
-    })
-    .filter_map(|addr| {
-        match addr {
-            Ok(addr) => Some(addr),
-            Err(e) => {
-                warn!("Broker address is invalid: {:?}", e);
-                None
+    // Discover the nsqd brokers having the topic from nsqlookupd
+    let lookup = Lookup::new("http://127.0.0.1:4161")?;
+    let mut nodes = DiscoveryPoller::new(lookup, "smart", &nsq_config);
+
+    // Take the first broker found, for example.
+    let first_node = async {
+        while let Some(event) = nodes.next().await {
+            match event {
+                Ok(DiscoveryEvent::NodeAdded(node)) => return Some(node),
+                Ok(DiscoveryEvent::NodeRemoved(_)) => {}
+                Err(e) => warn!(error = %e, "Discovery failed, polling again"),
             }
         }
-    }).collect::<Vec<SocketAddr>>();
-
-    info!("Found brokers: {:?}", brokers);
+        None
+    };
+    let node = match tokio::time::timeout(Duration::from_secs(30), first_node).await {
+        Ok(Some(node)) => node,
+        _ => return Err("No brokers found".into()),
+    };
 
-    if brokers.is_empty() {
-        return Err("No brokers found".into());
-    }
+    info!("Found broker: {}", node.tcp_address());
 
-    // Take the first broker from nsqlookupd, for example.
-    let broker = brokers.into_iter().next().unwrap();
+    // The broadcast address is usually a host name, `Connection::connect` resolves it
+    let broker = (node.host, node.tcp_port);
 
     // Make a service for nsqd brokers
     let mut mk_service = tower::service_fn(|(addr, config): (_, Arc<_>)| async move {
@@ -104,7 +85,7 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sy
     for i in 0..100 {
         let mut attempt = 5;
         while attempt > 0 {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
             let rsp = reconnectable.ready()
                 .await
                 .map_err(|e| anyhow::anyhow!("NSQ connection ready error: {:?}", e))?
@@ -130,9 +111,8 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sy
 
 type Request = (String, Vec<u8>);
 type ProducerClient = Client<PublishProducer, anyhow::Error, Request>;
-async fn make_client<S: ToSocketAddrs>(addr: S, nsq_config: &Config) -> Result<ProducerClient, anyhow::Error>
+async fn make_client<S: ToAddrs>(addr: S, nsq_config: &Config) -> Result<ProducerClient, anyhow::Error>
 {
-    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| anyhow::anyhow!("no address"))?;
     let connection = Connection::connect(addr, &nsq_config).await?;
     let producer: PublishProducer = connection.into();
 
@@ -140,24 +120,3 @@ async fn make_client<S: ToSocketAddrs>(addr: S, nsq_config: &Config) -> Result<P
     let client = Client::<_, anyhow::Error, _>::new(producer);
     Ok(client)
 }
-
-async fn fetch_topics_match(endpoint: &str, pattern: &str) -> Result<Vec<LookupProducer>> {
-    let lookup = Lookup::new(endpoint)?;
-    let topics = lookup.topics().await?
-        .topics.into_iter().filter(|topic| {
-            // TODO: use regex or simple pattern
-            topic == pattern
-        });
-
-    stream::iter(topics).then(|topic| async {
-        lookup.lookup(topic).await
-    })
-    .map_ok(|lookup_result| {
-        stream::iter(lookup_result.producers)
-            .map(|p| Ok::<_, nsq_in_rust::Error>(p))
-    })
-    .try_flatten()
-    .try_collect::<Vec<_>>()
-    .map_err(From::from)
-    .await
-}
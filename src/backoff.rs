@@ -0,0 +1,126 @@
+//! Exponential backoff with jitter.
+//!
+//! The jitter comes from a [`JitterRng`], so tests can make delays deterministic and users
+//! can plug in their own entropy source. The default is fastrand's thread-local generator.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Source of randomness for backoff jitter.
+///
+/// Implemented for any `Fn() -> f64` closure, which must return values in `[0, 1)`.
+pub trait JitterRng: Send + Sync {
+    fn next_f64(&self) -> f64;
+}
+
+impl<F> JitterRng for F
+where
+    F: Fn() -> f64 + Send + Sync,
+{
+    fn next_f64(&self) -> f64 {
+        self()
+    }
+}
+
+/// Fast, non-cryptographic thread-local RNG, the default jitter source.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadRng;
+
+impl JitterRng for ThreadRng {
+    fn next_f64(&self) -> f64 {
+        fastrand::f64()
+    }
+}
+
+#[derive(Clone)]
+pub struct Backoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+
+    /// Upper bound of any delay
+    pub max: Duration,
+
+    /// Growth factor between consecutive attempts
+    pub multiplier: f64,
+
+    /// Fraction of the delay which is randomized, `0.0` disables jitter and `1.0` picks
+    /// anything between zero and the full delay
+    pub jitter: f64,
+
+    rng: Arc<dyn JitterRng>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier: 2.0,
+            jitter: 0.0,
+            rng: Arc::new(ThreadRng),
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Use `rng` as the jitter source instead of the thread-local RNG.
+    pub fn with_rng(mut self, rng: impl JitterRng + 'static) -> Self {
+        self.rng = Arc::new(rng);
+        self
+    }
+
+    /// Delay before retry number `attempt`, starting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exp);
+        let delay = delay.min(self.max.as_secs_f64());
+        // Take up to `jitter` of the delay off, never adding to it so `max` holds
+        let delay = delay * (1.0 - self.jitter * self.rng.next_f64());
+        Duration::from_nanos((delay.max(0.0) * 1e9).round() as u64)
+    }
+}
+
+impl fmt::Debug for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backoff")
+            .field("initial", &self.initial)
+            .field("max", &self.max)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::Backoff;
+
+    #[test]
+    fn test_backoff_without_jitter() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let delays = (1..=6).map(|n| backoff.delay(n).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn test_backoff_with_injected_rng() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(0.5)
+            .with_rng(|| 0.5);
+        assert_eq!(backoff.delay(1), Duration::from_millis(75));
+        assert_eq!(backoff.delay(5), Duration::from_millis(750));
+
+        let backoff = backoff.with_rng(|| 0.0);
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,154 @@
+//! Recording of received frames, to reproduce decoding bugs seen in production.
+//!
+//! Set `Config::frame_recorder` and every frame nsqd sends is appended to a file as it came
+//! off the wire, after TLS and decompression, before being decoded. [`replay`] feeds such a
+//! file back through the codec:
+//!
+//! ```no_run
+//! # fn run() -> std::io::Result<()> {
+//! for frame in nsq_in_rust::capture::replay("frames.bin")? {
+//!     println!("{:?}", frame);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Frames are written exactly as received, so the file holds message bodies in clear.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::{BufMut, BytesMut};
+use tracing::warn;
+
+use crate::codec::{Decoder, NsqCodec, NsqFramed};
+use crate::error::Error;
+
+/// Appends received frames to a file, see the [module docs](self).
+///
+/// Clones share the file, so one recorder can capture several connections, their frames
+/// are then interleaved.
+#[derive(Clone)]
+pub struct FrameRecorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl FrameRecorder {
+    /// Record to `path`, appending if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Arc::new(Mutex::new(file)) })
+    }
+
+    /// Write one frame, given without its size prefix.
+    ///
+    /// Failing to record doesn't fail the connection, it is only logged.
+    pub(crate) fn record(&self, frame: &[u8]) {
+        let mut buf = Vec::with_capacity(4 + frame.len());
+        buf.put_u32(frame.len() as u32);
+        buf.extend_from_slice(frame);
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&buf) {
+            warn!("failed to record frame: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for FrameRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FrameRecorder")
+    }
+}
+
+/// Decode the frames recorded in `path`, in order.
+pub fn replay(path: impl AsRef<Path>) -> io::Result<Replay> {
+    Ok(Replay::new(std::fs::read(path)?))
+}
+
+/// Frames decoded from a recording, see [`replay`].
+///
+/// A frame that fails to decode is yielded as an error and replay goes on with the next
+/// one. A recording cut short in the middle of a frame ends with an
+/// `io::ErrorKind::UnexpectedEof` error.
+pub struct Replay {
+    codec: NsqCodec,
+    buf: BytesMut,
+}
+
+impl Replay {
+    pub fn new(recording: impl Into<Vec<u8>>) -> Self {
+        Self {
+            codec: NsqCodec::new(true),
+            buf: BytesMut::from(&recording.into()[..]),
+        }
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<NsqFramed, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.codec.decode(&mut self.buf) {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) if self.buf.is_empty() => None,
+            Ok(None) => {
+                self.buf.clear();
+                Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "recording ends within a frame").into()))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::{replay, FrameRecorder, Replay};
+    use crate::codec::{NsqFramed, RawResponse};
+    use crate::config::Config;
+    use crate::conn::Response;
+    use crate::error::Error;
+    use crate::testing::{self, FRAME_TYPE_RESPONSE};
+    use crate::Connection;
+
+    #[tokio::test]
+    async fn received_frames_are_replayed() {
+        let path = std::env::temp_dir().join(format!("nsq-capture-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = testing::serve(|mut nsqd| async move {
+            nsqd.write_raw(&testing::message_frame(b"0123456789abcdef", 1, b"hello")).await?;
+            Ok(())
+        }).await;
+
+        let mut config = Config::default();
+        config.frame_recorder = Some(FrameRecorder::create(&path).unwrap());
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        assert!(matches!(conn.next().await, Some(Ok(Response::Msg(_)))));
+
+        let frames: Vec<_> = replay(&path).unwrap().collect();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(frames[0], Ok(NsqFramed::Response(RawResponse::Json(_)))));
+        match &frames[1] {
+            Ok(NsqFramed::Message(msg)) => assert_eq!(msg.body(), b"hello"),
+            other => panic!("expected the message, got {:?}", other),
+        }
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn replay_reports_bad_and_truncated_frames() {
+        let mut recording = testing::frame(42, b"?");
+        recording.extend_from_slice(&testing::frame(FRAME_TYPE_RESPONSE, b"OK"));
+        recording.extend_from_slice(&testing::frame(FRAME_TYPE_RESPONSE, b"OK")[..5]);
+        let frames: Vec<_> = Replay::new(recording).collect();
+
+        assert!(matches!(frames[0], Err(Error::MalformedFrame { frame_type: 42, .. })));
+        assert!(matches!(frames[1], Ok(NsqFramed::Response(RawResponse::Ok))));
+        assert!(matches!(&frames[2], Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+        assert_eq!(frames.len(), 3);
+    }
+}
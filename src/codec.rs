@@ -25,46 +25,181 @@
 //                         2-byte
 //                        attempts
 //
+use std::fmt;
 use std::str;
-use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tracing::trace;
 use serde_json::{self, Value as JsonValue};
-use bytes::{Buf, BytesMut, BufMut};
-use tokio_util::codec::LengthDelimitedCodec;
+use bytes::{Buf, Bytes, BytesMut, BufMut};
+use tokio_util::codec::{LengthDelimitedCodec, LengthDelimitedCodecError};
 pub(crate) use tokio_util::codec::{Encoder, Decoder};
 
-use crate::command::{Command, Body};
+use crate::command::{Command, Body, MessageId};
 use crate::error::{Result, Error, NsqError};
+use crate::payload::PayloadCodec;
+#[cfg(feature = "debug-capture")]
+use crate::capture::FrameRecorder;
 
 // const SIZE_LEN: usize = 4;
-// const FRAME_TYPE_LEN: usize = 4;
+const FRAME_TYPE_LEN: usize = 4;
 // const TIMESTAMP_LEN: usize = 8;
 // const ATTEMPTS_LEN: usize = 2;
 const MESSAGE_ID_LEN: usize = 16;
 const MESSAGE_SIZE_LEN: usize = 4;
+const MESSAGE_COUNT_LEN: usize = 4;
+const BODY_SIZE_LEN: usize = 4;
+// timestamp, attempts and message id
+const MESSAGE_HEADER_LEN: usize = 8 + 2 + MESSAGE_ID_LEN;
 
-const FRAME_TYPE_RESPONSE: i32 = 0;
-const FRAME_TYPE_ERROR:    i32 = 1;
-const FRAME_TYPE_MESSAGE:  i32 = 2;
+pub const FRAME_TYPE_RESPONSE: i32 = 0;
+pub const FRAME_TYPE_ERROR:    i32 = 1;
+pub const FRAME_TYPE_MESSAGE:  i32 = 2;
 
 const HEARTBEAT_RESPONSE: &str = "_heartbeat_";
 const OK_RESPONSE: &str = "OK";
 const CLOSE_WAIT: &str = "CLOSE_WAIT";
 
+/// Encodes [`Command`]s and decodes the frames nsqd sends, see the [`protocol`](crate::protocol) module.
 #[derive(Debug)]
 pub struct NsqCodec {
     feature_negotiation: bool,
 
+    // Check received frames against the protocol spec, see `Config::strict_protocol`
+    strict: bool,
+
     // decode nsq response, witch is length delimited protocol
     length_delimited_codec: LengthDelimitedCodec,
+
+    observer: Option<FrameObserver>,
+
+    #[cfg(feature = "debug-capture")]
+    recorder: Option<FrameRecorder>,
+
+    // See `Config::read_buffer_capacity` and `Config::max_read_buffer_capacity`
+    read_buffer_capacity: usize,
+    max_read_buffer_capacity: usize,
+}
+
+/// What went over the wire, see [`FrameObserver`].
+#[derive(Debug)]
+pub enum FrameEvent<'a> {
+    /// A frame was received, `len` bytes including its size
+    Decoded { frame: &'a NsqFramed, len: usize },
+    /// A command was encoded to `len` bytes, before compression
+    Encoded { command: &'static str, len: usize },
+}
+
+/// Callback invoked with every frame decoded and every command encoded on a connection,
+/// including during negotiation, for wire level debugging and metrics.
+///
+/// It runs inline in the codec, so it should be cheap.
+#[derive(Clone)]
+pub struct FrameObserver(Arc<dyn Fn(FrameEvent) + Send + Sync>);
+
+impl FrameObserver {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(FrameEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for FrameObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FrameObserver")
+    }
 }
 
 impl NsqCodec {
     pub fn new(feature_negotiation: bool) -> Self {
         Self {
             feature_negotiation,
+            strict: false,
             length_delimited_codec: LengthDelimitedCodec::new(),
+            observer: None,
+            #[cfg(feature = "debug-capture")]
+            recorder: None,
+            read_buffer_capacity: 0,
+            max_read_buffer_capacity: usize::MAX,
+        }
+    }
+}
+
+impl NsqCodec {
+    /// Only enable once negotiation is over, it rejects the JSON responses negotiation uses.
+    pub(crate) fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub(crate) fn set_observer(&mut self, observer: Option<FrameObserver>) {
+        self.observer = observer;
+    }
+
+    #[cfg(feature = "debug-capture")]
+    pub(crate) fn set_recorder(&mut self, recorder: Option<FrameRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Fail on frames larger than `max` bytes, not counting the size prefix, instead of
+    /// buffering them. 8 MiB by default.
+    pub fn set_max_frame_size(&mut self, max: usize) {
+        self.length_delimited_codec.set_max_frame_length(max);
+    }
+
+    /// Encode `MPUB` of the messages from `msgs` straight into `buf`, without collecting
+    /// them first. The body size and message count are filled in once all are written.
+    pub fn encode_mpub<I, B>(&mut self, topic: &str, msgs: I, buf: &mut BytesMut) -> Result<()>
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        let start = buf.len();
+        buf.put(&b"MPUB "[..]);
+        buf.put(topic.as_bytes());
+        buf.put_u8(b'\n');
+        let size_at = buf.len();
+        buf.put_u32(0);
+        buf.put_u32(0);
+        let mut count = 0u32;
+        for msg in msgs {
+            let msg = msg.as_ref();
+            buf.reserve(MESSAGE_SIZE_LEN + msg.len());
+            buf.put_u32(msg.len() as u32);
+            buf.put(msg);
+            count += 1;
+        }
+        let body_len = buf.len() - size_at - BODY_SIZE_LEN;
+        let size = match u32::try_from(body_len) {
+            Ok(size) => size,
+            Err(_) => {
+                buf.truncate(start);
+                return Err(Error::InvalidCommand(format!("MPUB body of {} bytes is too large", body_len)));
+            }
+        };
+        buf[size_at..size_at + BODY_SIZE_LEN].copy_from_slice(&size.to_be_bytes());
+        buf[size_at + BODY_SIZE_LEN..size_at + BODY_SIZE_LEN + MESSAGE_COUNT_LEN].copy_from_slice(&count.to_be_bytes());
+
+        if let Some(ref observer) = self.observer {
+            (observer.0)(FrameEvent::Encoded { command: "MPUB", len: buf.len() - start });
+        }
+        Ok(())
+    }
+
+    /// Shrink the read buffer back to `capacity` once a frame leaves it holding more than `max`.
+    pub(crate) fn set_read_buffer_capacity(&mut self, capacity: usize, max: usize) {
+        self.read_buffer_capacity = capacity;
+        self.max_read_buffer_capacity = max;
+    }
+
+    /// Drop the allocation a large frame was read into, keeping what follows the frame.
+    fn shrink_read_buffer(&self, frame_len: usize, buf: &mut BytesMut) {
+        if frame_len + buf.capacity() > self.max_read_buffer_capacity {
+            let mut shrunk = BytesMut::with_capacity(self.read_buffer_capacity.max(buf.len()));
+            shrunk.extend_from_slice(buf);
+            *buf = shrunk;
         }
     }
 }
@@ -76,12 +211,74 @@ pub enum NsqFramed {
     Message(NsqMsg),
 }
 
-#[derive(Debug)]
 pub struct NsqMsg {
+    /// When nsqd received the message, in nanoseconds since the Unix epoch
     pub timestamp: u64,
     pub attempts: u16,
-    pub message_id: String,
-    pub body: Vec<u8>,
+    pub message_id: MessageId,
+    pub body: Bytes,
+}
+
+/// Size of a command body, not counting its own 4 byte size prefix.
+///
+/// An MPUB body is the message count followed by each message prefixed with its size.
+fn body_len(body: &Body) -> usize {
+    match body {
+        Body::Binary(bin) => bin.len(),
+        Body::Messages(msgs) => {
+            MESSAGE_COUNT_LEN + msgs.iter().map(|msg| MESSAGE_SIZE_LEN + msg.len()).sum::<usize>()
+        }
+        Body::Json(_) => unreachable!("serialized in place"),
+    }
+}
+
+/// Bodies longer than this are cut short in `Debug` output
+const DEBUG_BODY_LEN: usize = 64;
+
+impl NsqMsg {
+    pub fn id(&self) -> MessageId {
+        self.message_id
+    }
+
+    /// When nsqd received the message.
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.timestamp)
+    }
+
+    /// Number of times the message was delivered, including this one.
+    pub fn attempts(&self) -> u16 {
+        self.attempts
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Bytes {
+        self.body
+    }
+
+    /// Decode the body with `codec`.
+    pub fn decode_as<T, C: PayloadCodec<T>>(&self, codec: &C) -> Result<T> {
+        codec.decode(&self.body)
+    }
+}
+
+impl fmt::Debug for NsqMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.body[..self.body.len().min(DEBUG_BODY_LEN)];
+        let body = if shown.len() < self.body.len() {
+            format!("\"{}\"... ({} bytes)", shown.escape_ascii(), self.body.len())
+        } else {
+            format!("\"{}\"", shown.escape_ascii())
+        };
+        f.debug_struct("NsqMsg")
+            .field("message_id", &self.message_id)
+            .field("timestamp", &self.timestamp)
+            .field("attempts", &self.attempts)
+            .field("body", &format_args!("{}", body))
+            .finish()
+    }
 }
 
 #[derive(Debug)]
@@ -96,39 +293,55 @@ impl Encoder<Command> for NsqCodec {
     type Error = Error;
 
     fn encode(&mut self, cmd: Command, buf: &mut BytesMut) -> Result<()> {
-        let header = cmd.header();
-        buf.reserve(header.len());
-        buf.extend(header.as_bytes());
-
-        if let Some(body) = cmd.body() {
-            match body {
-                Body::Binary(bin) => {
-                    buf.reserve(bin.len() + 4);
-                    buf.put_u32(bin.len() as u32);
-                    buf.put(bin.as_slice());
+        // Everything is written straight into `buf`, which the transport reuses, so encoding
+        // doesn't allocate once the buffer has grown to fit the commands sent
+        let start = buf.len();
+        let command = cmd.name();
+        cmd.write_header(buf);
+        let body_start = buf.len();
+        match cmd.body() {
+            // Serialized in place, its size filled in after
+            Some(Body::Json(json)) => {
+                buf.put_u32(0);
+                if let Err(e) = serde_json::to_writer((&mut *buf).writer(), &json) {
+                    buf.truncate(start);
+                    return Err(e.into());
                 }
-                Body::Messages(msgs) => {
-                    let body_len = msgs.iter().fold(8, |acc, msg| acc + msg.len() + MESSAGE_SIZE_LEN);
-                    buf.reserve(body_len);
-                    buf.put_u32(body_len as u32);
-                    buf.put_u32(msgs.len() as u32);
-                    let _buf = msgs.iter().fold(buf, |buf, msg| {
-                        buf.put_u32(msg.len() as u32);
-                        buf.put(msg.as_slice());
-                        buf
-                    });
-                }
-                Body::Json(json) => {
-                    let body = serde_json::to_string(&json)?;
-                    trace!("send json: {}", &body);
-                    let body = body.as_bytes();
-                    buf.reserve(body.len() + 4);
-                    buf.put_u32(body.len() as u32);
-                    buf.put(body);
+                let body = &buf[body_start + BODY_SIZE_LEN..];
+                trace!("send json: {}", String::from_utf8_lossy(body));
+                let size = body.len() as u32;
+                buf[body_start..body_start + BODY_SIZE_LEN].copy_from_slice(&size.to_be_bytes());
+            }
+            Some(body) => {
+                let body_len = body_len(&body);
+                let size = match u32::try_from(body_len) {
+                    Ok(size) => size,
+                    Err(_) => {
+                        buf.truncate(start);
+                        return Err(Error::InvalidCommand(format!("{} body of {} bytes is too large", command, body_len)));
+                    }
+                };
+                buf.reserve(BODY_SIZE_LEN + body_len);
+                buf.put_u32(size);
+                match body {
+                    Body::Binary(bin) => buf.put(bin.as_slice()),
+                    Body::Messages(msgs) => {
+                        buf.put_u32(msgs.len() as u32);
+                        for msg in &msgs {
+                            buf.put_u32(msg.len() as u32);
+                            buf.put(msg.as_slice());
+                        }
+                    }
+                    Body::Json(_) => unreachable!("matched above"),
                 }
+                debug_assert_eq!(buf.len() - body_start, BODY_SIZE_LEN + body_len);
             }
+            None => {}
         }
 
+        if let Some(ref observer) = self.observer {
+            (observer.0)(FrameEvent::Encoded { command, len: buf.len() - start });
+        }
         Ok(())
     }
 }
@@ -144,33 +357,71 @@ impl Decoder for NsqCodec {
     type Item = NsqFramed;
     type Error = Error;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let mut buf = match self.length_delimited_codec.decode(buf)? {
-            Some(buf) => buf,
-            None => return Ok(None),
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let buf = match self.length_delimited_codec.decode(src) {
+            Ok(Some(buf)) => buf,
+            Ok(None) => return Ok(None),
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<LengthDelimitedCodecError>()) => {
+                // The size prefix is left in place when it is rejected
+                let size = src.get(..4).map_or(0, |mut size| size.get_u32());
+                return Err(Error::ProtocolViolation(format!(
+                    "frame of {} bytes exceeds the maximum of {}",
+                    size, self.length_delimited_codec.max_frame_length(),
+                )));
+            }
+            Err(e) => return Err(e.into()),
         };
 
-        let frame_type = buf.get_i32();
+        #[cfg(feature = "debug-capture")]
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(&buf);
+        }
+
+        // Including the size, which the length delimited codec strips
+        let len = buf.len() + 4;
+        self.shrink_read_buffer(len, src);
+        let frame = buf.freeze();
+        let mut data = frame.clone();
+        let frame_type = if data.len() >= FRAME_TYPE_LEN { data.get_i32() } else { -1 };
 
         let item = match frame_type {
-            FRAME_TYPE_RESPONSE => {
-                NsqFramed::Response(decode_raw_response(buf)?)
-            }
-            FRAME_TYPE_ERROR => {
-                NsqFramed::Error(decode_error(buf)?)
-            }
-            FRAME_TYPE_MESSAGE => {
-                NsqFramed::Message(decode_message(buf)?)
-            }
-            _x => {
-                return Err(io::Error::new(io::ErrorKind::Other, "unknown frame type").into());
-            }
+            FRAME_TYPE_RESPONSE => decode_raw_response(data).map(NsqFramed::Response),
+            FRAME_TYPE_ERROR => decode_error(data).map(NsqFramed::Error),
+            FRAME_TYPE_MESSAGE => decode_message(data).map(NsqFramed::Message),
+            _ => Err(Error::ProtocolViolation("unknown frame type".into())),
         };
+        let item = item.map_err(|e| malformed(frame_type, &frame, e))?;
 
+        if let Some(ref observer) = self.observer {
+            (observer.0)(FrameEvent::Decoded { frame: &item, len });
+        }
+        if self.strict {
+            validate_strict(&item)?;
+        }
         Ok(Some(item))
     }
 }
 
+/// Spec checks of decoded frames, done in strict mode.
+fn validate_strict(item: &NsqFramed) -> Result<()> {
+    let violation = match item {
+        NsqFramed::Response(RawResponse::Json(_)) => {
+            "JSON response outside of negotiation".to_string()
+        }
+        NsqFramed::Message(msg) if !msg.message_id.as_bytes().iter().all(u8::is_ascii_hexdigit) => {
+            format!("message id {} is not hex", msg.message_id)
+        }
+        NsqFramed::Message(msg) if msg.attempts == 0 => {
+            format!("message {} has zero attempts", msg.message_id)
+        }
+        NsqFramed::Error(e) if !e.code().starts_with("E_") => {
+            format!("error frame without an error code: {} {}", e.code(), e.description())
+        }
+        _ => return Ok(()),
+    };
+    Err(Error::ProtocolViolation(violation))
+}
+
 impl Decoder for Box<NsqCodec> {
     type Item = NsqFramed;
     type Error = Error;
@@ -180,22 +431,45 @@ impl Decoder for Box<NsqCodec> {
     }
 }
 
-fn decode_message(mut buf: BytesMut) -> Result<NsqMsg> {
+/// Bytes of the frame shown in decode errors
+const MALFORMED_HEAD_LEN: usize = 32;
+
+/// Wrap a decode error with what nsqd sent, bounded so a huge frame doesn't flood the logs.
+fn malformed(frame_type: i32, frame: &[u8], e: Error) -> Error {
+    let shown = &frame[..frame.len().min(MALFORMED_HEAD_LEN)];
+    let mut head: String = shown.iter().map(|b| format!("{:02x}", b)).collect();
+    if shown.len() < frame.len() {
+        head.push_str("...");
+    }
+    Error::MalformedFrame {
+        frame_type,
+        len: frame.len() + 4,
+        head,
+        source: Box::new(e),
+    }
+}
+
+fn decode_message(mut buf: Bytes) -> Result<NsqMsg> {
+    if buf.len() < MESSAGE_HEADER_LEN {
+        return Err(Error::ProtocolViolation(format!("message frame of {} bytes is too short", buf.len())));
+    }
     let timestamp = buf.get_u64();
     let attempts = buf.get_u16();
-    let buf = buf.as_ref();
-    let (head, body) = buf.split_at(MESSAGE_ID_LEN);
-    let message_id = str::from_utf8(&head)?.to_string();
+    // The body stays in the frame's buffer, only the id is copied
+    let body = buf.split_off(MESSAGE_ID_LEN);
+    let mut id = [0; MESSAGE_ID_LEN];
+    id.copy_from_slice(&buf);
+    let message_id = MessageId::from(id);
 
     Ok(NsqMsg {
         timestamp,
         attempts,
         message_id,
-        body: body.to_vec(),
+        body,
     })
 }
 
-fn decode_error(buf: BytesMut) -> Result<NsqError> {
+fn decode_error(buf: Bytes) -> Result<NsqError> {
     let err = str::from_utf8(buf.as_ref())?;
     let err = match err.find(" ") {
         Some(idx) => {
@@ -209,7 +483,7 @@ fn decode_error(buf: BytesMut) -> Result<NsqError> {
     Ok(err)
 }
 
-fn decode_raw_response(buf: BytesMut) -> Result<RawResponse> {
+fn decode_raw_response(buf: Bytes) -> Result<RawResponse> {
     match str::from_utf8(buf.as_ref())? {
         OK_RESPONSE => Ok(RawResponse::Ok),
         CLOSE_WAIT => Ok(RawResponse::CloseWait),
@@ -217,3 +491,242 @@ fn decode_raw_response(buf: BytesMut) -> Result<RawResponse> {
         body => Ok(RawResponse::Json(serde_json::from_str(body)?)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use bytes::{Buf, Bytes, BytesMut};
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use serde_json::json;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::command::{Command, MessageId};
+    use crate::error::Error;
+    use crate::testing::{self, vectors, FRAME_TYPE_ERROR, FRAME_TYPE_RESPONSE};
+    use super::{NsqCodec, NsqFramed, RawResponse};
+
+    #[test]
+    fn message_accessors_and_debug() {
+        let mut codec = NsqCodec::new(true);
+        let mut frame = testing::message_frame(b"0123456789abcdef", 3, &[b'x'; 100]);
+        // Timestamp of 1.5s after the epoch
+        frame[8..16].copy_from_slice(&1_500_000_000u64.to_be_bytes());
+        let msg = match codec.decode(&mut frame).unwrap() {
+            Some(NsqFramed::Message(msg)) => msg,
+            other => panic!("unexpected frame: {:?}", other),
+        };
+        assert_eq!(msg.id().to_string(), "0123456789abcdef");
+        assert_eq!(msg.timestamp(), UNIX_EPOCH + Duration::from_millis(1500));
+        assert_eq!(msg.attempts(), 3);
+        assert_eq!(msg.body().len(), 100);
+
+        let debug = format!("{:?}", msg);
+        assert!(debug.contains(&format!("\"{}\"... (100 bytes)", "x".repeat(64))), "{}", debug);
+        assert_eq!(msg.into_body().len(), 100);
+    }
+
+    fn encode(cmd: Command) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        NsqCodec::new(true).encode(cmd, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn commands_match_the_spec_vectors() {
+        for (cmd, bytes) in vectors::commands() {
+            let name = format!("{:?}", cmd);
+            assert_eq!(encode(cmd.clone()), bytes, "{}", name);
+            let mut buf = BytesMut::from(bytes);
+            assert_eq!(vectors::decode_command(&mut buf), Ok(Some(cmd)));
+            assert!(buf.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn frames_match_the_spec_vectors() {
+        let mut codec = NsqCodec::new(true);
+        for (name, bytes) in vectors::frames() {
+            let decoded = match codec.decode(&mut BytesMut::from(bytes)) {
+                Ok(Some(NsqFramed::Response(RawResponse::Ok))) => "OK".to_string(),
+                Ok(Some(NsqFramed::Response(RawResponse::Heartbeat))) => "_heartbeat_".to_string(),
+                Ok(Some(NsqFramed::Response(RawResponse::CloseWait))) => "CLOSE_WAIT".to_string(),
+                Ok(Some(NsqFramed::Response(RawResponse::Json(value)))) => value.to_string(),
+                Ok(Some(NsqFramed::Error(e))) => format!("{} {}", e.code(), e.description()),
+                Ok(Some(NsqFramed::Message(msg))) => {
+                    assert_eq!((msg.timestamp(), msg.attempts()), (UNIX_EPOCH + Duration::from_millis(1500), 2));
+                    format!("message {} {}", msg.id(), String::from_utf8_lossy(msg.body()))
+                }
+                other => panic!("{} decoded as {:?}", name, other),
+            };
+            assert_eq!(decoded, name);
+        }
+    }
+
+    fn topic() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9._-]{1,64}"
+    }
+
+    fn body() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..256)
+    }
+
+    fn message_id() -> impl Strategy<Value = MessageId> {
+        "[0-9a-f]{16}".prop_map(|id| id.parse().unwrap())
+    }
+
+    fn command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            Just(Command::Version),
+            btree_map("[a-z_]{1,16}", any::<i64>(), 0..4).prop_map(|settings| Command::Identify(json!(settings))),
+            (topic(), "[a-zA-Z0-9._-]{1,54}(#ephemeral)?").prop_map(|(topic, channel)| Command::Sub(topic, channel)),
+            (topic(), body()).prop_map(|(topic, body)| Command::Pub(topic, body)),
+            (topic(), vec(body(), 0..8)).prop_map(|(topic, bodies)| Command::Mpub(topic, bodies)),
+            (topic(), any::<u64>(), body()).prop_map(|(topic, defer, body)| Command::Dpub(topic, defer, body)),
+            any::<u64>().prop_map(Command::Rdy),
+            message_id().prop_map(Command::Fin),
+            (message_id(), any::<u64>()).prop_map(|(id, delay)| Command::Req(id, delay)),
+            message_id().prop_map(Command::Touch),
+            Just(Command::Close),
+            Just(Command::Nop),
+            any::<String>().prop_map(Command::Auth),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn commands_round_trip(cmds in vec(command(), 1..8)) {
+            let mut codec = NsqCodec::new(true);
+            let mut buf = BytesMut::new();
+            for cmd in cmds.clone() {
+                codec.encode(cmd, &mut buf).unwrap();
+            }
+            for cmd in cmds {
+                prop_assert_eq!(vectors::decode_command(&mut buf), Ok(Some(cmd)));
+            }
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn frames_round_trip(
+            id in "[0-9a-f]{16}",
+            timestamp: u64,
+            attempts: u16,
+            body in body(),
+            error in "E_[A-Z_]{1,20} [a-z]{1,10}( [a-z]{1,10}){0,3}",
+            split: Index,
+        ) {
+            let mut frame = testing::message_frame(id.as_bytes().try_into().unwrap(), attempts, &body);
+            frame[8..16].copy_from_slice(&timestamp.to_be_bytes());
+            let mut wire = frame.clone();
+            wire.extend_from_slice(&testing::frame(FRAME_TYPE_ERROR, error.as_bytes()));
+
+            // Whatever the split, a frame is only decoded once complete
+            let mut codec = NsqCodec::new(true);
+            let mut buf = wire.split_to(split.index(wire.len()));
+            let mut decoded = vec![];
+            while let Some(item) = codec.decode(&mut buf).unwrap() {
+                decoded.push(item);
+            }
+            buf.extend_from_slice(&wire);
+            while let Some(item) = codec.decode(&mut buf).unwrap() {
+                decoded.push(item);
+            }
+            prop_assert!(buf.is_empty());
+
+            match &decoded[..] {
+                [NsqFramed::Message(msg), NsqFramed::Error(e)] => {
+                    prop_assert_eq!(msg.id().to_string(), id);
+                    prop_assert_eq!(msg.timestamp, timestamp);
+                    prop_assert_eq!(msg.attempts(), attempts);
+                    prop_assert_eq!(msg.body(), &body[..]);
+                    prop_assert_eq!(format!("{} {}", e.code(), e.description()), error);
+                }
+                other => prop_assert!(false, "unexpected frames: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn mpub_round_trips() {
+        let mut rng = fastrand::Rng::with_seed(3880);
+        for _ in 0..200 {
+            let msgs: Vec<Vec<u8>> = (0..rng.usize(0..8))
+                .map(|_| (0..rng.usize(0..64)).map(|_| rng.u8(..)).collect())
+                .collect();
+            let encoded = encode(Command::Mpub("topic".into(), msgs.clone()));
+
+            let mut body = encoded.strip_prefix(b"MPUB topic\n".as_slice()).unwrap();
+            assert_eq!(body.get_u32() as usize, body.len());
+            let mut decoded = vec![];
+            for _ in 0..body.get_u32() {
+                let len = body.get_u32() as usize;
+                decoded.push(body[..len].to_vec());
+                body.advance(len);
+            }
+            assert!(body.is_empty());
+            assert_eq!(decoded, msgs);
+        }
+    }
+
+    #[test]
+    fn streamed_mpub_matches_collected() {
+        let msgs = [Bytes::from_static(b"ab"), Bytes::from_static(b""), Bytes::from_static(b"cde")];
+        let mut buf = BytesMut::new();
+        NsqCodec::new(true).encode_mpub("t", msgs.iter(), &mut buf).unwrap();
+        assert_eq!(buf, encode(Command::Mpub("t".into(), msgs.iter().map(|m| m.to_vec()).collect()))[..]);
+    }
+
+    #[test]
+    fn decode_errors_show_the_frame() {
+        let mut codec = NsqCodec::new(true);
+        let mut buf = testing::frame(FRAME_TYPE_RESPONSE, b"{\"bad\"");
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert!(e.to_string().contains("000000007b2262616422"), "{}", e);
+        match e {
+            Error::MalformedFrame { frame_type, len, head, source } => {
+                assert_eq!((frame_type, len, head.as_str()), (FRAME_TYPE_RESPONSE, 14, "000000007b2262616422"));
+                assert!(matches!(*source, Error::JsonError(_)), "{:?}", source);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let mut buf = testing::frame(7, &[0xff; 40]);
+        match codec.decode(&mut buf) {
+            Err(Error::MalformedFrame { frame_type: 7, head, .. }) => {
+                assert_eq!(head, format!("00000007{}...", "ff".repeat(28)));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut codec = NsqCodec::new(true);
+        codec.set_max_frame_size(1024);
+
+        let mut buf = BytesMut::from(&(u32::MAX / 2).to_be_bytes()[..]);
+        match codec.decode(&mut buf) {
+            Err(Error::ProtocolViolation(e)) => assert!(e.contains("2147483647 bytes"), "{}", e),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn read_buffer_is_shrunk_after_a_large_frame() {
+        let mut codec = NsqCodec::new(true);
+        codec.set_read_buffer_capacity(64, 1024);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&testing::message_frame(b"0123456789abcdef", 1, &[0; 4096]));
+        buf.extend_from_slice(&testing::frame(FRAME_TYPE_RESPONSE, b"OK")[..5]);
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(NsqFramed::Message(_))));
+        assert!(buf.capacity() < 1024, "{}", buf.capacity());
+
+        // The partial frame after the large one survives
+        buf.extend_from_slice(&testing::frame(FRAME_TYPE_RESPONSE, b"OK")[5..]);
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(NsqFramed::Response(_))));
+    }
+}
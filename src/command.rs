@@ -1,8 +1,59 @@
+use std::fmt::{self, Write};
+use std::str::{self, FromStr};
+
+use bytes::BytesMut;
 use serde_json::Value as JsonValue;
 
+use crate::error::Error;
+
 pub type MessageBody = Vec<u8>;
 
-#[derive(Debug)]
+/// The 16 byte id nsqd assigns to a message, hex digits in practice.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId([u8; 16]);
+
+impl MessageId {
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl From<[u8; 16]> for MessageId {
+    fn from(id: [u8; 16]) -> Self {
+        MessageId(id)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = Error;
+
+    /// Parses the 16 hex digits nsqd formats ids as.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let id: [u8; 16] = s.as_bytes().try_into()
+            .map_err(|_| Error::InvalidMessageId(format!("{:?} is not 16 bytes", s)))?;
+        if !id.iter().all(u8::is_ascii_hexdigit) {
+            return Err(Error::InvalidMessageId(format!("{:?} is not hex", s)));
+        }
+        Ok(MessageId(id))
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match str::from_utf8(&self.0) {
+            Ok(id) => f.write_str(id),
+            Err(_) => write!(f, "{}", self.0.escape_ascii()),
+        }
+    }
+}
+
+impl fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageId({})", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Version,
     Identify(JsonValue),
@@ -11,9 +62,9 @@ pub enum Command {
     Mpub(String, Vec<MessageBody>),
     Dpub(String, u64, MessageBody),
     Rdy(u64),
-    Fin(String),
-    Req(String, u64),
-    Touch(String),
+    Fin(MessageId),
+    Req(MessageId, u64),
+    Touch(MessageId),
     Close,
     Nop,
     Auth(String),
@@ -26,24 +77,26 @@ pub(crate) enum Body {
 }
 
 impl Command {
-    pub(crate) fn header(&self) -> String {
+    /// Write the command line, e.g. `PUB topic\n`, straight into `buf`.
+    pub(crate) fn write_header(&self, buf: &mut BytesMut) {
         use self::Command::*;
-        let cmd_name = self.cmd();
-        match *self {
-            Version                     => cmd_name.to_string(),
-            Identify(..)                => format!("{}\n",       cmd_name),
-            Sub(ref topic, ref channel) => format!("{} {} {}\n", cmd_name, topic, channel),
-            Pub(ref topic, _)           => format!("{} {}\n",    cmd_name, topic),
-            Mpub(ref topic, _)          => format!("{} {}\n",    cmd_name, topic),
-            Dpub(ref topic, defer, _)   => format!("{} {} {}\n", cmd_name, topic, defer),
-            Rdy(count)                  => format!("{} {}\n",    cmd_name, count),
-            Fin(ref id)                 => format!("{} {}\n",    cmd_name, id),
-            Req(ref id, timeout)        => format!("{} {} {}\n", cmd_name, id, timeout),
-            Touch(ref id)               => format!("{} {}\n",    cmd_name, id),
-            Close                       => format!("{}\n",       cmd_name),
-            Nop                         => format!("{}\n",       cmd_name),
-            Auth(..)                    => format!("{}\n",       cmd_name),
-        }
+        let cmd_name = self.name();
+        // Writing to a `BytesMut` can't fail
+        let _ = match *self {
+            Version                     => write!(buf, "{}",           cmd_name),
+            Identify(..)                => writeln!(buf, "{}",         cmd_name),
+            Sub(ref topic, ref channel) => writeln!(buf, "{} {} {}",   cmd_name, topic, channel),
+            Pub(ref topic, _)           => writeln!(buf, "{} {}",      cmd_name, topic),
+            Mpub(ref topic, _)          => writeln!(buf, "{} {}",      cmd_name, topic),
+            Dpub(ref topic, defer, _)   => writeln!(buf, "{} {} {}",   cmd_name, topic, defer),
+            Rdy(count)                  => writeln!(buf, "{} {}",      cmd_name, count),
+            Fin(ref id)                 => writeln!(buf, "{} {}",      cmd_name, id),
+            Req(ref id, timeout)        => writeln!(buf, "{} {} {}",   cmd_name, id, timeout),
+            Touch(ref id)               => writeln!(buf, "{} {}",      cmd_name, id),
+            Close                       => writeln!(buf, "{}",         cmd_name),
+            Nop                         => writeln!(buf, "{}",         cmd_name),
+            Auth(..)                    => writeln!(buf, "{}",         cmd_name),
+        };
     }
 
     pub(crate) fn body(self) -> Option<Body> {
@@ -57,7 +110,18 @@ impl Command {
         }
     }
 
-    fn cmd(&self) -> &str {
+    /// Whether nsqd answers this command with `OK` or an error. The others only get an
+    /// error back when they fail.
+    pub(crate) fn expects_response(&self) -> bool {
+        use self::Command::*;
+        match *self {
+            Identify(..) | Sub(..) | Pub(..) | Mpub(..) | Dpub(..) | Close | Auth(..) => true,
+            Version | Rdy(..) | Fin(..) | Req(..) | Touch(..) | Nop => false,
+        }
+    }
+
+    /// The command as sent, e.g. `PUB`
+    pub(crate) fn name(&self) -> &'static str {
         use self::Command::*;
         match *self {
             Version => "  V2",
@@ -76,3 +140,21 @@ impl Command {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MessageId;
+    use crate::Error;
+
+    #[test]
+    fn message_id_is_parsed_from_hex() {
+        let id: MessageId = "0123456789abcdef".parse().unwrap();
+        assert_eq!(id.as_bytes(), b"0123456789abcdef");
+        for invalid in ["0123456789abcde", "0123456789abcdefa", "0123456789abcdeg", "0123456789abcd\n\n"] {
+            match invalid.parse::<MessageId>() {
+                Err(Error::InvalidMessageId(_)) => {}
+                other => panic!("unexpected {:?} for {:?}", other, invalid),
+            }
+        }
+    }
+}
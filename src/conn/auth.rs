@@ -0,0 +1,47 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+
+use crate::error::Error;
+
+/// Supplies the secret sent in `AUTH`, see `Config::auth_secret`.
+///
+/// It is asked on every connect, reconnects included, so a secret read from a vault or
+/// rotated since the last connection is picked up. An error fails the connection.
+pub trait AuthTokenProvider: Send + Sync {
+    fn token(&self) -> BoxFuture<'_, Result<String, Error>>;
+}
+
+/// A fixed secret.
+impl AuthTokenProvider for String {
+    fn token(&self) -> BoxFuture<'_, Result<String, Error>> {
+        future::ready(Ok(self.clone())).boxed()
+    }
+}
+
+/// A shared [`AuthTokenProvider`], cheap to clone along with the `Config`.
+#[derive(Clone)]
+pub struct AuthToken(Arc<dyn AuthTokenProvider>);
+
+impl AuthToken {
+    pub fn new(provider: impl AuthTokenProvider + 'static) -> Self {
+        AuthToken(Arc::new(provider))
+    }
+
+    /// A fixed secret, as given in the configuration.
+    pub fn secret(secret: impl Into<String>) -> Self {
+        AuthToken::new(secret.into())
+    }
+
+    pub(crate) async fn token(&self) -> Result<String, Error> {
+        self.0.token().await
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken")
+    }
+}
@@ -29,32 +29,59 @@
 //! ```
 //! See [NSQ TCP Protocol Spec](https://nsq.io/clients/tcp_protocol_spec.html) to read more.
 
+use std::fmt;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::net::SocketAddr;
-use bytes::{Buf, BytesMut};
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::BytesMut;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_snappy::SnappyIO;
 use futures::{
     prelude::*,
+    ready,
     stream::{SplitSink, SplitStream},
 };
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Framed, FramedParts};
 use serde::Deserialize;
-use super::tls::TlsStream;
-use tracing::{trace, debug, error};
+use socket2::{SockRef, TcpKeepalive};
+use super::tls::upgrade_tls;
+use tracing::{field, info_span, trace, debug, error, warn, Instrument, Span};
 
-use crate::error::Error;
+use crate::error::{Error, NsqError};
 use crate::codec::{Decoder, Encoder, NsqCodec, NsqFramed, RawResponse};
-use crate::command::Command;
-use crate::conn::{Heartbeat, Response, BaseIo};
-use crate::config::{Config, TlsConfig};
+use crate::command::{Command, MessageId};
+use crate::conn::{Heartbeat, Response, BaseIo, CommandSender, MessageStream, ToAddrs};
+use crate::config::{Compress, Config, TlsConfig};
+use crate::lookup::{check_channel_name, check_topic_name};
 use crate::producer::Producer;
 use crate::conn::deflate::DeflateStream;
+use crate::conn::rewind::Rewind;
+use crate::conn::stats::{ConnectionStats, Counted, Counters};
 
-pub struct Connection(pub(crate) Heartbeat<BaseIo>);
+/// Enough for any IDENTIFY response in a single read
+const NEGOTIATION_READ_SIZE: usize = 1024;
+
+/// nsqd's default `--msg-timeout`
+const NSQD_DEFAULT_MSG_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Connection {
+    pub(crate) transport: Heartbeat<BaseIo>,
+    peer_addr: SocketAddr,
+    identify: IdentifyResponse,
+    auth: Option<AuthResponse>,
+    negotiated: Negotiated,
+    counters: Arc<Counters>,
+    // nsqd rejects publishing once subscribed, which it is from its OK to SUB on
+    subscribed: bool,
+    sub_pending: bool,
+    // Whether `send` leaves commands buffered until `flush`
+    corked: bool,
+    span: Span,
+}
 
 pub type ConnSink = SplitSink<Heartbeat<BaseIo>, Command>;
 pub type ConnStream = SplitStream<Heartbeat<BaseIo>>;
@@ -66,54 +93,453 @@ impl<T> AsyncRW for T where T: AsyncRead + AsyncWrite {}
 // impl<S> AsyncReadWrite for TlsStream<S>
 //     where S: AsyncRead + AsyncWrite + Unpin {}
 
-#[derive(Debug, Deserialize)]
-struct IdentifyResponse {
-    max_rdy_count: i64,
-    auth_required: bool,
-    deflate: bool,
-    deflate_level: u32,
-    max_deflate_level: u64,
-    max_msg_timeout: u64,
-    msg_timeout: u64,
-    output_buffer_size: i64,
-    output_buffer_timeout: u64,
-    sample_rate: i32,
-    snappy: bool,
-    tls_v1: bool,
-    version: String,
+/// nsqd's answer to IDENTIFY, i.e. the settings it agreed to for the connection.
+///
+/// Fields missing from the response, as with older nsqd versions, take nsqd's defaults, and
+/// fields this client doesn't know are ignored. `msg_timeout` is the exception, 0 when not
+/// reported.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IdentifyResponse {
+    /// Highest `RDY` count nsqd accepts
+    pub max_rdy_count: i64,
+    pub auth_required: bool,
+    /// Whether the connection was upgraded to deflate
+    pub deflate: bool,
+    pub deflate_level: u32,
+    pub max_deflate_level: u64,
+    /// Highest timeout, in milliseconds, `TOUCH` can extend a message to
+    pub max_msg_timeout: u64,
+    /// Message timeout in milliseconds, 0 if nsqd didn't report it
+    pub msg_timeout: u64,
+    pub output_buffer_size: i64,
+    /// In milliseconds, -1 or 0 if buffering was disabled, depending on the nsqd version
+    pub output_buffer_timeout: i64,
+    pub sample_rate: i32,
+    /// Whether the connection was upgraded to snappy
+    pub snappy: bool,
+    /// Whether the connection was upgraded to TLS
+    pub tls_v1: bool,
+    /// nsqd version
+    pub version: String,
+}
+
+impl Default for IdentifyResponse {
+    fn default() -> Self {
+        Self {
+            max_rdy_count: 2500,
+            auth_required: false,
+            deflate: false,
+            deflate_level: 6,
+            max_deflate_level: 6,
+            max_msg_timeout: 15 * 60 * 1000,
+            msg_timeout: 0,
+            output_buffer_size: 16 * 1024,
+            output_buffer_timeout: 250,
+            sample_rate: 0,
+            snappy: false,
+            tls_v1: false,
+            version: String::new(),
+        }
+    }
+}
+
+/// The effective settings of a connection, after feature negotiation.
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    pub tls: bool,
+    /// Compression in use, with the deflate level actually used
+    pub compress: Compress,
+    /// Interval at which nsqd sends heartbeats, as requested in IDENTIFY, `None` if disabled
+    pub heartbeat_interval: Option<Duration>,
+    /// Highest `RDY` count nsqd accepts
+    pub max_rdy_count: i64,
+    /// Time nsqd waits for a message to be finished before requeueing it, at most
+    /// `max_msg_timeout` whatever was configured
+    pub msg_timeout: Duration,
+}
+
+impl Negotiated {
+    fn new(config: &Config, identify: &IdentifyResponse) -> Self {
+        let compress = if identify.snappy {
+            Compress::Snappy
+        } else if identify.deflate {
+            Compress::Deflate{ level: deflate_level(config, identify) }
+        } else {
+            Compress::Disabled
+        };
+        Self {
+            tls: identify.tls_v1,
+            compress,
+            heartbeat_interval: config.effective_heartbeat_interval(),
+            max_rdy_count: identify.max_rdy_count,
+            msg_timeout: msg_timeout(config, identify),
+        }
+    }
+}
+
+/// What was left behind when a connection was closed.
+#[derive(Debug, Default)]
+pub struct CloseReport {
+    /// Whether nsqd acknowledged `CLS` with `CLOSE_WAIT` before the deadline. If not, the
+    /// connection was aborted.
+    pub graceful: bool,
+
+    /// Ids of messages delivered while closing which were never finished, nsqd will requeue
+    /// them once their timeout expires.
+    pub abandoned_messages: Vec<MessageId>,
+
+    /// Number of published messages which were never acknowledged, they may or may not have
+    /// been accepted by nsqd.
+    pub unacknowledged_publishes: usize,
 }
 
-#[derive(Debug, Deserialize)]
+/// The auth server's answer to `AUTH`, relayed by nsqd.
+#[derive(Debug, Clone, Deserialize)]
 pub struct AuthResponse {
+    /// The identity the secret belongs to
     pub identify: String,
+    /// Where to find out more about the identity
     pub identify_url: Option<String>,
+    /// Number of topic and channel permissions granted
     pub permission_count: i64,
 }
 
+/// Called with the [`AuthResponse`] once nsqd accepted `AUTH`, see `Config::auth_hook`.
+///
+/// Returning an error fails the connection with `Error::Auth`, e.g. when the identity or its
+/// permissions are not the expected ones.
+#[derive(Clone)]
+pub struct AuthHook(Arc<AuthCheck>);
+
+type AuthCheck = dyn Fn(&AuthResponse) -> Result<(), String> + Send + Sync;
+
+impl AuthHook {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&AuthResponse) -> Result<(), String> + Send + Sync + 'static,
+    {
+        AuthHook(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for AuthHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthHook")
+    }
+}
+
 impl Connection {
-    pub async fn connect<A: Into<SocketAddr>>(addr: A, config: &Config) -> Result<Self, Error> {
-        let (transport, identify) = connect(addr, config).await?;
-        trace!("connected to nsqd, with identify: {:?}", identify);
-        Ok(Self(transport))
+    /// Connect to nsqd at `addr`, which may be a host name, e.g. `"nsqd.local:4150"`, resolved
+    /// with `config.resolver`.
+    ///
+    /// Fails with `Error::InvalidConfig` before dialing if `config` doesn't validate.
+    pub async fn connect<A: ToAddrs>(addr: A, config: &Config) -> Result<Self, Error> {
+        config.validate()?;
+        let span = info_span!(
+            "nsq_connection",
+            peer = field::Empty,
+            client_id = %config.client_id,
+            topic = field::Empty,
+            channel = field::Empty,
+            tls = field::Empty,
+            compress = field::Empty,
+        );
+        let counters = Arc::new(Counters::default());
+        let (peer_addr, framed, identify, auth) = async {
+            let tcp = dial(addr, config).await?;
+            let peer_addr = tcp.peer_addr()?;
+            Span::current().record("peer", field::display(peer_addr));
+            let (framed, identify, auth) = negotiate(Counted::new(tcp, counters.clone()), config).await?;
+            trace!("connected to nsqd, with identify: {:?}", identify);
+            Ok::<_, Error>((peer_addr, framed, identify, auth))
+        }.instrument(span.clone()).await?;
+        let negotiated = Negotiated::new(config, &identify);
+        span.record("tls", negotiated.tls);
+        span.record("compress", field::debug(&negotiated.compress));
+        // nsqd sends a heartbeat per interval, unless configured otherwise missing two in a row
+        // means the peer is gone
+        let read_timeout = config.read_timeout.or(negotiated.heartbeat_interval.map(|interval| interval * 2));
+        Ok(Self {
+            transport: Heartbeat::new(framed, read_timeout)
+                .with_write_timeout(config.write_timeout)
+                .with_coalescing(config.client_output_buffer_size, config.client_output_buffer_timeout)
+                .with_counters(counters.clone())
+                .with_rate_limit(config.rate_limit)
+                .with_validator(config.message_validator.clone()),
+            peer_addr,
+            identify,
+            auth,
+            negotiated,
+            counters,
+            subscribed: false,
+            sub_pending: false,
+            corked: false,
+            span,
+        })
+    }
+
+    /// Connect to nsqd at `host`, resolved with `config.resolver`, like `connect((host, port))`.
+    pub async fn connect_host(host: &str, port: u16, config: &Config) -> Result<Self, Error> {
+        Self::connect((host, port), config).await
     }
 
     /// Send `Command` to the server
+    ///
+    /// Publishing after `SUB` fails with `Error::InvalidCommand` without reaching nsqd, which
+    /// would answer `E_INVALID`.
     pub async fn send(&mut self, cmd: Command) -> Result<(), Error> {
-        self.0.send(cmd).await
+        self.check_command(&cmd)?;
+        if self.corked {
+            return self.transport.feed(cmd).instrument(self.span.clone()).await;
+        }
+        self.transport.send(cmd).instrument(self.span.clone()).await
+    }
+
+    /// Write out every buffered command.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let transport = &mut self.transport;
+        future::poll_fn(|cx| transport.poll_flush_now(cx)).instrument(self.span.clone()).await
+    }
+
+    /// Have `send` buffer commands instead of writing each one, until `uncork`.
+    ///
+    /// Lets batches of commands go out in as few writes as possible, `flush` to write them
+    /// at some point meanwhile. The buffer is still written once full, and whenever a
+    /// heartbeat is answered.
+    pub fn cork(&mut self) {
+        self.corked = true;
+    }
+
+    /// Go back to writing each command as it is sent, flushing what was buffered.
+    pub async fn uncork(&mut self) -> Result<(), Error> {
+        self.corked = false;
+        self.flush().await
+    }
+
+    /// Track the state `cmd` puts the connection in, rejecting it if invalid there.
+    pub(crate) fn check_command(&mut self, cmd: &Command) -> Result<(), Error> {
+        match *cmd {
+            Command::Sub(ref topic, ref channel) => {
+                check_topic_name(topic)?;
+                check_channel_name(channel)?;
+                self.span.record("topic", topic.as_str());
+                self.span.record("channel", channel.as_str());
+                self.sub_pending = true;
+            }
+            Command::Pub(ref topic, _) | Command::Mpub(ref topic, _) | Command::Dpub(ref topic, ..) => {
+                self.check_publish(topic)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_publish(&self, topic: &str) -> Result<(), Error> {
+        check_topic_name(topic)?;
+        if self.subscribed {
+            return Err(Error::InvalidCommand("cannot publish on a subscribed connection".into()));
+        }
+        Ok(())
+    }
+
+    /// Track the state `response` puts the connection in, taking it as the answer to a
+    /// pending `SUB`.
+    fn track_response(&mut self, response: &Result<Response, Error>) {
+        if self.sub_pending {
+            match response {
+                Ok(Response::Ok) | Ok(Response::Msg(_)) | Ok(Response::Invalid { .. }) => {
+                    self.sub_pending = false;
+                    self.subscribed = true;
+                }
+                Ok(Response::Err(_)) | Err(_) => self.sub_pending = false,
+                Ok(Response::CloseWait) => {}
+            }
+        }
     }
 
     /// Receive from the server
     pub async fn receive(&mut self) -> Result<Response, Error> {
-        match self.0.next().await {
+        let response = match self.transport.next().instrument(self.span.clone()).await {
             Some(r) => r,
             None => {
                 Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
             }
+        };
+        self.track_response(&response);
+        response
+    }
+
+    /// Subscribe to `channel` of `topic`, waiting for nsqd to accept.
+    ///
+    /// The connection is split into the stream of delivered messages and a sender for the
+    /// commands handling them. Nothing is delivered until `RDY` is sent.
+    pub async fn subscribe(mut self, topic: impl Into<String>, channel: impl Into<String>)
+        -> Result<(CommandSender, MessageStream), Error>
+    {
+        self.send(Command::Sub(topic.into(), channel.into())).await?;
+        match self.receive().await? {
+            Response::Ok => {}
+            Response::Err(e) => return Err(e.into()),
+            Response::Msg(msg) | Response::Invalid { msg, .. } => return Err(Error::ProtocolViolation(
+                format!("message {} delivered before SUB was acknowledged", msg.message_id),
+            )),
+            Response::CloseWait => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
+        let span = self.span.clone();
+        let (sink, stream) = self.transport.split();
+        Ok((CommandSender::new(sink, span.clone()), MessageStream::new(stream, span)))
+    }
+
+    /// Allow nsqd to have up to `count` messages in flight on this connection.
+    pub async fn rdy(&mut self, count: u64) -> Result<(), Error> {
+        self.send(Command::Rdy(count)).await
+    }
+
+    /// Finish a message. nsqd only answers if this fails, with `E_FIN_FAILED`.
+    pub async fn fin(&mut self, id: MessageId) -> Result<(), Error> {
+        self.send(Command::Fin(id)).await
+    }
+
+    /// Requeue a message, to be redelivered after `delay`.
+    pub async fn req(&mut self, id: MessageId, delay: Duration) -> Result<(), Error> {
+        self.send(Command::Req(id, delay.as_millis() as u64)).await
+    }
+
+    /// Reset the timeout of an in-flight message.
+    pub async fn touch(&mut self, id: MessageId) -> Result<(), Error> {
+        self.send(Command::Touch(id)).await
+    }
+
+    /// The address of the nsqd this connection is connected to
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
     }
 
+    /// What nsqd agreed to in IDENTIFY
+    pub fn identify(&self) -> &IdentifyResponse {
+        &self.identify
+    }
+
+    /// Who nsqd authenticated this connection as, `None` if it doesn't require auth
+    pub fn auth(&self) -> Option<&AuthResponse> {
+        self.auth.as_ref()
+    }
+
+    /// The feature set in effect, e.g. to size `RDY` within `max_rdy_count`
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    /// Bytes and frames exchanged so far, including the negotiation.
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+
+    /// The span of this connection, carrying its peer, client id and negotiated features.
+    ///
+    /// Crate internals log within it, use it to attribute other events to the connection.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Split into a sink of commands and a stream of responses and messages.
+    ///
+    /// Heartbeats are answered by whichever half is polled, so a connection only ever
+    /// written to stays alive. Frames the sink reads meanwhile are kept for the stream.
+    ///
+    /// The sink sends commands as they are, without the checks of `send`, e.g. against
+    /// publishing after `SUB`; see `subscribe` for halves which keep them.
     pub fn split(self) -> (ConnSink, ConnStream) {
-        self.0.split()
+        self.transport.split()
+    }
+
+    /// Close the connection gracefully.
+    ///
+    /// Sends `CLS`, waits for nsqd to answer `CLOSE_WAIT` and shuts down the socket, so nsqd
+    /// stops delivering and can requeue in-flight messages right away instead of after their
+    /// timeout. Messages delivered meanwhile are listed in the report. This waits as long as
+    /// nsqd takes, see `close_timeout` to bound it.
+    pub async fn close(mut self) -> Result<CloseReport, Error> {
+        let mut report = CloseReport::default();
+        let span = self.span.clone();
+        self.close_gracefully(&mut report).instrument(span).await?;
+        report.graceful = true;
+        Ok(report)
+    }
+
+    /// Close the connection, giving up on a graceful close after `timeout`.
+    ///
+    /// Sends `CLS` and waits for nsqd to answer `CLOSE_WAIT` before shutting down the socket.
+    /// If that doesn't happen in time the connection is aborted. Either way, the returned
+    /// report lists what was abandoned.
+    pub async fn close_timeout(self, timeout: Duration) -> Result<CloseReport, Error> {
+        self.shutdown(timeout, 0).await
+    }
+
+    /// `pending_responses` is the number of responses still owed for earlier publishes.
+    pub(crate) async fn shutdown(mut self, timeout: Duration, pending_responses: usize) -> Result<CloseReport, Error> {
+        let mut report = CloseReport {
+            unacknowledged_publishes: pending_responses,
+            ..Default::default()
+        };
+        let span = self.span.clone();
+        match tokio::time::timeout(timeout, self.close_gracefully(&mut report)).instrument(span).await {
+            Ok(Ok(())) => report.graceful = true,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => debug!("connection not closed within {:?}, aborting", timeout),
+        }
+        // Dropping the transport severs the TCP connection if it is still open
+        Ok(report)
+    }
+
+    async fn close_gracefully(&mut self, report: &mut CloseReport) -> Result<(), Error> {
+        self.transport.send(Command::Close).await?;
+        while let Some(response) = self.transport.next().await {
+            match response? {
+                Response::Msg(msg) | Response::Invalid { msg, .. } => report.abandoned_messages.push(msg.message_id),
+                Response::Ok | Response::Err(_) => {
+                    report.unacknowledged_publishes = report.unacknowledged_publishes.saturating_sub(1);
+                }
+                Response::CloseWait => break,
+            }
+        }
+        self.transport.close().await
+    }
+}
+
+impl Stream for Connection {
+    type Item = Result<Response, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let response = ready!(Pin::new(&mut self.transport).poll_next(cx));
+        if let Some(ref response) = response {
+            self.track_response(response);
+        }
+        Poll::Ready(response)
+    }
+}
+
+impl Sink<Command> for Connection {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Command) -> Result<(), Self::Error> {
+        self.check_command(&item)?;
+        Pin::new(&mut self.transport).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_close(cx)
     }
 }
 
@@ -123,115 +549,249 @@ impl From<Connection> for Producer {
     }
 }
 
-async fn connect<A>(addr: A, config: &Config)
-    -> Result<(Heartbeat<BaseIo>, IdentifyResponse), Error>
+/// Open the TCP connection to nsqd, resolving `addr` with `config.resolver` if needed.
+///
+/// The resolved addresses are tried in order until one connects, giving each of them
+/// `config.dial_timeout`.
+pub(crate) async fn dial<A: ToAddrs>(addr: A, config: &Config) -> Result<TcpStream, Error> {
+    let mut errors = Vec::new();
+    for addr in interleave_families(addr.to_addrs(&config.resolver).await?) {
+        if config.local_addr.is_some_and(|local_addr| local_addr.is_ipv4() != addr.is_ipv4()) {
+            let e = io::Error::new(io::ErrorKind::AddrNotAvailable, "not of the same family as local_addr");
+            errors.push((addr, e));
+            continue;
+        }
+        let connected = tokio::time::timeout(config.dial_timeout, connect_from(config.local_addr, addr)).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")));
+        match connected.and_then(|tcp| set_socket_options(&tcp, config).map(|_| tcp)) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                debug!("failed to connect to {}: {}", addr, e);
+                errors.push((addr, e));
+            }
+        }
+    }
+    Err(Error::ConnectFailed(errors))
+}
+
+/// Alternate IPv6 and IPv4 addresses, starting with the resolver's preference, so a host
+/// whose one family is unreachable doesn't wait out every address of it first.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+async fn connect_from(local_addr: Option<SocketAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
+    match local_addr {
+        Some(local_addr) => {
+            let socket = if local_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            socket.bind(local_addr)?;
+            socket.connect(addr).await
+        }
+        None => TcpStream::connect(addr).await,
+    }
+}
+
+fn set_socket_options(tcp: &TcpStream, config: &Config) -> io::Result<()> {
+    tcp.set_nodelay(config.tcp_nodelay)?;
+    if let Some(keepalive) = config.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(keepalive).with_interval(keepalive);
+        SockRef::from(tcp).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Go through IDENTIFY, the upgrades it asks for and AUTH.
+pub(crate) async fn negotiate<T>(mut tcp: T, config: &Config)
+    -> Result<(Framed<BaseIo, NsqCodec>, IdentifyResponse, Option<AuthResponse>), Error>
 where
-    A: Into<SocketAddr>,
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    let mut tcp = TcpStream::connect(addr.into()).await?;
     let mut nsq_codec = NsqCodec::new(true);
+    nsq_codec.set_observer(config.frame_observer.clone());
+    #[cfg(feature = "debug-capture")]
+    nsq_codec.set_recorder(config.frame_recorder.clone());
+    nsq_codec.set_max_frame_size(config.max_frame_size);
 
     let mut write_buf = BytesMut::new();
     nsq_codec.encode(Command::Version, &mut write_buf)?;
-    let identify = config.identify()?;
+    let identify = config.identify_unchecked()?;
     trace!("send identify: {:?}", &identify);
     nsq_codec.encode(identify, &mut write_buf)?;
 
     tcp.write_all(&write_buf.split()[..]).await?;
-    let response = read_response(&mut tcp, &mut nsq_codec).await?;
+    // Negotiation reads are buffered, anything read past a response belongs to the next layer
+    let mut read_buf = BytesMut::new();
+    let response = read_response(&mut tcp, &mut nsq_codec, &mut read_buf).await?;
     trace!("identify response: {:?}", response);
 
-    // TODO
     let identify: IdentifyResponse = match response {
-        // feature_negotiation false response Ok
-        NsqFramed::Response(RawResponse::Ok) => {
-            unreachable!();
-        }
-
-        // feature_negotiation true response Json object
         NsqFramed::Response(RawResponse::Json(value)) => {
             serde_json::from_value(value)?
         }
-
-        // Reponse heartbeat
-        NsqFramed::Response(RawResponse::Heartbeat) => {
+        NsqFramed::Response(RawResponse::Heartbeat) | NsqFramed::Response(RawResponse::CloseWait) => {
             // Wrong response
             return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
         }
-        NsqFramed::Response(RawResponse::CloseWait) => {
-            // EOF
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
-        }
-        NsqFramed::Message(_) => {
-            // Wrong response
-            unreachable!();
+        // OK only answers an IDENTIFY without feature_negotiation, which is never sent
+        NsqFramed::Response(RawResponse::Ok) | NsqFramed::Message(_) => {
+            return Err(Error::ProtocolViolation("IDENTIFY was not answered with its settings".into()));
         }
         NsqFramed::Error(e) => {
-            // NSQ Error
-            // TODO
             error!("IDENTIFY response error: {:?}", e);
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            return Err(identify_error(e));
         }
     };
-    let socket = tcp;
-    // let socket = BaseIo::Tcp(tcp);
-    // let codec = Codec::new(nsq_codec);
-    // let mut framed = Framed::new(BaseIo::Tcp(tcp), codec);
-
-    // let socket = {
-    //     if identify.tls_v1 {
-    //         let tls_config = config.tls_v1.as_ref().unwrap();
-    //         let domain = match config.tls_v1 {
-    //             Some(TlsConfig{ref domain, ..}) => domain.as_str(),
-    //             _ => unreachable!(),
-    //         };
-    //         let tls_stream = upgrade_tls(domain, tcp, tls_config, &mut nsq_codec).await?;
-    //         BaseIo::Tls(tls_stream)
-    //     } else {
-    //         BaseIo::Tcp(tcp)
-    //         // let FramedParts { io, codec, read_buf, write_buf, .. } = framed.into_parts();
-    //         // let framed = Framed::new(BaseIo::Tcp(io), codec);
-    //         // let mut parts = framed.into_parts();
-    //         // parts.read_buf = read_buf;
-    //         // parts.write_buf = write_buf;
-    //         // Framed::from_parts(parts)
-    //     }
-    // };
-
-    let boxed_stream = if identify.snappy {
-        let mut snappy_stream = upgrade_snappy(socket);
-        if let NsqFramed::Response(RawResponse::Ok) = read_response(&mut snappy_stream, &mut nsq_codec).await? {
-            BaseIo::Snappy(snappy_stream)
-        } else {
-            return Err(Error::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "compression negotiation expected OK",
-            )));
-        }
-    } else if identify.deflate {
-        let mut deflate_stream = upgrade_deflate(socket, identify.deflate_level);
-        if let NsqFramed::Response(RawResponse::Ok) = read_response(&mut deflate_stream, &mut nsq_codec).await? {
-            BaseIo::Deflate(deflate_stream)
-        } else {
-            return Err(Error::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "compression negotiation expected OK",
-            )));
+    // Each upgrade wraps the stream so far, getting the bytes read ahead put back first
+    let mut io = BaseIo::new(tcp);
+    for upgrade in upgrades(config, &identify)? {
+        let rewound = Rewind::new(io, read_buf.split().freeze());
+        io = match upgrade {
+            Upgrade::Tls(tls_config) => {
+                let tls = upgrade_tls(rewound, tls_config).await?;
+                BaseIo::new(negotiated(tls, &mut nsq_codec, &mut read_buf).await?)
+            }
+            Upgrade::Snappy => {
+                BaseIo::new(negotiated(upgrade_snappy(rewound), &mut nsq_codec, &mut read_buf).await?)
+            }
+            Upgrade::Deflate{ level } => {
+                BaseIo::new(negotiated(upgrade_deflate(rewound, level), &mut nsq_codec, &mut read_buf).await?)
+            }
+        };
+    }
+    // Whatever follows the last negotiation response is already plain protocol
+    read_buf.reserve(config.read_buffer_capacity);
+    nsq_codec.set_read_buffer_capacity(config.read_buffer_capacity, config.max_read_buffer_capacity);
+    let mut parts = FramedParts::new::<Command>(io, nsq_codec);
+    parts.read_buf = read_buf;
+    parts.write_buf = BytesMut::with_capacity(config.write_buffer_capacity);
+    let mut framed = Framed::from_parts(parts);
+
+    let auth_response = if identify.auth_required {
+        let auth_response = auth(config, &mut framed).await?;
+        debug!("connection auth response: {:?}", auth_response);
+        if let Some(ref hook) = config.auth_hook {
+            (hook.0)(&auth_response).map_err(Error::Auth)?;
         }
+        Some(auth_response)
     } else {
-        BaseIo::NoCompress(socket)
+        None
     };
-    let mut framed = Framed::new(boxed_stream, nsq_codec);
 
-    if identify.auth_required {
-        let auth_response = auth(config, &mut framed).await?;
-        debug!("connection auth response: {:?}", auth_response);
+    framed.codec_mut().set_strict(config.strict_protocol);
+    Ok((framed, identify, auth_response))
+}
+
+/// A layer nsqd agreed to put on top of the stream in its IDENTIFY response.
+enum Upgrade<'a> {
+    Tls(&'a TlsConfig),
+    Snappy,
+    Deflate{ level: u32 },
+}
+
+/// The upgrades to go through, in the order nsqd expects: TLS first, then at most one
+/// compression.
+fn upgrades<'a>(config: &'a Config, identify: &IdentifyResponse) -> Result<Vec<Upgrade<'a>>, Error> {
+    let mut upgrades = Vec::with_capacity(2);
+    if identify.tls_v1 {
+        let tls_config = config.tls_v1.as_ref()
+            .ok_or_else(|| Error::UnknownError("nsqd enabled TLS without being asked to".into()))?;
+        upgrades.push(Upgrade::Tls(tls_config));
     }
+    match (identify.snappy, identify.deflate) {
+        (true, true) => return Err(Error::ProtocolViolation("nsqd enabled both snappy and deflate".into())),
+        (true, false) => upgrades.push(Upgrade::Snappy),
+        (false, true) => upgrades.push(Upgrade::Deflate{ level: deflate_level(config, identify) }),
+        (false, false) => {}
+    }
+    Ok(upgrades)
+}
 
-    // handle heartbeat
-    Ok((Heartbeat::new(framed), identify))
+/// Map nsqd rejecting IDENTIFY to an error naming the offending config field, if known.
+fn identify_error(e: NsqError) -> Error {
+    // nsqd describes the rejected value, e.g. "IDENTIFY output buffer size (32) is invalid"
+    const FIELDS: &[(&str, &str)] = &[
+        ("output buffer size", "output_buffer_size"),
+        ("output buffer timeout", "output_buffer_timeout"),
+        ("heartbeat interval", "heartbeat_interval"),
+        ("sample rate", "sample_rate"),
+        ("msg timeout", "msg_timeout"),
+        ("deflate level", "compress"),
+    ];
+    if let "E_INVALID" | "E_BAD_BODY" = e.code() {
+        if let Some((_, field)) = FIELDS.iter().find(|(desc, _)| e.description().contains(desc)) {
+            return Error::InvalidConfig {
+                field,
+                reason: format!("rejected by nsqd: {}", e.description()),
+            };
+        }
+    }
+    e.into()
+}
+
+/// The level to compress with, the configured one clamped to the server's `max_deflate_level`.
+fn deflate_level(config: &Config, identify: &IdentifyResponse) -> u32 {
+    let level = match config.compress {
+        Compress::Deflate{ level } => level,
+        _ => identify.deflate_level,
+    };
+    if u64::from(level) > identify.max_deflate_level {
+        warn!(
+            "deflate level {} exceeds nsqd's max_deflate_level {}, using {}",
+            level, identify.max_deflate_level, identify.max_deflate_level,
+        );
+        return identify.max_deflate_level as u32;
+    }
+    level
 }
 
+/// The message timeout in effect, warning if nsqd reduced the configured one.
+fn msg_timeout(config: &Config, identify: &IdentifyResponse) -> Duration {
+    let mut effective = match identify.msg_timeout {
+        // Not reported by nsqd before 0.2.28, which defaults to 60s
+        0 if config.msg_timeout.is_zero() => NSQD_DEFAULT_MSG_TIMEOUT,
+        0 => config.msg_timeout,
+        ms => Duration::from_millis(ms),
+    };
+    if identify.max_msg_timeout > 0 {
+        effective = effective.min(Duration::from_millis(identify.max_msg_timeout));
+    }
+    // 0 asks for nsqd's default, which can't be reduced
+    if !config.msg_timeout.is_zero() && effective < config.msg_timeout {
+        warn!(
+            "msg_timeout {:?} exceeds nsqd's max_msg_timeout {}ms, using {:?}",
+            config.msg_timeout, identify.max_msg_timeout, effective,
+        );
+    }
+    effective
+}
+
+/// Wait for nsqd to acknowledge an upgrade of the stream, i.e. TLS or compression.
+///
+/// `read_buf` must be empty, bytes read after the acknowledgement are left in it.
+async fn negotiated<T>(mut io: T, nsq_codec: &mut NsqCodec, read_buf: &mut BytesMut) -> Result<T, Error>
+where
+    T: AsyncRead + Unpin,
+{
+    if let NsqFramed::Response(RawResponse::Ok) = read_response(&mut io, nsq_codec, read_buf).await? {
+        Ok(io)
+    } else {
+        Err(Error::from(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "upgrade negotiation expected OK",
+        )))
+    }
+}
 
 fn upgrade_deflate<T>(io: T, level: u32) -> DeflateStream<T>
 where
@@ -251,8 +811,8 @@ async fn auth<T>(config: &Config, transport: &mut T) -> Result<AuthResponse, Err
           T: Stream<Item = Result<NsqFramed, Error>>,
           T: Unpin,
 {
-    let secret = if let Some(ref secret) = config.auth_secret {
-        secret.clone()
+    let secret = if let Some(ref token) = config.auth_secret {
+        token.token().await?
     } else {
         return Err(Error::Auth("Required auth secret".into()));
     };
@@ -267,8 +827,8 @@ async fn auth<T>(config: &Config, transport: &mut T) -> Result<AuthResponse, Err
             NsqFramed::Error(e) => {
                 return Err(e.into());
             }
-            _ => {
-                unreachable!();
+            other => {
+                return Err(Error::ProtocolViolation(format!("AUTH was answered with {:?}", other)));
             }
         }
     } else {
@@ -278,18 +838,818 @@ async fn auth<T>(config: &Config, transport: &mut T) -> Result<AuthResponse, Err
     Ok(response)
 }
 
-async fn read_response<T>(socket: &mut T, nsq_codec: &mut NsqCodec) -> Result<NsqFramed, Error>
+/// Read one frame, buffering in `read_buf` which keeps any bytes after it.
+async fn read_response<T>(socket: &mut T, nsq_codec: &mut NsqCodec, read_buf: &mut BytesMut) -> Result<NsqFramed, Error>
 where T: AsyncRead + Unpin,
 {
-    let mut read_buf = BytesMut::new();
-    read_buf.resize(4, 0);
-    socket.read_exact(&mut read_buf[..4]).await?;
-    let len = (&read_buf[..4]).get_i32() as usize;
-    read_buf.resize(len + 4, 0);
-    socket.read_exact(&mut read_buf[4..len + 4]).await?;
-    if let Some(response) = nsq_codec.decode(&mut read_buf)? {
-        Ok(response)
-    } else {
-        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    loop {
+        if let Some(response) = nsq_codec.decode(read_buf)? {
+            return Ok(response);
+        }
+        read_buf.reserve(NEGOTIATION_READ_SIZE);
+        if socket.read_buf(read_buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use futures::future::{self, BoxFuture, FutureExt};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use crate::command::Command;
+    use crate::config::{Compress, Config, Resolve, Resolver, TlsConfig};
+    use crate::conn::{AuthToken, AuthTokenProvider, MessageValidator, OnInvalid, Response};
+    use crate::conn::heartbeat::MAX_RECEIVED;
+    use crate::error::Error;
+    use crate::testing::{self, FRAME_TYPE_RESPONSE};
+    use super::{deflate_level, dial, interleave_families, msg_timeout, upgrades, AuthHook, Connection, IdentifyResponse, Upgrade};
+
+    #[tokio::test]
+    async fn close_reports_abandoned_messages() {
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "CLS");
+            conn.write_message(b"0123456789abcdef", 1, b"late").await?;
+            conn.write_response(b"CLOSE_WAIT").await?;
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let report = conn.close().await.unwrap();
+        assert!(report.graceful);
+        assert_eq!(report.abandoned_messages, vec!["0123456789abcdef".parse().unwrap()]);
+        assert_eq!(report.unacknowledged_publishes, 0);
+    }
+
+    #[tokio::test]
+    async fn close_aborts_after_deadline() {
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "CLS");
+            // Never answer CLOSE_WAIT
+            let _ = done_rx.await;
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let report = conn.shutdown(Duration::from_millis(50), 3).await.unwrap();
+        assert!(!report.graceful);
+        assert_eq!(report.unacknowledged_publishes, 3);
+        let _ = done_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn keeps_frames_coalesced_with_identify_response() {
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "IDENTIFY");
+            let mut wire = testing::frame(FRAME_TYPE_RESPONSE, testing::identify_response().to_string().as_bytes());
+            wire.extend_from_slice(&testing::message_frame(b"0123456789abcdef", 1, b"early"));
+            conn.write_raw(&wire).await?;
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        match conn.receive().await.unwrap() {
+            Response::Msg(msg) => assert_eq!(&msg.body[..], b"early"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "deflate")]
+    #[tokio::test]
+    async fn keeps_compressed_bytes_coalesced_with_identify_response() {
+        use std::io::Write;
+        use flate2::{write::DeflateEncoder, Compression};
+
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "IDENTIFY");
+            let mut identify = testing::identify_response();
+            identify["deflate"] = true.into();
+
+            // The IDENTIFY response, then the compressed upgrade OK and a message, all in one write
+            let mut deflate = DeflateEncoder::new(Vec::new(), Compression::new(6));
+            deflate.write_all(&testing::frame(FRAME_TYPE_RESPONSE, b"OK"))?;
+            deflate.write_all(&testing::message_frame(b"0123456789abcdef", 1, b"early"))?;
+            deflate.flush()?;
+            let mut wire = testing::frame(FRAME_TYPE_RESPONSE, identify.to_string().as_bytes());
+            wire.extend_from_slice(deflate.get_ref());
+            conn.write_raw(&wire).await?;
+
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+
+        let config = Config { compress: Compress::Deflate{ level: 6 }, ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        assert!(conn.negotiated().compress.is_deflate());
+        assert_eq!(conn.negotiated().max_rdy_count, 2500);
+        match conn.receive().await.unwrap() {
+            Response::Msg(msg) => assert_eq!(&msg.body[..], b"early"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn deflated_commands_are_flushed_right_away() {
+        use std::io::Write;
+        use flate2::{write::DeflateEncoder, Compression, Decompress, FlushDecompress};
+
+        let (received_tx, received_rx) = oneshot::channel();
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            conn.read_command().await?.unwrap();
+            let mut identify = testing::identify_response();
+            identify["deflate"] = true.into();
+            conn.write_response(identify.to_string().as_bytes()).await?;
+            let mut deflate = DeflateEncoder::new(Vec::new(), Compression::new(6));
+            deflate.write_all(&testing::frame(FRAME_TYPE_RESPONSE, b"OK"))?;
+            deflate.flush()?;
+            conn.write_raw(deflate.get_ref()).await?;
+
+            // A lone RDY must be decompressible without anything written after it
+            let mut inflate = Decompress::new(false);
+            let mut plain = Vec::with_capacity(64);
+            let mut buf = [0u8; 64];
+            while !plain.ends_with(b"RDY 1\n") {
+                let n = conn.read_raw(&mut buf).await?;
+                assert!(n > 0, "connection closed before RDY was readable");
+                inflate.decompress_vec(&buf[..n], &mut plain, FlushDecompress::Sync)?;
+            }
+            let _ = received_tx.send(());
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+
+        let config = Config { compress: Compress::Deflate{ level: 6 }, ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        conn.rdy(1).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), received_rx).await
+            .expect("RDY stuck in the compressor").unwrap();
+    }
+
+    #[test]
+    fn deflate_level_is_clamped_to_server_max() {
+        let mut identify = testing::identify_response();
+        identify["deflate"] = true.into();
+        identify["max_deflate_level"] = 3.into();
+        let identify: IdentifyResponse = serde_json::from_value(identify).unwrap();
+
+        let config = Config { compress: Compress::Deflate{ level: 9 }, ..Default::default() };
+        assert_eq!(deflate_level(&config, &identify), 3);
+        let config = Config { compress: Compress::Deflate{ level: 2 }, ..Default::default() };
+        assert_eq!(deflate_level(&config, &identify), 2);
+    }
+
+    #[test]
+    fn upgrades_follow_tls_then_compression() {
+        let mut config = Config {
+            tls_v1: Some(TlsConfig::new("nsqd.local")),
+            compress: Compress::Deflate{ level: 3 },
+            ..Default::default()
+        };
+        let mut identify = IdentifyResponse::default();
+        assert!(upgrades(&config, &identify).unwrap().is_empty());
+
+        identify.tls_v1 = true;
+        identify.deflate = true;
+        match upgrades(&config, &identify).unwrap()[..] {
+            [Upgrade::Tls(_), Upgrade::Deflate{ level: 3 }] => {}
+            _ => panic!("expected TLS, then deflate"),
+        }
+
+        identify.snappy = true;
+        assert!(matches!(upgrades(&config, &identify), Err(Error::ProtocolViolation(_))));
+
+        config.tls_v1 = None;
+        identify.deflate = false;
+        assert!(upgrades(&config, &identify).is_err());
+    }
+
+    #[test]
+    fn identify_responses_of_other_versions_parse() {
+        // Before AUTH and the output buffer settings were reported
+        let old = serde_json::json!({
+            "max_rdy_count": 2500,
+            "version": "0.2.28",
+            "max_msg_timeout": 900000,
+            "msg_timeout": 60000,
+            "tls_v1": false,
+            "deflate": false,
+            "deflate_level": 0,
+            "max_deflate_level": 6,
+            "snappy": false,
+            "sample_rate": 0,
+        });
+        let identify: IdentifyResponse = serde_json::from_value(old).unwrap();
+        assert_eq!(identify.version, "0.2.28");
+        assert!(!identify.auth_required);
+        assert_eq!(identify.output_buffer_size, 16 * 1024);
+
+        let mut current = testing::identify_response();
+        current["topology_zone"] = "zone-a".into();
+        current["max_rdy_count"] = 100.into();
+        let identify: IdentifyResponse = serde_json::from_value(current).unwrap();
+        assert_eq!(identify.max_rdy_count, 100);
+
+        let identify: IdentifyResponse = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(identify.max_msg_timeout, 900_000);
+    }
+
+    #[test]
+    fn msg_timeout_is_clamped_to_server_max() {
+        let mut identify = testing::identify_response();
+        identify["msg_timeout"] = 0.into();
+        identify["max_msg_timeout"] = 60_000.into();
+        let identify: IdentifyResponse = serde_json::from_value(identify).unwrap();
+
+        let config = Config { msg_timeout: Duration::from_secs(120), ..Default::default() };
+        assert_eq!(msg_timeout(&config, &identify), Duration::from_secs(60));
+        let config = Config { msg_timeout: Duration::from_secs(30), ..Default::default() };
+        assert_eq!(msg_timeout(&config, &identify), Duration::from_secs(30));
+
+        // Left out by nsqd before 0.2.28
+        let mut identify = testing::identify_response();
+        identify.as_object_mut().unwrap().remove("msg_timeout");
+        let identify: IdentifyResponse = serde_json::from_value(identify).unwrap();
+        assert_eq!(identify.msg_timeout, 0);
+        assert_eq!(msg_timeout(&config, &identify), Duration::from_secs(30));
+        let config = Config { msg_timeout: Duration::ZERO, ..Default::default() };
+        assert_eq!(msg_timeout(&config, &identify), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn invalid_config_fails_before_dialing() {
+        let config = Config { sample_rate: 100, ..Default::default() };
+        // Nothing listens there, a dial would fail with a connection error instead
+        match Connection::connect("127.0.0.1:1", &config).await {
+            Err(Error::InvalidConfig { field, .. }) => assert_eq!(field, "sample_rate"),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn identify_rejected_at_capacity() {
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "IDENTIFY");
+            conn.write_error("E_TOO_MANY_CONNECTIONS max 100 connections reached").await
+        }).await;
+
+        let err = Connection::connect(addr, &Config::default()).await.err().unwrap();
+        assert!(err.is_at_capacity(), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn connect_falls_back_to_next_address() {
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let addr = testing::serve(|mut conn| async move {
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(&[closed, addr][..], &Config::default()).await.unwrap();
+        assert_eq!(conn.peer_addr(), addr);
+
+        match Connection::connect(&[closed][..], &Config::default()).await {
+            Err(Error::ConnectFailed(errors)) => {
+                assert_eq!(errors.iter().map(|(addr, _)| *addr).collect::<Vec<SocketAddr>>(), vec![closed]);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|c| c.peer_addr())),
+        }
+    }
+
+    #[tokio::test]
+    async fn host_is_resolved_with_the_configured_resolver() {
+        struct Static(SocketAddr);
+
+        impl Resolve for Static {
+            fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+                assert_eq!((host, port), ("nsqd.test", 4150));
+                future::ready(Ok(vec![self.0])).boxed()
+            }
+        }
+
+        async fn serve() -> (SocketAddr, Config) {
+            let addr = testing::serve(|mut conn| async move {
+                assert!(conn.read_command().await?.is_none());
+                Ok(())
+            }).await;
+            (addr, Config { resolver: Resolver::new(Static(addr)), ..Default::default() })
+        }
+
+        let (addr, config) = serve().await;
+        let conn = Connection::connect_host("nsqd.test", 4150, &config).await.unwrap();
+        assert_eq!(conn.peer_addr(), addr);
+
+        let (addr, config) = serve().await;
+        let conn = Connection::connect("nsqd.test:4150", &config).await.unwrap();
+        assert_eq!(conn.peer_addr(), addr);
+
+        // Everything connecting by address goes through it, e.g. the pool
+        let (addr, config) = serve().await;
+        let pool = crate::pool::ConnectionPool::new("nsqd.test:4150", config, 1);
+        assert_eq!(pool.get().await.unwrap().peer_addr(), addr);
+    }
+
+    #[tokio::test]
+    async fn auth_response_is_checked_and_kept() {
+        async fn serve_auth(permission_count: i64) -> SocketAddr {
+            let mut identify = testing::identify_response();
+            identify["auth_required"] = true.into();
+            testing::serve_with(identify, move |mut conn| async move {
+                let auth = conn.read_command().await?.unwrap();
+                assert_eq!((auth.name.as_str(), auth.body.as_deref()), ("AUTH", Some(&b"secret"[..])));
+                let response = serde_json::json!({
+                    "identify": "producer",
+                    "identify_url": "https://auth.local/producer",
+                    "permission_count": permission_count,
+                });
+                conn.write_response(response.to_string().as_bytes()).await?;
+                conn.read_to_end().await?;
+                Ok(())
+            }).await
+        }
+
+        let config = Config {
+            auth_secret: Some(AuthToken::secret("secret")),
+            auth_hook: Some(AuthHook::new(|auth| match auth.permission_count {
+                0 => Err(format!("{} has no permissions", auth.identify)),
+                _ => Ok(()),
+            })),
+            ..Default::default()
+        };
+        let conn = Connection::connect(serve_auth(2).await, &config).await.unwrap();
+        let auth = conn.auth().unwrap();
+        assert_eq!(auth.identify, "producer");
+        assert_eq!(auth.identify_url.as_deref(), Some("https://auth.local/producer"));
+
+        match Connection::connect(serve_auth(0).await, &config).await {
+            Err(Error::Auth(reason)) => assert_eq!(reason, "producer has no permissions"),
+            other => panic!("unexpected result: {:?}", other.map(|c| c.peer_addr())),
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_token_is_fetched_on_every_connect() {
+        struct Rotating(AtomicUsize);
+
+        impl AuthTokenProvider for Rotating {
+            fn token(&self) -> BoxFuture<'_, Result<String, Error>> {
+                let n = self.0.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(format!("secret-{}", n)) }.boxed()
+            }
+        }
+
+        async fn serve_auth(expected: &'static [u8]) -> SocketAddr {
+            let mut identify = testing::identify_response();
+            identify["auth_required"] = true.into();
+            testing::serve_with(identify, move |mut conn| async move {
+                let auth = conn.read_command().await?.unwrap();
+                assert_eq!(auth.body.as_deref(), Some(expected));
+                let response = serde_json::json!({"identify": "producer", "permission_count": 1});
+                conn.write_response(response.to_string().as_bytes()).await?;
+                conn.read_to_end().await?;
+                Ok(())
+            }).await
+        }
+
+        let config = Config::builder().auth_token_provider(Rotating(AtomicUsize::new(0))).build().unwrap();
+        Connection::connect(serve_auth(b"secret-0").await, &config).await.unwrap();
+        Connection::connect(serve_auth(b"secret-1").await, &config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn strict_mode_rejects_zero_attempts() {
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_message(b"0123456789abcdef", 0, b"bogus").await?;
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+
+        let config = Config { strict_protocol: true, ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        match conn.receive().await {
+            Err(Error::ProtocolViolation(e)) => assert!(e.contains("zero attempts"), "{}", e),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn unexpected_json_and_auth_responses_are_violations() {
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_response(b"{}").await?;
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        assert!(matches!(conn.receive().await, Err(Error::ProtocolViolation(_))));
+
+        let mut identify = testing::identify_response();
+        identify["auth_required"] = true.into();
+        let addr = testing::serve_with(identify, |mut conn| async move {
+            conn.read_command().await?;
+            conn.write_ok().await?;
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+        let config = Config { auth_secret: Some(AuthToken::secret("secret")), ..Default::default() };
+        match Connection::connect(addr, &config).await {
+            Err(Error::ProtocolViolation(e)) => assert!(e.contains("AUTH"), "{}", e),
+            other => panic!("unexpected result: {:?}", other.map(|c| c.peer_addr())),
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_options_are_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            tcp_nodelay: true,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let tcp = dial(listener.local_addr().unwrap(), &config).await.unwrap();
+        assert!(tcp.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&tcp).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_heartbeats_fail_the_connection() {
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let addr = testing::serve(|conn| async move {
+            // Stay connected without ever sending a heartbeat
+            let _conn = conn;
+            let _ = done_rx.await;
+            Ok(())
+        }).await;
+
+        // The shortest interval nsqd accepts
+        let config = Config { heartbeat_interval: Some(Duration::from_secs(1)), ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        match conn.receive().await {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let _ = done_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn read_and_write_timeouts_fail_the_connection() {
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let addr = testing::serve(|conn| async move {
+            // Neither send nor read anything
+            let _conn = conn;
+            let _ = done_rx.await;
+            Ok(())
+        }).await;
+
+        let config = Config {
+            heartbeat_interval: None,
+            read_timeout: Some(Duration::from_millis(100)),
+            write_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        match conn.receive().await {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // More than the socket buffers hold
+        match conn.send(Command::Pub("test".into(), vec![0; 64 * 1024 * 1024])).await {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let _ = done_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn heartbeats_and_output_buffering_can_be_disabled() {
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            let body = conn.read_command().await?.unwrap().body.unwrap();
+            let identify: serde_json::Value = serde_json::from_slice(&body)?;
+            assert_eq!(identify["heartbeat_interval"], -1);
+            assert_eq!(identify["output_buffer_timeout"], -1);
+            let mut response = testing::identify_response();
+            response["output_buffer_timeout"] = (-1).into();
+            conn.write_response(response.to_string().as_bytes()).await?;
+            conn.write_message(b"0123456789abcdef", 1, b"unbuffered").await
+        }).await;
+
+        let config = Config { heartbeat_interval: None, output_buffer_timeout: None, ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        assert_eq!(conn.negotiated().heartbeat_interval, None);
+        assert_eq!(conn.identify().output_buffer_timeout, -1);
+        assert!(matches!(conn.receive().await, Ok(Response::Msg(_))));
+    }
+
+    #[tokio::test]
+    async fn connects_from_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = {
+            let reserved = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            reserved.local_addr().unwrap()
+        };
+        let config = Config { local_addr: Some(local_addr), ..Default::default() };
+        let tcp = dial(listener.local_addr().unwrap(), &config).await.unwrap();
+        assert_eq!(tcp.local_addr().unwrap(), local_addr);
+    }
+
+    #[tokio::test]
+    async fn commands_without_response_are_coalesced() {
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "RDY");
+            assert_eq!(conn.read_command().await?.unwrap().name, "FIN");
+            conn.write_ok().await
+        }).await;
+
+        let config = Config {
+            client_output_buffer_size: 1024,
+            client_output_buffer_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let conn = Connection::connect(addr, &config).await.unwrap();
+        let (mut sink, mut stream) = conn.split();
+        let start = tokio::time::Instant::now();
+        sink.feed(Command::Rdy(1)).await.unwrap();
+        sink.feed(Command::Fin("0123456789abcdef".parse().unwrap())).await.unwrap();
+        // Flushing waits for the deadline, without the stream being polled
+        sink.flush().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(matches!(stream.next().await.unwrap().unwrap(), Response::Ok));
+    }
+
+    #[tokio::test]
+    async fn sink_half_answers_heartbeats() {
+        let (names_tx, names_rx) = oneshot::channel();
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_response(b"_heartbeat_").await?;
+            conn.write_message(b"0123456789abcdef", 1, b"body").await?;
+            let first = conn.read_command().await?.unwrap().name;
+            let second = conn.read_command().await?.unwrap().name;
+            let _ = names_tx.send((first, second));
+            conn.read_to_end().await?;
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let (mut sink, mut stream) = conn.split();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sink.send(Command::Touch("0123456789abcdef".parse().unwrap())).await.unwrap();
+        let (first, second) = tokio::time::timeout(Duration::from_secs(1), names_rx).await.unwrap().unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("NOP", "TOUCH"));
+        assert!(matches!(stream.next().await, Some(Ok(Response::Msg(_)))));
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_handled_per_policy() {
+        async fn receive(on_invalid: OnInvalid) -> (Option<Result<Response, Error>>, String) {
+            let (cmd_tx, cmd_rx) = oneshot::channel();
+            let addr = testing::serve(|mut conn| async move {
+                conn.write_message(b"0123456789abcdef", 1, b"corrupt").await?;
+                conn.write_message(b"0123456789abcdeg", 1, b"valid").await?;
+                let cmd = conn.read_command().await?.map(|cmd| format!("{} {}", cmd.name, cmd.params.join(" ")));
+                let _ = cmd_tx.send(cmd.unwrap_or_default());
+                Ok(())
+            }).await;
+
+            let config = Config {
+                message_validator: Some(MessageValidator::new(on_invalid, |msg| match msg.body() {
+                    b"corrupt" => Err("checksum mismatch".into()),
+                    _ => Ok(()),
+                })),
+                ..Config::default()
+            };
+            let mut conn = Connection::connect(addr, &config).await.unwrap();
+            let first = conn.next().await;
+            // The connection outlives an invalid message, the valid one after it still arrives
+            if matches!(first, Some(Ok(Response::Invalid { .. }))) {
+                assert!(matches!(conn.next().await, Some(Ok(Response::Msg(msg))) if msg.body() == b"valid"));
+            }
+            drop(conn);
+            (first, cmd_rx.await.unwrap())
+        }
+
+        let (first, cmd) = receive(OnInvalid::Drop).await;
+        assert!(matches!(first, Some(Ok(Response::Msg(msg))) if msg.body() == b"valid"));
+        assert_eq!(cmd, "FIN 0123456789abcdef");
+
+        let (_, cmd) = receive(OnInvalid::Requeue(Duration::from_secs(2))).await;
+        assert_eq!(cmd, "REQ 0123456789abcdef 2000");
+
+        let (first, cmd) = receive(OnInvalid::Error).await;
+        match first {
+            Some(Ok(Response::Invalid { msg, reason })) => {
+                assert_eq!(msg.message_id.to_string(), "0123456789abcdef");
+                assert_eq!(reason, "checksum mismatch");
+            }
+            other => panic!("expected the message to be rejected, got {:?}", other),
+        }
+        assert_eq!(cmd, "");
+    }
+
+    #[tokio::test]
+    async fn stats_count_frames_and_heartbeats() {
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_response(b"_heartbeat_").await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "NOP");
+            conn.write_message(b"0123456789abcdef", 1, b"body").await?;
+            conn.write_error("E_FIN_FAILED FIN failed").await
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let negotiation = conn.stats();
+        assert!(negotiation.bytes_read > 0 && negotiation.bytes_written > 0);
+        assert!(matches!(conn.receive().await.unwrap(), Response::Msg(_)));
+        assert!(matches!(conn.receive().await.unwrap(), Response::Err(_)));
+
+        let stats = conn.stats();
+        assert_eq!((stats.responses, stats.messages, stats.errors), (1, 1, 1));
+        assert_eq!(stats.heartbeats_answered, 1);
+        assert!(stats.bytes_read > negotiation.bytes_read);
+        assert_eq!(stats.bytes_written, negotiation.bytes_written + b"NOP\n".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn subscribe_splits_the_connection() {
+        let addr = testing::serve(|mut conn| async move {
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!((cmd.name.as_str(), cmd.params.as_slice()), ("SUB", &["topic".to_string(), "channel".to_string()][..]));
+            conn.write_ok().await?;
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!((cmd.name.as_str(), cmd.params.as_slice()), ("RDY", &["5".to_string()][..]));
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!((cmd.name.as_str(), cmd.params.as_slice()), ("REQ", &["0123456789abcdef".to_string(), "1500".to_string()][..]));
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let (mut commands, mut messages) = conn.subscribe("topic", "channel").await.unwrap();
+        commands.rdy(5).await.unwrap();
+        commands.req("0123456789abcdef".parse().unwrap(), Duration::from_millis(1500)).await.unwrap();
+        let publish = commands.send(Command::Pub("topic".into(), b"msg".to_vec())).await;
+        assert!(matches!(publish, Err(Error::InvalidCommand(_))));
+        assert!(messages.next().await.is_none());
+        assert!(!messages.closed_cleanly());
+
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "SUB");
+            conn.write_ok().await?;
+            conn.write_response(b"CLOSE_WAIT").await?;
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let (_commands, mut messages) = conn.subscribe("topic", "channel").await.unwrap();
+        assert!(messages.next().await.is_none());
+        assert!(messages.closed_cleanly());
+        assert!(messages.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_read_ahead_by_the_sink_are_bounded() {
+        let addr = testing::serve(|mut conn| async move {
+            for _ in 0..MAX_RECEIVED + 10 {
+                conn.write_response(b"OK").await?;
+            }
+            while conn.read_command().await?.is_some() {}
+            Ok(())
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for _ in 0..2 {
+            conn.send(Command::Nop).await.unwrap();
+        }
+        assert_eq!(conn.transport.received.len(), MAX_RECEIVED);
+        for _ in 0..MAX_RECEIVED + 10 {
+            assert!(matches!(conn.receive().await.unwrap(), Response::Ok));
+        }
+    }
+
+    #[tokio::test]
+    async fn close_wait_is_told_apart_from_eof() {
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_response(b"CLOSE_WAIT").await
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        assert!(matches!(conn.receive().await.unwrap(), Response::CloseWait));
+        assert!(conn.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn publishing_after_subscribing_is_rejected() {
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "SUB");
+            conn.write_error("E_BAD_CHANNEL SUB channel name invalid").await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "SUB");
+            conn.write_response(b"OK").await?;
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        conn.send(Command::Sub("topic".into(), "channel".into())).await.unwrap();
+        assert!(conn.receive().await.is_err());
+        // Not subscribed, the SUB was rejected
+        conn.check_publish("topic").unwrap();
+
+        conn.send(Command::Sub("topic".into(), "channel".into())).await.unwrap();
+        conn.check_publish("topic").unwrap();
+        assert!(matches!(conn.receive().await.unwrap(), Response::Ok));
+        let err = conn.send(Command::Pub("topic".into(), b"body".to_vec())).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidCommand(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn frame_observer_sees_both_directions() {
+        use std::sync::{Arc, Mutex};
+        use crate::codec::{FrameEvent, FrameObserver, NsqFramed};
+
+        let addr = testing::serve(|mut conn| async move {
+            conn.read_command().await?.unwrap();
+            conn.write_ok().await
+        }).await;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let observer = FrameObserver::new(move |event| {
+            let event = match event {
+                FrameEvent::Encoded { command, len } => format!("> {} {}", command, len),
+                FrameEvent::Decoded { frame: NsqFramed::Response(_), len } => format!("< response {}", len),
+                FrameEvent::Decoded { .. } => "< other".to_string(),
+            };
+            seen.lock().unwrap().push(event);
+        });
+        let config = Config { frame_observer: Some(observer), ..Default::default() };
+        let mut conn = Connection::connect(addr, &config).await.unwrap();
+        conn.send(Command::Pub("topic".into(), b"body".to_vec())).await.unwrap();
+        conn.receive().await.unwrap();
+
+        let events = events.lock().unwrap();
+        // Magic, IDENTIFY and its response, then the publish
+        assert_eq!(events.len(), 5, "{:?}", events);
+        assert_eq!(events[0], ">   V2 4");
+        assert!(events[1].starts_with("> IDENTIFY "));
+        assert!(events[2].starts_with("< response "));
+        assert_eq!(events[3], format!("> PUB {}", "PUB topic\n".len() + 4 + 4));
+        assert_eq!(events[4], "< response 10");
+    }
+
+    #[test]
+    fn address_families_are_interleaved() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "127.0.0.1:1", "127.0.0.2:1"]
+            .iter().map(|a| a.parse().unwrap()).collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "127.0.0.1:1", "[::2]:1", "127.0.0.2:1", "[::3]:1"]
+            .iter().map(|a| a.parse().unwrap()).collect();
+        assert_eq!(interleave_families(addrs), expected);
+    }
+
+    #[tokio::test]
+    async fn connects_over_ipv6() {
+        let listener = match TcpListener::bind("[::1]:0").await {
+            Ok(listener) => listener,
+            // No IPv6 loopback in this environment
+            Err(_) => return,
+        };
+        let addr = listener.local_addr().unwrap();
+        let tcp = dial(("::1", addr.port()), &Config::default()).await.unwrap();
+        assert_eq!(tcp.peer_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn corked_commands_are_written_together() {
+        let addr = testing::serve(|mut conn| async move {
+            // All three in the first read
+            let mut buf = [0u8; 64];
+            let n = conn.read_raw(&mut buf).await?;
+            assert_eq!(&buf[..n], b"RDY 1\nTOUCH 0123456789abcdef\nNOP\n");
+            Ok(())
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        let written = conn.stats().bytes_written;
+        conn.cork();
+        conn.rdy(1).await.unwrap();
+        conn.touch("0123456789abcdef".parse().unwrap()).await.unwrap();
+        conn.send(Command::Nop).await.unwrap();
+        assert_eq!(conn.stats().bytes_written, written);
+        conn.uncork().await.unwrap();
+        assert!(conn.next().await.is_none());
     }
 }
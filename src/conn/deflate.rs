@@ -43,6 +43,8 @@ where
         Pin::new(&mut self.writer).poll_write(cx, buf)
     }
 
+    /// Ends the pending deflate block with a sync flush, so nsqd can decompress every command
+    /// written so far without waiting for the compressor to fill a block.
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
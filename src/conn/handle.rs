@@ -0,0 +1,202 @@
+//! A connection shared between tasks.
+//!
+//! [`Connection`] answers one command at a time: whoever sends has to read the response
+//! before anyone else may send. [`ConnectionHandle`] lifts that by moving the connection into
+//! a driver task which writes commands as they come, routes each response back to its
+//! sender, answers heartbeats and passes delivered messages on to [`Messages`].
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use tracing::{debug, warn, Instrument};
+
+use crate::codec::NsqMsg;
+use crate::command::{Command, MessageBody};
+use crate::config::Config;
+use crate::conn::{Connection, Response, ToAddrs};
+use crate::error::Error;
+
+type Reply = oneshot::Sender<Result<(), Error>>;
+
+/// Cheaply cloneable handle to a connection owned by a driver task, see the module docs.
+///
+/// The driver exits, closing the connection, once every handle was dropped or the
+/// connection failed.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    tx: mpsc::UnboundedSender<(Command, Reply)>,
+    peer_addr: SocketAddr,
+}
+
+/// Messages delivered on a [`ConnectionHandle`]'s connection, after a `SUB`.
+///
+/// Ends when the connection does. Messages rejected with `OnInvalid::Error` are skipped, left
+/// in flight for nsqd to redeliver after its timeout.
+pub struct Messages {
+    rx: mpsc::UnboundedReceiver<NsqMsg>,
+}
+
+impl ConnectionHandle {
+    pub async fn connect<A: ToAddrs>(addr: A, config: &Config) -> Result<(Self, Messages), Error> {
+        let conn = Connection::connect(addr, config).await?;
+        Ok(Self::spawn(conn))
+    }
+
+    /// Move `conn` into a driver task.
+    pub fn spawn(conn: Connection) -> (Self, Messages) {
+        let (tx, requests) = mpsc::unbounded();
+        let (messages_tx, messages) = mpsc::unbounded();
+        let peer_addr = conn.peer_addr();
+        let span = conn.span().clone();
+        tokio::spawn(drive(conn, requests, messages_tx).instrument(span));
+        (Self { tx, peer_addr }, Messages { rx: messages })
+    }
+
+    /// Send `cmd`, then wait for nsqd's response if it gets one, e.g. a `PUB`.
+    ///
+    /// Commands without a response, like `FIN`, complete once written. If they fail nsqd
+    /// answers with an error which can't be told apart from that of any other, so it is only
+    /// logged.
+    pub async fn send(&self, cmd: Command) -> Result<(), Error> {
+        let (reply, response) = oneshot::channel();
+        self.tx.unbounded_send((cmd, reply)).map_err(|_| closed())?;
+        response.await.map_err(|_| closed())?
+    }
+
+    /// Publish a message to a topic.
+    pub async fn publish(&self, topic: impl Into<String>, msg: impl Into<MessageBody>) -> Result<(), Error> {
+        self.send(Command::Pub(topic.into(), msg.into())).await
+    }
+
+    /// The address of the nsqd this connection is connected to
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Whether the driver exited, after which every command fails.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl Stream for Messages {
+    type Item = NsqMsg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+fn closed() -> Error {
+    io::Error::new(io::ErrorKind::NotConnected, "connection closed").into()
+}
+
+async fn drive(
+    mut conn: Connection,
+    mut requests: mpsc::UnboundedReceiver<(Command, Reply)>,
+    messages: mpsc::UnboundedSender<NsqMsg>,
+) {
+    // Senders of the commands still waiting for a response, in the order they were sent
+    let mut pending: VecDeque<Reply> = VecDeque::new();
+    let error = loop {
+        tokio::select! {
+            request = requests.next() => {
+                let (cmd, reply) = match request {
+                    Some(request) => request,
+                    None => break None,
+                };
+                let expects_response = cmd.expects_response();
+                if let Err(e) = conn.send(cmd).await {
+                    let _ = reply.send(Err(e));
+                    break Some("write failed");
+                }
+                if expects_response {
+                    pending.push_back(reply);
+                } else {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            response = conn.next() => {
+                let result = match response {
+                    Some(Ok(Response::Msg(msg))) => {
+                        if messages.unbounded_send(msg).is_err() {
+                            debug!("messages dropped, discard message");
+                        }
+                        continue;
+                    }
+                    // Left in flight for nsqd to redeliver after its timeout
+                    Some(Ok(Response::Invalid { msg, reason })) => {
+                        warn!("skip invalid message {}: {}", msg.message_id, reason);
+                        continue;
+                    }
+                    Some(Ok(Response::Ok)) => Ok(()),
+                    // A failed FIN, REQ or TOUCH, which didn't wait for a response
+                    Some(Ok(Response::Err(e))) if !e.is_fatal() => {
+                        debug!("command failed: {}", e);
+                        continue;
+                    }
+                    Some(Ok(Response::Err(e))) => Err(e.into()),
+                    Some(Err(e)) => {
+                        debug!("connection failed: {}", e);
+                        if let Some(reply) = pending.pop_front() {
+                            let _ = reply.send(Err(e));
+                        }
+                        break Some("connection failed");
+                    }
+                    Some(Ok(Response::CloseWait)) | None => break Some("connection closed"),
+                };
+                match pending.pop_front() {
+                    Some(reply) => { let _ = reply.send(result); }
+                    None => debug!("unexpected response: {:?}", result),
+                }
+            }
+        }
+    };
+    if let Some(reason) = error {
+        for reply in pending {
+            let _ = reply.send(Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason).into()));
+        }
+    }
+    debug!("exit connection driver");
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::config::Config;
+    use crate::testing;
+    use super::ConnectionHandle;
+
+    #[tokio::test]
+    async fn responses_are_routed_to_their_senders() {
+        let addr = testing::serve(|mut conn| async move {
+            // Both publishes are in flight before either is answered
+            let first = conn.read_command().await?.unwrap();
+            let second = conn.read_command().await?.unwrap();
+            assert_eq!(first.body.as_deref(), Some(&b"first"[..]));
+            assert_eq!(second.body.as_deref(), Some(&b"second"[..]));
+            conn.write_message(b"0123456789abcdef", 1, b"delivered").await?;
+            conn.write_ok().await?;
+            conn.write_error("E_PUB_FAILED publish failed").await
+        }).await;
+
+        let (handle, mut messages) = ConnectionHandle::connect(addr, &Config::default()).await.unwrap();
+        let other = handle.clone();
+        let first = tokio::spawn(async move { handle.publish("test", "first").await });
+        let second = async {
+            // Make sure the first publish is written first
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            other.publish("test", "second").await
+        };
+        let (first, second) = futures::join!(first, second);
+        assert!(first.unwrap().is_ok());
+        assert!(second.is_err());
+        assert_eq!(&messages.next().await.unwrap().body[..], b"delivered");
+    }
+}
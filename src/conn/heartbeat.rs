@@ -1,11 +1,20 @@
+use std::collections::VecDeque;
+use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 use futures::prelude::*;
 use futures::ready;
 use tokio_util::codec::Framed;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, Sleep};
+use tracing::warn;
 
 use crate::codec::NsqCodec;
+use crate::conn::ratelimit::{RateLimit, TokenBucket};
+use crate::conn::stats::Counters;
+use crate::conn::validate::{MessageValidator, OnInvalid};
 use crate::error::Error;
 use crate::{
     codec::{
@@ -18,10 +27,49 @@ use crate::command::Command;
 
 type InnerFramed<T> = Framed<T, NsqCodec>;
 
+/// Most frames the sink reads ahead for the stream. Past that it stops reading, and leaves
+/// heartbeats unanswered, until the stream takes some.
+pub(crate) const MAX_RECEIVED: usize = 1024;
+
 pub struct Heartbeat<T> {
     inner: InnerFramed<T>,
     response_remaining: usize,
     status: Status,
+    liveness: Option<Liveness>,
+    write_timeout: Option<WriteTimeout>,
+    coalesce: Option<Coalesce>,
+    counters: Arc<Counters>,
+    rate_limit: Option<TokenBucket>,
+    // Read by the sink to answer heartbeats, for the stream to yield, up to `MAX_RECEIVED`
+    pub(super) received: VecDeque<Option<Result<Response, Error>>>,
+    // The task last waiting on the stream, see `poll_receive`
+    reader: Option<Waker>,
+    validator: Option<MessageValidator>,
+    // FIN or REQ of messages the validator rejected, sent along with heartbeat answers
+    rejected: Vec<Command>,
+}
+
+/// Deadline for the next frame from nsqd, which sends at least a heartbeat per interval.
+struct Liveness {
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+/// Deadline for a pending flush to make progress, see `Config::write_timeout`.
+struct WriteTimeout {
+    timeout: Duration,
+    // Set while a flush is pending
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+/// Holds back flushes of commands which get no response, see `Config::client_output_buffer_size`.
+struct Coalesce {
+    size: usize,
+    timeout: Duration,
+    // Set while commands are held back
+    deadline: Option<Pin<Box<Sleep>>>,
+    // Whether a command waiting for a response is buffered
+    urgent: bool,
 }
 
 enum Status {
@@ -33,8 +81,140 @@ impl<T> Heartbeat<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    pub(crate) fn new(inner: InnerFramed<T>) -> Self {
-        Self { inner, response_remaining: 0, status: Status::Reading }
+    /// With a `liveness_timeout`, the stream fails if nothing is received for that long.
+    /// Without one, as when heartbeats are disabled, an idle connection is never failed.
+    pub(crate) fn new(inner: InnerFramed<T>, liveness_timeout: Option<Duration>) -> Self {
+        let liveness = liveness_timeout.map(|timeout| Liveness {
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+        });
+        Self {
+            inner,
+            response_remaining: 0,
+            status: Status::Reading,
+            liveness,
+            write_timeout: None,
+            coalesce: None,
+            counters: Arc::default(),
+            rate_limit: None,
+            received: VecDeque::new(),
+            reader: None,
+            validator: None,
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Fail flushes which don't complete within `timeout`.
+    pub(crate) fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout.map(|timeout| WriteTimeout { timeout, deadline: None });
+        self
+    }
+
+    /// Hold back commands without a response until `size` bytes are buffered or for `timeout`.
+    pub(crate) fn with_coalescing(mut self, size: usize, timeout: Duration) -> Self {
+        if size > 0 && !timeout.is_zero() {
+            self.coalesce = Some(Coalesce { size, timeout, deadline: None, urgent: false });
+        }
+        self
+    }
+
+    /// Count frames in `counters`, shared with whoever counts the bytes under them.
+    pub(crate) fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Hold back commands sent through the sink beyond `limit`. Heartbeat responses aren't.
+    pub(crate) fn with_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.rate_limit = limit.map(TokenBucket::new);
+        self
+    }
+
+    /// Check received messages with `validator` before yielding them.
+    pub(crate) fn with_validator(mut self, validator: Option<MessageValidator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// Like `start_send` of `Command::Mpub`, encoding the messages as they are iterated.
+    pub(crate) fn start_send_mpub<I, B>(&mut self, topic: &str, msgs: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        if let Some(ref mut coalesce) = self.coalesce {
+            coalesce.urgent = true;
+        }
+        let mut buf = std::mem::take(self.inner.write_buffer_mut());
+        let buffered = buf.len();
+        let encoded = self.inner.codec_mut().encode_mpub(topic, msgs, &mut buf);
+        let len = buf.len() - buffered;
+        *self.inner.write_buffer_mut() = buf;
+        encoded?;
+        if let Some(ref mut rate_limit) = self.rate_limit {
+            rate_limit.consume(len);
+        }
+        Ok(())
+    }
+
+    /// Whether held back commands are due, registering for the deadline if not.
+    fn poll_coalesce_deadline(&mut self, cx: &mut Context) -> bool {
+        match self.coalesce {
+            Some(Coalesce { deadline: Some(ref mut deadline), .. }) => deadline.as_mut().poll(cx).is_ready(),
+            _ => false,
+        }
+    }
+
+    /// Flush, including commands held back for coalescing.
+    pub(crate) fn poll_flush_now(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        ready!(self.poll_write(cx)?);
+        if let Some(ref mut coalesce) = self.coalesce {
+            coalesce.deadline = None;
+            coalesce.urgent = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Flush what is buffered, failing if nothing could be written for the write timeout.
+    fn poll_write(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let buffered = self.inner.write_buffer().len();
+        let res = Pin::new(&mut self.inner).poll_flush(cx);
+        let write_timeout = match self.write_timeout {
+            Some(ref mut write_timeout) => write_timeout,
+            None => return res,
+        };
+        if res.is_ready() {
+            write_timeout.deadline = None;
+            return res;
+        }
+        // The deadline only covers a stall, slowly draining a large buffer is fine
+        if self.inner.write_buffer().len() < buffered {
+            write_timeout.deadline = None;
+        }
+        let timeout = write_timeout.timeout;
+        let deadline = write_timeout.deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        ready!(deadline.as_mut().poll(cx));
+        write_timeout.deadline = None;
+        let msg = format!("nothing could be written to nsqd for {:?}, connection is stuck", timeout);
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, msg).into()))
+    }
+
+    fn poll_liveness(&mut self, cx: &mut Context) -> Poll<Error> {
+        match self.liveness {
+            Some(ref mut liveness) => {
+                ready!(liveness.deadline.as_mut().poll(cx));
+                let msg = format!("nothing received from nsqd for {:?}, connection is dead", liveness.timeout);
+                Poll::Ready(io::Error::new(io::ErrorKind::TimedOut, msg).into())
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn reset_liveness(&mut self) {
+        if let Some(ref mut liveness) = self.liveness {
+            let deadline = Instant::now() + liveness.timeout;
+            liveness.deadline.as_mut().reset(deadline);
+        }
     }
 
     fn start_pong(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
@@ -42,38 +222,45 @@ where
         while self.response_remaining > 0 {
             Pin::new(&mut self.inner).start_send(Command::Nop)?;
             self.response_remaining -= 1;
+            self.counters.heartbeat_answered();
+        }
+        for cmd in std::mem::take(&mut self.rejected) {
+            Pin::new(&mut self.inner).start_send(cmd)?;
         }
         self.status = Status::Responding;
         Poll::Ready(Ok(()))
     }
 
     fn poll_pong(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
-        ready!(Pin::new(&mut self.inner).poll_flush(cx)?);
+        ready!(self.poll_write(cx)?);
         self.status = Status::Reading;
         Poll::Ready(Ok(()))
     }
-}
 
-impl<T> Stream for Heartbeat<T>
-where
-    T: AsyncRead + AsyncWrite + Unpin,
-{
-    type Item = Result<Response, Error>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        if self.response_remaining > 0 {
+    /// Finish answering the heartbeats received so far, and sending FIN or REQ of the
+    /// messages rejected.
+    fn poll_answer_heartbeats(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        if self.response_remaining > 0 || !self.rejected.is_empty() {
             ready!(self.as_mut().start_pong(cx)?);
         }
-
         match self.status {
-            Status::Responding => {
-                ready!(self.as_mut().poll_pong(cx)?);
-            }
-            Status::Reading => {}
+            Status::Responding => self.poll_pong(cx),
+            Status::Reading => Poll::Ready(Ok(())),
         }
+    }
 
+    /// Read the next frame other than a heartbeat, answering heartbeats on the way.
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Response, Error>>> {
         loop {
-            match ready!(Pin::new(&mut self.as_mut().inner).poll_next(cx)) {
+            let frame = ready!(Pin::new(&mut self.inner).poll_next(cx));
+            self.reset_liveness();
+            match frame {
+                Some(Ok(NsqFramed::Response(_))) => self.counters.response(),
+                Some(Ok(NsqFramed::Error(_))) => self.counters.error(),
+                Some(Ok(NsqFramed::Message(_))) => self.counters.message(),
+                Some(Err(_)) | None => {}
+            }
+            match frame {
                 Some(Ok(msg)) => {
                     match msg {
                         NsqFramed::Response(RawResponse::Ok) => {
@@ -82,22 +269,35 @@ where
                         // Handling heartbeat
                         NsqFramed::Response(RawResponse::Heartbeat) => {
                             self.response_remaining += 1;
-                            ready!(self.as_mut().start_pong(cx))?;
-                            // poll pong
-                            ready!(self.as_mut().poll_pong(cx))?;
+                            ready!(self.as_mut().poll_answer_heartbeats(cx))?;
                             continue;
                         }
                         NsqFramed::Response(RawResponse::CloseWait) => {
-                            return Poll::Ready(None);
+                            return Poll::Ready(Some(Ok(Response::CloseWait)));
                         }
+                        // Only IDENTIFY and AUTH are answered with JSON, both during negotiation
                         NsqFramed::Response(RawResponse::Json(_)) => {
-                            // Not possible
-                            // TODO
-                            // return Poll::Ready(None);
-                            unreachable!();
+                            let violation = "JSON response outside of negotiation".into();
+                            return Poll::Ready(Some(Err(Error::ProtocolViolation(violation))));
                         }
                         NsqFramed::Message(msg) => {
-                            return Poll::Ready(Some(Ok(Response::Msg(msg))));
+                            let validator = match self.validator {
+                                Some(ref validator) => validator.clone(),
+                                None => return Poll::Ready(Some(Ok(Response::Msg(msg)))),
+                            };
+                            let reason = match (validator.check)(&msg) {
+                                Ok(()) => return Poll::Ready(Some(Ok(Response::Msg(msg)))),
+                                Err(reason) => reason,
+                            };
+                            warn!("message {} is invalid: {}", msg.message_id, reason);
+                            let cmd = match validator.on_invalid {
+                                OnInvalid::Drop => Command::Fin(msg.message_id),
+                                OnInvalid::Requeue(delay) => Command::Req(msg.message_id, delay.as_millis() as u64),
+                                OnInvalid::Error => return Poll::Ready(Some(Ok(Response::Invalid { msg, reason }))),
+                            };
+                            self.rejected.push(cmd);
+                            ready!(self.as_mut().poll_answer_heartbeats(cx))?;
+                            continue;
                         }
                         NsqFramed::Error(nsq_error) => {
                             if nsq_error.is_fatal() {
@@ -117,6 +317,60 @@ where
             }
         }
     }
+
+    /// Read what has arrived from the sink side, so heartbeats are answered even if the
+    /// stream half of a split connection is never polled. Frames are kept for the stream.
+    fn poll_receive(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_answer_heartbeats(cx)?);
+        if matches!(self.received.back(), Some(None | Some(Err(_)))) {
+            return Poll::Ready(Ok(()));
+        }
+        while self.received.len() < MAX_RECEIVED {
+            let item = match self.as_mut().poll_frame(cx) {
+                Poll::Ready(item) => item,
+                Poll::Pending => break,
+            };
+            let end = !matches!(item, Some(Ok(_)));
+            self.received.push_back(item);
+            if end {
+                break;
+            }
+        }
+        // Reading registered this task for the socket instead of the one waiting on the
+        // stream, wake that one up to register again and take what was received
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Stream for Heartbeat<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Response, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.poll_coalesce_deadline(cx) {
+            ready!(self.poll_flush_now(cx)?);
+        }
+        ready!(self.as_mut().poll_answer_heartbeats(cx)?);
+
+        if let Some(item) = self.received.pop_front() {
+            return Poll::Ready(item);
+        }
+        match self.as_mut().poll_frame(cx) {
+            Poll::Ready(item) => Poll::Ready(item),
+            // Only check the deadline once nothing is buffered, frames which arrived while
+            // the stream wasn't polled still prove the connection alive
+            Poll::Pending => {
+                self.reader = Some(cx.waker().clone());
+                let e = ready!(self.poll_liveness(cx));
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
 }
 
 impl<T> Sink<Command> for Heartbeat<T>
@@ -126,15 +380,39 @@ where
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_receive(cx)?);
+        if let Some(ref mut rate_limit) = self.rate_limit {
+            ready!(rate_limit.poll_acquire(cx));
+        }
         Pin::new(&mut self.inner).poll_ready(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Command) -> Result<(), Self::Error> {
-        Pin::new(&mut self.inner).start_send(item)
+        if let Some(ref mut coalesce) = self.coalesce {
+            coalesce.urgent |= item.expects_response();
+        }
+        let buffered = self.inner.write_buffer().len();
+        Pin::new(&mut self.inner).start_send(item)?;
+        let len = self.inner.write_buffer().len() - buffered;
+        if let Some(ref mut rate_limit) = self.rate_limit {
+            rate_limit.consume(len);
+        }
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        ready!(self.as_mut().poll_receive(cx)?);
+        let this = &mut *self;
+        if let Some(ref mut coalesce) = this.coalesce {
+            let buffered = this.inner.write_buffer().len();
+            if buffered > 0 && buffered < coalesce.size && !coalesce.urgent {
+                let timeout = coalesce.timeout;
+                let deadline = coalesce.deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                // Written once due, by whichever half gets there first
+                ready!(deadline.as_mut().poll(cx));
+            }
+        }
+        this.poll_flush_now(cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
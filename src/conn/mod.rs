@@ -1,174 +1,98 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use crate::error::{Error, NsqError};
-use crate::codec::{NsqMsg, NsqFramed};
-use crate::command::Command;
+use crate::error::NsqError;
+pub use crate::codec::{FrameEvent, FrameObserver, NsqMsg, NsqFramed, RawResponse};
 
-use pin_project::pin_project;
-use futures::prelude::*;
-use tokio_snappy::SnappyIO;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::TcpStream;
-use self::deflate::DeflateStream;
 
+mod auth;
 mod deflate;
+mod handle;
 mod heartbeat;
+mod ratelimit;
+mod resolve;
+mod rewind;
+mod stats;
+mod subscription;
 mod tls;
+mod transport;
+mod validate;
 pub mod connection;
+pub mod reconnect;
 
-pub(crate) trait Transport: Stream<Item = Result<NsqFramed, Error>> + Sink<Command, Error = Error> + Unpin {}
-pub(crate) trait MessageStream: Stream<Item = Result<Response, Error>> + Sink<Command, Error = Error> + Unpin {}
 pub(crate) use heartbeat::Heartbeat;
-use self::tls::TlsStream;
-pub use connection::Connection;
+pub use auth::{AuthToken, AuthTokenProvider};
+pub use connection::{AuthHook, AuthResponse, Connection, CloseReport, IdentifyResponse, Negotiated};
+pub use handle::{ConnectionHandle, Messages};
+pub use ratelimit::RateLimit;
+pub use resolve::{GaiResolver, Resolve, Resolver, ToAddrs};
+pub use stats::ConnectionStats;
+pub use subscription::{CommandSender, MessageStream};
+pub use transport::NsqTransport;
+pub use validate::{MessageValidator, OnInvalid};
+pub use self::tls::CertificatePin;
+pub use reconnect::{reconnecting, reconnecting_with, ConnectError, Reconnect, ReconnectingConnection, ReconnectPolicy, AT_CAPACITY_DELAY};
+#[cfg(feature = "tls-tokio")]
+pub use self::tls::{SessionCache, ResumptionStats};
 
 #[derive(Debug)]
 pub enum Response {
     Ok,
     Err(NsqError),
     Msg(NsqMsg),
+    /// A message `Config::message_validator` rejected with `OnInvalid::Error`, and why. The
+    /// connection carries on, the message stays in flight until finished or requeued.
+    Invalid { msg: NsqMsg, reason: String },
+    /// nsqd acknowledged `CLS` and is about to close the connection, unlike the stream just
+    /// ending when the connection drops
+    CloseWait,
 }
 
-#[pin_project(project = BaseIoProj)]
-pub enum BaseIo
-{
-    Snappy(#[pin] SnappyIO<TcpStream>),
-    SnappyTls(#[pin] SnappyIO<TlsStream<TcpStream>>),
-    Deflate(#[pin] DeflateStream<TcpStream>),
-    DeflateTls(#[pin] DeflateStream<TlsStream<TcpStream>>),
-    NoCompress(#[pin] TcpStream),
-    NoCompressTsl(#[pin] TlsStream<TcpStream>),
+/// The byte stream under the NSQ framing: TCP, possibly wrapped in TLS and compression.
+///
+/// Layers are stacked at runtime, each boxing the one below, so any combination of them
+/// works without being spelled out.
+pub struct BaseIo {
+    inner: Box<dyn Layer>,
+}
+
+trait Layer: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T> Layer for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl BaseIo {
+    pub(crate) fn new<T>(io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self { inner: Box::new(io) }
+    }
 }
 
 impl AsyncRead for BaseIo {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        let this: BaseIoProj = self.project();
-        match this {
-            BaseIoProj::Snappy(s) => {
-                let s: Pin<&mut SnappyIO<TcpStream>> = s;
-                s.poll_read(cx, buf)
-            }
-            BaseIoProj::SnappyTls(s) => {
-                let s: Pin<&mut SnappyIO<TlsStream<TcpStream>>> = s;
-                s.poll_read(cx, buf)
-            }
-            BaseIoProj::Deflate(s) => {
-                let s: Pin<&mut DeflateStream<TcpStream>> = s;
-                s.poll_read(cx, buf)
-            }
-            BaseIoProj::DeflateTls(s) => {
-                let s: Pin<&mut DeflateStream<TlsStream<TcpStream>>> = s;
-                s.poll_read(cx, buf)
-            }
-            BaseIoProj::NoCompress(s) => {
-                let s: Pin<&mut TcpStream> = s;
-                s.poll_read(cx, buf)
-            }
-            BaseIoProj::NoCompressTsl(s) => {
-                let s: Pin<&mut TlsStream<TcpStream>> = s;
-                s.poll_read(cx, buf)
-            }
-        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
     }
 }
 
 impl AsyncWrite for BaseIo {
     fn poll_write(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        let this: BaseIoProj = self.project();
-        match this {
-            BaseIoProj::Snappy(s) => {
-                let s: Pin<&mut SnappyIO<TcpStream>> = s;
-                s.poll_write(cx, buf)
-            }
-            BaseIoProj::SnappyTls(s) => {
-                let s: Pin<&mut SnappyIO<TlsStream<TcpStream>>> = s;
-                s.poll_write(cx, buf)
-            }
-            BaseIoProj::Deflate(s) => {
-                let s: Pin<&mut DeflateStream<TcpStream>> = s;
-                s.poll_write(cx, buf)
-            }
-            BaseIoProj::DeflateTls(s) => {
-                let s: Pin<&mut DeflateStream<TlsStream<TcpStream>>> = s;
-                s.poll_write(cx, buf)
-            }
-            BaseIoProj::NoCompress(s) => {
-                let s: Pin<&mut TcpStream> = s;
-                s.poll_write(cx, buf)
-            }
-            BaseIoProj::NoCompressTsl(s) => {
-                let s: Pin<&mut TlsStream<TcpStream>> = s;
-                s.poll_write(cx, buf)
-            }
-        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        let this: BaseIoProj = self.project();
-        match this {
-            BaseIoProj::Snappy(s) => {
-                let s: Pin<&mut SnappyIO<TcpStream>> = s;
-                s.poll_flush(cx)
-            }
-            BaseIoProj::SnappyTls(s) => {
-                let s: Pin<&mut SnappyIO<TlsStream<TcpStream>>> = s;
-                s.poll_flush(cx)
-            }
-            BaseIoProj::Deflate(s) => {
-                let s: Pin<&mut DeflateStream<TcpStream>> = s;
-                s.poll_flush(cx)
-            }
-            BaseIoProj::DeflateTls(s) => {
-                let s: Pin<&mut DeflateStream<TlsStream<TcpStream>>> = s;
-                s.poll_flush(cx)
-            }
-            BaseIoProj::NoCompress(s) => {
-                let s: Pin<&mut TcpStream> = s;
-                s.poll_flush(cx)
-            }
-            BaseIoProj::NoCompressTsl(s) => {
-                let s: Pin<&mut TlsStream<TcpStream>> = s;
-                s.poll_flush(cx)
-            }
-        }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        let this: BaseIoProj = self.project();
-        match this {
-            BaseIoProj::Snappy(s) => {
-                let s: Pin<&mut SnappyIO<TcpStream>> = s;
-                s.poll_shutdown(cx)
-            }
-            BaseIoProj::SnappyTls(s) => {
-                let s: Pin<&mut SnappyIO<TlsStream<TcpStream>>> = s;
-                s.poll_shutdown(cx)
-            }
-            BaseIoProj::Deflate(s) => {
-                let s: Pin<&mut DeflateStream<TcpStream>> = s;
-                s.poll_shutdown(cx)
-            }
-            BaseIoProj::DeflateTls(s) => {
-                let s: Pin<&mut DeflateStream<TlsStream<TcpStream>>> = s;
-                s.poll_shutdown(cx)
-            }
-            BaseIoProj::NoCompress(s) => {
-                let s: Pin<&mut TcpStream> = s;
-                s.poll_shutdown(cx)
-            }
-            BaseIoProj::NoCompressTsl(s) => {
-                let s: Pin<&mut TlsStream<TcpStream>> = s;
-                s.poll_shutdown(cx)
-            }
-        }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
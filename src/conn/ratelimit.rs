@@ -0,0 +1,93 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use tokio::time::{Instant, Sleep};
+
+/// Bound on the outbound command rate of a connection, see `Config::rate_limit`.
+///
+/// Up to a second's worth may be sent in a burst after being idle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimit {
+    CommandsPerSecond(u32),
+    /// Counted as encoded, before compression
+    BytesPerSecond(u64),
+}
+
+impl RateLimit {
+    fn per_second(&self) -> f64 {
+        match *self {
+            RateLimit::CommandsPerSecond(n) => n.max(1) as f64,
+            RateLimit::BytesPerSecond(n) => n.max(1) as f64,
+        }
+    }
+}
+
+/// Token bucket going into debt: a command is let through while any tokens are left and its
+/// whole cost is taken afterwards, as the size of a command is only known once encoded.
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    delay: Pin<Box<Sleep>>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            tokens: limit.per_second(),
+            refilled: now,
+            delay: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    /// Ready once a command may be sent.
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let rate = self.limit.per_second();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(rate);
+            self.refilled = now;
+            if self.tokens > 0.0 {
+                return Poll::Ready(());
+            }
+            let wait = Duration::from_secs_f64(-self.tokens / rate) + Duration::from_millis(1);
+            self.delay.as_mut().reset(now + wait);
+            ready!(self.delay.as_mut().poll(cx));
+        }
+    }
+
+    /// Charge for a command of `len` encoded bytes.
+    pub(crate) fn consume(&mut self, len: usize) {
+        self.tokens -= match self.limit {
+            RateLimit::CommandsPerSecond(_) => 1.0,
+            RateLimit::BytesPerSecond(_) => len as f64,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::future;
+    use super::{RateLimit, TokenBucket};
+
+    #[tokio::test]
+    async fn commands_beyond_the_burst_are_delayed() {
+        let mut bucket = TokenBucket::new(RateLimit::CommandsPerSecond(20));
+        let start = Instant::now();
+        for _ in 0..25 {
+            future::poll_fn(|cx| bucket.poll_acquire(cx)).await;
+            bucket.consume(0);
+        }
+        // 20 go out right away, then one every 50ms
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+}
@@ -1,63 +1,240 @@
-use std::time::Duration;
+//! Reconnect a transport whenever it fails.
+//!
+//! [`Reconnect`] wraps a factory of connections into a `Stream` and `Sink` which connects on
+//! first use and again after every error or EOF, waiting as its [`ReconnectPolicy`] says.
+//! State tied to a connection, e.g. a `SUB`, is lost with it and has to be sent again.
+//!
+//! A node refusing connections for being at capacity, see [`Error::is_at_capacity`], is
+//! retried after [`ReconnectPolicy::at_capacity_delay`] instead, longer than usual. Connect
+//! errors which retrying can't fix, see [`ConnectError::is_transient`], are returned instead.
+//!
+//! Neither [`Producer`](crate::Producer) nor subscriptions reconnect on their own yet, wrap
+//! their connection with [`reconnecting`] to get that.
+
+use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use pin_project::pin_project;
-use futures::{ready, Future, Stream, Sink};
+use futures::{future::BoxFuture, ready, Future, FutureExt, Stream, Sink};
 use tokio::time::Sleep;
+use tracing::debug;
 
-#[pin_project]
-pub(crate) struct Reconnect<F, S, M> {
-    #[pin]
-    state: State<F, S, Sleep>,
-    strategy: Strategy,
-    mk_connection: M,
-    attempts: u32,
+use crate::backoff::Backoff;
+use crate::config::{BackoffConfig, Config};
+use crate::conn::{Connection, ToAddrs};
+use crate::error::Error;
+
+/// The shortest delay before reconnecting to a node at capacity, by default.
+pub const AT_CAPACITY_DELAY: Duration = Duration::from_secs(10);
+
+/// Decides how long to wait before each reconnect attempt.
+pub trait ReconnectPolicy: Send + Sync {
+    /// Delay before reconnect attempt number `attempt`, starting from 1, or `None` to give up.
+    fn next_delay(&self, attempt: u32) -> Option<Duration>;
+
+    /// Delay before attempt number `attempt` when the last one failed with the node at
+    /// capacity. Four times `next_delay` and at least [`AT_CAPACITY_DELAY`] by default, so a
+    /// full node isn't retried in a tight loop.
+    fn at_capacity_delay(&self, attempt: u32) -> Option<Duration> {
+        self.next_delay(attempt).map(|delay| (delay * 4).max(AT_CAPACITY_DELAY))
+    }
+
+    /// Give up once `max_attempts` reconnect attempts failed.
+    fn give_up_after(self, max_attempts: u32) -> GiveUpAfter<Self>
+    where
+        Self: Sized,
+    {
+        GiveUpAfter { policy: self, max_attempts }
+    }
+}
+
+/// Capped exponential delays, with jitter if configured.
+impl ReconnectPolicy for Backoff {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        Some(self.delay(attempt))
+    }
+}
+
+/// The delays of `Config::backoff`, giving up after its `max_attempts`.
+impl ReconnectPolicy for BackoffConfig {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        self.delay(attempt)
+    }
+}
+
+/// Reconnect right away, forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Immediate;
+
+impl ReconnectPolicy for Immediate {
+    fn next_delay(&self, _attempt: u32) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+}
+
+/// Reconnect at a fixed interval, forever.
+#[derive(Debug, Clone, Copy)]
+pub struct Fixed(pub Duration);
+
+impl ReconnectPolicy for Fixed {
+    fn next_delay(&self, _attempt: u32) -> Option<Duration> {
+        Some(self.0)
+    }
+}
+
+/// See [`ReconnectPolicy::give_up_after`].
+#[derive(Debug, Clone)]
+pub struct GiveUpAfter<P> {
+    policy: P,
+    max_attempts: u32,
 }
 
-pub enum Strategy {
-    Immediat,
-    Exponential(Duration),
+impl<P: ReconnectPolicy> ReconnectPolicy for GiveUpAfter<P> {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt > self.max_attempts {
+            return None;
+        }
+        self.policy.next_delay(attempt)
+    }
+
+    fn at_capacity_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt > self.max_attempts {
+            return None;
+        }
+        self.policy.at_capacity_delay(attempt)
+    }
+}
+
+/// An error failing a connection, telling whether the node is at capacity.
+pub trait ConnectError: std::fmt::Debug {
+    fn is_at_capacity(&self) -> bool;
+
+    /// Whether connecting again may succeed, `false` ends reconnecting with this error.
+    fn is_transient(&self) -> bool {
+        true
+    }
 }
 
-enum State<F, S, D> {
+impl ConnectError for Error {
+    fn is_at_capacity(&self) -> bool {
+        Error::is_at_capacity(self)
+    }
+
+    /// Settings nsqd or the client reject, and failed authorization, are not.
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::InvalidConfig { .. } | Error::Auth(_) => false,
+            #[cfg(feature = "tls-tokio")]
+            Error::InvalidDnsNameError(_) => false,
+            Error::NsqError(e) => !matches!(e.code(), "E_AUTH_FAILED" | "E_UNAUTHORIZED" | "E_AUTH_DISABLED"),
+            _ => true,
+        }
+    }
+}
+
+impl ConnectError for io::Error {
+    fn is_at_capacity(&self) -> bool {
+        false
+    }
+}
+
+pub struct Reconnect<F, S, M> {
+    state: State<F, S>,
+    policy: Box<dyn ReconnectPolicy>,
+    mk_connection: M,
+    attempts: u32,
+    at_capacity: bool,
+}
+
+enum State<F, S> {
     Idle,
-    Delaying(D),
+    Delaying(Pin<Box<Sleep>>),
     Connecting(F),
     Connected(S),
+    GaveUp,
+}
+
+/// A [`Connection`] which reconnects to the same nsqd, see [`reconnecting`].
+pub type ReconnectingConnection = Reconnect<
+    BoxFuture<'static, Result<Connection, Error>>,
+    Connection,
+    Box<dyn Fn() -> BoxFuture<'static, Result<Connection, Error>> + Send + Sync>,
+>;
+
+/// Connect to `addr` lazily, and again whenever the connection fails, waiting as
+/// `config.backoff` says.
+pub fn reconnecting<A>(addr: A, config: Config) -> ReconnectingConnection
+where
+    A: ToAddrs + Clone + Send + Sync + 'static,
+{
+    let policy = config.backoff;
+    reconnecting_with(addr, config, policy)
+}
+
+/// Like [`reconnecting`], waiting as `policy` says instead.
+pub fn reconnecting_with<A, P>(addr: A, config: Config, policy: P) -> ReconnectingConnection
+where
+    A: ToAddrs + Clone + Send + Sync + 'static,
+    P: ReconnectPolicy + 'static,
+{
+    Reconnect::new(policy, Box::new(move || {
+        let addr = addr.clone();
+        let config = config.clone();
+        async move { Connection::connect(addr, &config).await }.boxed()
+    }))
 }
 
 impl<F, S, M> Reconnect<F, S, M> {
-    pub(crate) fn new(strategy: Strategy, mk_connection: M) -> Self {
+    pub fn new(policy: impl ReconnectPolicy + 'static, mk_connection: M) -> Self {
         Self {
             state: State::Idle,
-            strategy,
+            policy: Box::new(policy),
             mk_connection,
             attempts: 0,
+            at_capacity: false,
         }
     }
 
-    fn poll_connected<R, E>(&mut self, cx: &mut Context) -> Poll<&mut S>
+    /// Number of failed attempts since the last successful connection
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Whether the last attempt failed with the node at capacity, until a connection succeeds
+    pub fn is_at_capacity(&self) -> bool {
+        self.at_capacity
+    }
+
+    /// `Err(None)` once the policy gave up, `Err(Some(_))` for an error not worth retrying.
+    fn poll_connected<E>(&mut self, cx: &mut Context) -> Poll<Result<&mut S, Option<E>>>
     where
-        S: Stream<Item = Result<R, E>> + Unpin,
         M: Fn() -> F,
         F: Future<Output = Result<S, E>> + Unpin,
+        E: ConnectError,
     {
         loop {
             let state = match self.state {
+                // The first connection is made right away
+                State::Idle if self.attempts == 0 => {
+                    State::Connecting((self.mk_connection)())
+                }
                 State::Idle => {
-                    match self.strategy {
-                        Strategy::Immediat => {
-                            let fut = (self.mk_connection)();
-                            State::Connecting(fut)
-                        }
-                        Strategy::Exponential(duration) => {
-                            State::Delaying(tokio::time::sleep(Duration::from_secs(duration.as_secs() * self.attempts as u64)))
+                    let delay = if self.at_capacity {
+                        self.policy.at_capacity_delay(self.attempts)
+                    } else {
+                        self.policy.next_delay(self.attempts)
+                    };
+                    match delay {
+                        Some(delay) => State::Delaying(Box::pin(tokio::time::sleep(delay))),
+                        None => {
+                            debug!("giving up reconnecting after {} attempts", self.attempts);
+                            State::GaveUp
                         }
                     }
                 }
                 State::Delaying(ref mut sleep) => {
-                    ready!(Pin::new(sleep).poll(cx));
+                    ready!(sleep.as_mut().poll(cx));
                     let fut = (self.mk_connection)();
                     State::Connecting(fut)
                 }
@@ -65,10 +242,18 @@ impl<F, S, M> Reconnect<F, S, M> {
                     match Pin::new(fut).poll(cx) {
                         Poll::Ready(Ok(conn)) => {
                             self.attempts = 0;
+                            self.at_capacity = false;
                             State::Connected(conn)
                         }
-                        Poll::Ready(Err(_e)) => {
+                        Poll::Ready(Err(e)) if !e.is_transient() => {
+                            debug!("connect failed, giving up: {:?}", e);
+                            self.state = State::GaveUp;
+                            return Poll::Ready(Err(Some(e)));
+                        }
+                        Poll::Ready(Err(e)) => {
+                            debug!("connect failed: {:?}", e);
                             self.attempts += 1;
+                            self.at_capacity = e.is_at_capacity();
                             State::Idle
                         }
                         Poll::Pending => {
@@ -77,37 +262,50 @@ impl<F, S, M> Reconnect<F, S, M> {
                     }
                 }
                 State::Connected(ref mut conn) => {
-                    return Poll::Ready(conn);
+                    return Poll::Ready(Ok(conn));
+                }
+                State::GaveUp => {
+                    return Poll::Ready(Err(None));
                 }
             };
             self.state = state;
         }
     }
+
+    fn disconnected(&mut self, at_capacity: bool) {
+        self.attempts += 1;
+        self.at_capacity = at_capacity;
+        self.state = State::Idle;
+    }
 }
 
 impl<F, S, M, R, E> Stream for Reconnect<F, S, M>
 where
     S: Stream<Item = Result<R, E>> + Unpin,
-    M: Fn() -> F,
+    M: Fn() -> F + Unpin,
     F: Future<Output = Result<S, E>> + Unpin,
+    E: ConnectError,
 {
     type Item = Result<R, E>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         loop {
-            let mut conn = ready!(self.poll_connected(cx));
-            match ready!(Pin::new(&mut conn).poll_next(cx)) {
+            let conn = match ready!(self.poll_connected(cx)) {
+                Ok(conn) => conn,
+                Err(e) => return Poll::Ready(e.map(Err)),
+            };
+            match ready!(Pin::new(conn).poll_next(cx)) {
                 Some(Ok(res)) => {
                     return Poll::Ready(Some(Ok(res)));
                 }
-                Some(Err(_e)) => {
-                    self.attempts += 1;
-                    self.state = State::Idle;
+                Some(Err(e)) => {
+                    debug!("connection failed, reconnecting: {:?}", e);
+                    self.disconnected(e.is_at_capacity());
                     continue;
                 }
                 None => {
-                    self.attempts += 1;
-                    self.state = State::Idle;
+                    debug!("connection closed, reconnecting");
+                    self.disconnected(false);
                     continue;
                 }
             }
@@ -115,37 +313,137 @@ where
     }
 }
 
-impl<F, S, M, R, I, E> Sink<I> for Reconnect<F, S, M>
+impl<F, S, M, I, E> Sink<I> for Reconnect<F, S, M>
 where
-    S: Stream<Item = Result<R, E>> + Unpin,
     S: Sink<I, Error = E> + Unpin,
-    M: Fn() -> F,
+    M: Fn() -> F + Unpin,
     F: Future<Output = Result<S, E>> + Unpin,
+    E: ConnectError + From<io::Error>,
 {
     type Error = E;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        let conn = ready!(self.poll_connected(cx));
-        let _ = ready!(Pin::new(conn).poll_ready(cx));
-        Poll::Ready(Ok(()))
+        loop {
+            let conn = match ready!(self.poll_connected(cx)) {
+                Ok(conn) => conn,
+                Err(Some(e)) => return Poll::Ready(Err(e)),
+                Err(None) => {
+                    let e = io::Error::new(io::ErrorKind::NotConnected, "gave up reconnecting");
+                    return Poll::Ready(Err(e.into()));
+                }
+            };
+            match ready!(Pin::new(conn).poll_ready(cx)) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(e) => {
+                    debug!("connection failed, reconnecting: {:?}", e);
+                    self.disconnected(e.is_at_capacity());
+                }
+            }
+        }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
-        let mut conn = match &mut self.state {
+        let conn = match &mut self.state {
             State::Connected(conn) => conn,
             _ => panic!("Wrong state to start_send, must must be preceded by a successful call to poll_ready"),
         };
-        Pin::new(&mut conn).start_send(item)?;
-        Ok(())
+        Pin::new(conn).start_send(item)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        let conn = ready!(self.poll_connected(cx));
-        Pin::new(conn).poll_flush(cx)
+        match self.state {
+            State::Connected(ref mut conn) => Pin::new(conn).poll_flush(cx),
+            // Whatever was buffered went down with the connection
+            _ => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        let conn = ready!(self.poll_connected(cx));
-        Pin::new(conn).poll_close(cx)
+        match self.state {
+            State::Connected(ref mut conn) => Pin::new(conn).poll_close(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use futures::{FutureExt, StreamExt};
+
+    use crate::config::Config;
+    use crate::conn::{Connection, Response};
+    use crate::error::{Error, NsqError};
+    use crate::testing;
+    use super::{Fixed, Immediate, Reconnect, ReconnectPolicy, AT_CAPACITY_DELAY};
+
+    #[tokio::test]
+    async fn reconnects_after_the_connection_closes() {
+        let mut addrs = Vec::new();
+        for body in [&b"first"[..], &b"second"[..]] {
+            addrs.push(testing::serve(move |mut conn| async move {
+                conn.write_message(b"0123456789abcdef", 1, body).await
+            }).await);
+        }
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let mut conn = Reconnect::new(Immediate, move || {
+            let addr = addrs[counter.fetch_add(1, Ordering::SeqCst)];
+            async move { Connection::connect(addr, &Config::default()).await }.boxed()
+        });
+
+        for expected in [&b"first"[..], &b"second"[..]] {
+            match conn.next().await.unwrap().unwrap() {
+                Response::Msg(msg) => assert_eq!(msg.body, expected),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn nodes_at_capacity_are_retried_later() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let mut conn = Reconnect::new(Fixed(Duration::from_millis(1)), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let refusal = NsqError::new("E_TOO_MANY_CONNECTIONS", "max 100 connections reached");
+            futures::future::ready(Err::<Connection, _>(Error::NsqError(refusal))).boxed()
+        });
+
+        // The second attempt waits for `AT_CAPACITY_DELAY`, not the policy's millisecond
+        assert!(tokio::time::timeout(Duration::from_millis(100), conn.next()).await.is_err());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert!(conn.is_at_capacity());
+
+        assert_eq!(Fixed(Duration::from_secs(5)).at_capacity_delay(1), Some(Duration::from_secs(20)));
+        assert_eq!(Fixed(Duration::from_millis(1)).give_up_after(1).at_capacity_delay(1), Some(AT_CAPACITY_DELAY));
+        assert_eq!(Fixed(Duration::from_millis(1)).give_up_after(1).at_capacity_delay(2), None);
+    }
+
+    #[tokio::test]
+    async fn non_transient_connect_errors_are_returned() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let mut conn = Reconnect::new(Immediate, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let refusal = NsqError::new("E_AUTH_FAILED", "AUTH failed");
+            futures::future::ready(Err::<Connection, _>(Error::NsqError(refusal))).boxed()
+        });
+
+        assert!(matches!(conn.next().await, Some(Err(Error::NsqError(_)))));
+        assert!(conn.next().await.is_none());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let policy = Fixed(Duration::from_millis(10)).give_up_after(2);
+        assert_eq!(policy.next_delay(1), Some(Duration::from_millis(10)));
+        assert_eq!(policy.next_delay(2), Some(Duration::from_millis(10)));
+        assert_eq!(policy.next_delay(3), None);
     }
 }
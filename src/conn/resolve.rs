@@ -0,0 +1,127 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+
+/// Turns a host name into the addresses to connect to, see `Config::resolver`.
+///
+/// Implement it to put caching, timeouts or DNS-over-TLS in front of broker resolution.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// The system resolver, `getaddrinfo` run by tokio on its blocking pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GaiResolver;
+
+impl Resolve for GaiResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        async move {
+            Ok(tokio::net::lookup_host((host, port)).await?.collect())
+        }.boxed()
+    }
+}
+
+/// A shared [`Resolve`] implementation, cheap to clone along with the `Config`.
+#[derive(Clone)]
+pub struct Resolver(Arc<dyn Resolve>);
+
+impl Resolver {
+    pub fn new(resolver: impl Resolve + 'static) -> Self {
+        Resolver(Arc::new(resolver))
+    }
+
+    pub(crate) async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.0.resolve(host, port).await
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(GaiResolver)
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+/// An address of nsqd to connect to, its host name resolved with `Config::resolver`.
+///
+/// Implemented for what tokio's `ToSocketAddrs` takes: socket addresses, `"host:port"`
+/// strings and `(host, port)` pairs.
+pub trait ToAddrs: Send + Sync {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+impl ToAddrs for SocketAddr {
+    fn to_addrs<'a>(&'a self, _: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        future::ready(Ok(vec![*self])).boxed()
+    }
+}
+
+impl ToAddrs for (IpAddr, u16) {
+    fn to_addrs<'a>(&'a self, _: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        future::ready(Ok(vec![SocketAddr::from(*self)])).boxed()
+    }
+}
+
+impl ToAddrs for [SocketAddr] {
+    fn to_addrs<'a>(&'a self, _: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        future::ready(Ok(self.to_vec())).boxed()
+    }
+}
+
+impl ToAddrs for Vec<SocketAddr> {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        self[..].to_addrs(resolver)
+    }
+}
+
+impl ToAddrs for str {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        if let Ok(addr) = self.parse::<SocketAddr>() {
+            return future::ready(Ok(vec![addr])).boxed();
+        }
+        let host_port = self.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse().ok()?)));
+        match host_port {
+            Some((host, port)) => resolver.resolve(host, port).boxed(),
+            None => future::ready(Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address"))).boxed(),
+        }
+    }
+}
+
+impl ToAddrs for String {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        self.as_str().to_addrs(resolver)
+    }
+}
+
+impl ToAddrs for (&str, u16) {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        match self.0.parse::<IpAddr>() {
+            Ok(ip) => future::ready(Ok(vec![SocketAddr::new(ip, self.1)])).boxed(),
+            Err(_) => resolver.resolve(self.0, self.1).boxed(),
+        }
+    }
+}
+
+impl ToAddrs for (String, u16) {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        match self.0.parse::<IpAddr>() {
+            Ok(ip) => future::ready(Ok(vec![SocketAddr::new(ip, self.1)])).boxed(),
+            Err(_) => resolver.resolve(&self.0, self.1).boxed(),
+        }
+    }
+}
+
+impl<T: ToAddrs + ?Sized> ToAddrs for &T {
+    fn to_addrs<'a>(&'a self, resolver: &'a Resolver) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        (**self).to_addrs(resolver)
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Traffic on a connection since it was opened, see [`Connection::stats`](crate::Connection::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Bytes received on the socket, i.e. after TLS and compression
+    pub bytes_read: u64,
+    /// Bytes sent on the socket
+    pub bytes_written: u64,
+    /// Response frames, including heartbeats
+    pub responses: u64,
+    /// Error frames
+    pub errors: u64,
+    /// Message frames
+    pub messages: u64,
+    pub heartbeats_answered: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    responses: AtomicU64,
+    errors: AtomicU64,
+    messages: AtomicU64,
+    heartbeats_answered: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn response(&self) {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn heartbeat_answered(&self) {
+        self.heartbeats_answered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            responses: self.responses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            heartbeats_answered: self.heartbeats_answered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts the bytes going through the socket.
+pub(crate) struct Counted<T> {
+    inner: T,
+    counters: Arc<Counters>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, counters: Arc<Counters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.counters.bytes_read.fetch_add(read, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.counters.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
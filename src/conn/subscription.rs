@@ -0,0 +1,137 @@
+//! The two halves of a subscribed connection, see [`Connection::subscribe`](crate::Connection::subscribe).
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::ready;
+use tracing::{debug, Span};
+
+use crate::codec::NsqMsg;
+use crate::command::{Command, MessageId};
+use crate::conn::connection::{ConnSink, ConnStream};
+use crate::conn::Response;
+use crate::error::Error;
+
+/// Messages delivered to a subscription.
+///
+/// Heartbeats are answered and stray `OK`s skipped. Failed `FIN`, `REQ` and `TOUCH`
+/// commands and messages the validator rejected are yielded as errors without ending the
+/// stream. Any other error is yielded as the last item.
+///
+/// The stream ends once nsqd acknowledges `CLS` with `CLOSE_WAIT`, or the connection drops
+/// or fails; [`closed_cleanly`](MessageStream::closed_cleanly) tells them apart.
+pub struct MessageStream {
+    inner: ConnStream,
+    span: Span,
+    done: bool,
+    closed_cleanly: bool,
+}
+
+/// Sends commands on a subscribed connection, e.g. to finish messages from its
+/// [`MessageStream`]. Publishing fails with `Error::InvalidCommand`, as nsqd would reject it.
+pub struct CommandSender {
+    inner: ConnSink,
+    span: Span,
+}
+
+impl MessageStream {
+    pub(crate) fn new(inner: ConnStream, span: Span) -> Self {
+        Self { inner, span, done: false, closed_cleanly: false }
+    }
+
+    /// Whether the stream ended because nsqd closed the connection after `CLS`, rather than
+    /// it dropping or failing. False until the stream ends.
+    pub fn closed_cleanly(&self) -> bool {
+        self.closed_cleanly
+    }
+}
+
+impl Stream for MessageStream {
+    type Item = Result<NsqMsg, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        if self.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Response::Msg(msg))) => return Poll::Ready(Some(Ok(msg))),
+                Some(Ok(Response::Invalid { msg, reason })) => {
+                    return Poll::Ready(Some(Err(Error::InvalidMessage { id: msg.message_id, reason })));
+                }
+                Some(Ok(Response::Ok)) => debug!("discard OK on subscribed connection"),
+                Some(Ok(Response::Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Some(Ok(Response::CloseWait)) => {
+                    self.done = true;
+                    self.closed_cleanly = true;
+                    return Poll::Ready(None);
+                }
+                None => {
+                    self.done = true;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
+impl CommandSender {
+    pub(crate) fn new(inner: ConnSink, span: Span) -> Self {
+        Self { inner, span }
+    }
+
+    /// Allow nsqd to have up to `count` messages in flight on this connection.
+    pub async fn rdy(&mut self, count: u64) -> Result<(), Error> {
+        self.send(Command::Rdy(count)).await
+    }
+
+    /// Finish a message.
+    pub async fn fin(&mut self, id: MessageId) -> Result<(), Error> {
+        self.send(Command::Fin(id)).await
+    }
+
+    /// Requeue a message, to be redelivered after `delay`.
+    pub async fn req(&mut self, id: MessageId, delay: Duration) -> Result<(), Error> {
+        self.send(Command::Req(id, delay.as_millis() as u64)).await
+    }
+
+    /// Reset the timeout of an in-flight message.
+    pub async fn touch(&mut self, id: MessageId) -> Result<(), Error> {
+        self.send(Command::Touch(id)).await
+    }
+}
+
+impl Sink<Command> for CommandSender {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Command) -> Result<(), Self::Error> {
+        if let Command::Pub(..) | Command::Mpub(..) | Command::Dpub(..) = item {
+            return Err(Error::InvalidCommand("cannot publish on a subscribed connection".into()));
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -1,57 +1,567 @@
-use tokio::net::TcpStream;
-use crate::config::TlsConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::config::{Pem, TlsConfig, TlsVersion};
 use crate::error::Error;
-use crate::codec::NsqCodec;
+use tracing::warn;
 
 #[cfg(feature = "tls-native")]
-pub(crate) use tokio_native_tls::{TlsConnector, TlsStream};
+pub(crate) use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
 #[cfg(feature = "tls-tokio")]
 pub(crate) use tokio_rustls::{
-    rustls::RootCertStore,
+    rustls::{
+        Certificate,
+        CipherSuite,
+        OwnedTrustAnchor,
+        PrivateKey,
+        RootCertStore,
+        SupportedCipherSuite,
+        SupportedProtocolVersion,
+        ALL_CIPHER_SUITES,
+        DEFAULT_CIPHER_SUITES,
+        version,
+    },
     rustls::client::{
         ClientConfig,
+        ClientSessionMemoryCache,
+        NoClientSessionStorage,
+        ServerCertVerified,
+        ServerCertVerifier,
         ServerName,
+        StoresClientSessions,
+        WebPkiVerifier,
     },
     client::TlsStream,
     TlsConnector,
 };
 
+/// A pinned server certificate, see `TlsConfig::pinned`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// SHA-256 of the DER encoded certificate, changes whenever the certificate is reissued
+    Certificate([u8; 32]),
+    /// SHA-256 of the DER encoded SubjectPublicKeyInfo, as in HPKP. It survives reissuing
+    /// the certificate for the same key.
+    PublicKey([u8; 32]),
+}
+
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+impl CertificatePin {
+    /// Pin the DER encoded certificate `cert`.
+    pub fn certificate(cert: &[u8]) -> Self {
+        CertificatePin::Certificate(sha256(cert))
+    }
+
+    /// Pin the public key of the DER encoded certificate `cert`, `None` if it isn't a
+    /// well-formed X.509 certificate.
+    pub fn public_key_of(cert: &[u8]) -> Option<Self> {
+        subject_public_key_info(cert).map(|spki| CertificatePin::PublicKey(sha256(spki)))
+    }
+
+    pub(crate) fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            CertificatePin::Certificate(hash) => sha256(cert) == *hash,
+            CertificatePin::PublicKey(hash) => {
+                subject_public_key_info(cert).is_some_and(|spki| sha256(spki) == *hash)
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, data).as_ref());
+    hash
+}
+
+/// The DER encoded SubjectPublicKeyInfo of a DER encoded X.509 certificate.
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let cert = der_next(cert).filter(|e| e.tag == SEQUENCE)?;
+    let tbs = der_next(cert.content).filter(|e| e.tag == SEQUENCE)?;
+    let mut rest = tbs.content;
+    if rest.first() == Some(&VERSION) {
+        rest = der_next(rest)?.rest;
+    }
+    // serialNumber, signature, issuer, validity and subject come first
+    for _ in 0..5 {
+        rest = der_next(rest)?.rest;
+    }
+    der_next(rest).filter(|e| e.tag == SEQUENCE).map(|spki| spki.whole)
+}
+
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+struct DerElement<'a> {
+    tag: u8,
+    content: &'a [u8],
+    /// Including the tag and length
+    whole: &'a [u8],
+    /// What follows the element
+    rest: &'a [u8],
+}
+
+/// Split the first DER element off `input`.
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+fn der_next(input: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input.get(2..2 + n)?.iter().fold(0usize, |len, b| len << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let whole = input.get(..header.checked_add(len)?)?;
+    Some(DerElement { tag, content: &whole[header..], whole, rest: &input[whole.len()..] })
+}
+
+#[cfg(feature = "tls-tokio")]
+use std::{
+    io,
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+};
+
+/// A TLS session cache shared by all connections made with the same `TlsConfig`.
+///
+/// Reconnecting to an nsqd the client has talked to before resumes the previous TLS session
+/// instead of doing a full handshake, which saves a round trip and the certificate
+/// verification. Clones share the same cache.
 #[cfg(feature = "tls-tokio")]
-async fn upgrade_tls(domain: &str, inner: TcpStream, tls_config: &TlsConfig, nsq_codec: &mut NsqCodec)
-    -> Result<TlsStream<TcpStream>, Error>
+#[derive(Clone)]
+pub struct SessionCache {
+    inner: Arc<CountingStore>,
+}
+
+/// Counters of a [`SessionCache`].
+#[cfg(feature = "tls-tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResumptionStats {
+    /// Handshakes which offered a cached session to the server
+    pub hits: u64,
+    /// Handshakes which found no session to resume
+    pub misses: u64,
+}
+
+#[cfg(feature = "tls-tokio")]
+impl ResumptionStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+struct CountingStore {
+    sessions: Arc<ClientSessionMemoryCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[cfg(feature = "tls-tokio")]
+impl SessionCache {
+    /// Create a cache holding up to `size` sessions.
+    pub fn new(size: usize) -> Self {
+        Self {
+            inner: Arc::new(CountingStore {
+                sessions: ClientSessionMemoryCache::new(size.max(1)),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> ResumptionStats {
+        ResumptionStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+impl Default for SessionCache {
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+impl std::fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCache").field("stats", &self.stats()).finish()
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+impl StoresClientSessions for CountingStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.sessions.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.sessions.get(key);
+        // rustls also stores key exchange hints here, only count session lookups
+        if key.starts_with(b"session") {
+            let counter = if value.is_some() { &self.hits } else { &self.misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+pub(crate) async fn upgrade_tls<T>(inner: T, tls_config: &TlsConfig)
+    -> Result<TlsStream<T>, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    todo!()
-    // // TODO from config
-    // let root_certs = RootCertStore { roots: vec![] };
-    // let client_config = ClientConfig::builder()
-    //     .with_safe_defaults()
-    //     .with_root_certificates(root_certs)
-    //     .with_no_client_auth();
-    // let client_config = Arc::new(client_config);
-    // let connector = TlsConnector::from(client_config);
-    // // TODO FIXME data from peer may have already read in the buffer
-    // let mut tls_socket = connector.connect(ServerName::try_from(tls_config.domain.as_str())?, inner).await?;
-    // if let NsqFramed::Response(RawResponse::Ok) = read_response(&mut tls_socket, nsq_codec).await? {
-    //     // Ok(Box::new(tls_socket))
-    //     Ok(tls_socket)
-    // } else {
-    //     Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
-    // }
+    let client_config = client_config(tls_config)?;
+    let connector = TlsConnector::from(Arc::new(client_config))
+        .early_data(tls_config.early_data);
+    let server_name = ServerName::try_from(tls_config.domain.as_str())?;
+    Ok(connector.connect(server_name, inner).await?)
+}
+
+#[cfg(feature = "tls-tokio")]
+fn client_config(tls_config: &TlsConfig) -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    match read_pem(&tls_config.root_ca_pem, &tls_config.root_ca_file)? {
+        Some((pem, source)) => {
+            for cert in load_certs(&pem, &source)? {
+                roots.add(&cert).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("invalid root certificate in {}: {:?}", source, e))
+                })?;
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+            }));
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_cipher_suites(&cipher_suites(tls_config)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&protocol_versions(tls_config))?
+        .with_root_certificates(roots.clone());
+    let identity = (
+        read_pem(&tls_config.cert_pem, &tls_config.cert_file)?,
+        read_pem(&tls_config.key_pem, &tls_config.key_file)?,
+    );
+    let mut client_config = match identity {
+        (Some((cert, cert_source)), Some((key, key_source))) => {
+            builder.with_single_cert(load_certs(&cert, &cert_source)?, load_private_key(&key, &key_source)?)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    if tls_config.insecure_skip_verify {
+        warn!("TLS certificate verification is disabled for {}", tls_config.domain);
+        client_config.dangerous().set_certificate_verifier(Arc::new(SkipServerVerification));
+    }
+    if !tls_config.pinned.is_empty() {
+        let inner: Arc<dyn ServerCertVerifier> = if tls_config.insecure_skip_verify {
+            Arc::new(SkipServerVerification)
+        } else {
+            Arc::new(WebPkiVerifier::new(roots, None))
+        };
+        client_config.dangerous().set_certificate_verifier(Arc::new(PinnedVerification {
+            inner,
+            pins: tls_config.pinned.clone(),
+        }));
+    }
+
+    client_config.session_storage = match tls_config.session_cache {
+        Some(ref cache) => cache.inner.clone(),
+        None => Arc::new(NoClientSessionStorage {}),
+    };
+    client_config.enable_early_data = tls_config.early_data;
+    Ok(client_config)
+}
+
+/// IANA names of the cipher suites rustls supports.
+#[cfg(feature = "tls-tokio")]
+const CIPHER_SUITE_NAMES: &[(&str, CipherSuite)] = &[
+    ("TLS_AES_256_GCM_SHA384", CipherSuite::TLS13_AES_256_GCM_SHA384),
+    ("TLS_AES_128_GCM_SHA256", CipherSuite::TLS13_AES_128_GCM_SHA256),
+    ("TLS_CHACHA20_POLY1305_SHA256", CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+    ("TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256),
+    ("TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256", CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256),
+    ("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256),
+    ("TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256", CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256),
+];
+
+/// `TlsConfig::cipher_suites` by name, rustls' defaults if there are none.
+#[cfg(feature = "tls-tokio")]
+fn cipher_suites(tls_config: &TlsConfig) -> Result<Vec<SupportedCipherSuite>, Error> {
+    if tls_config.cipher_suites.is_empty() {
+        return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+    }
+    tls_config.cipher_suites.iter().map(|name| {
+        // Also accept rustls' names, which prefix TLS 1.3 suites with TLS13_ instead of TLS_
+        let iana_name = match name.strip_prefix("TLS13_") {
+            Some(rest) => format!("TLS_{}", rest),
+            None => name.clone(),
+        };
+        CIPHER_SUITE_NAMES.iter()
+            .find(|(known, _)| *known == iana_name)
+            .and_then(|(_, suite)| ALL_CIPHER_SUITES.iter().find(|supported| supported.suite() == *suite))
+            .copied()
+            .ok_or_else(|| Error::InvalidConfig {
+                field: "tls_v1",
+                reason: format!("cipher suite {} is unknown or not supported by rustls", name),
+            })
+    }).collect()
+}
+
+/// The versions rustls supports between `TlsConfig::min_version` and `max_version`.
+#[cfg(feature = "tls-tokio")]
+fn protocol_versions(tls_config: &TlsConfig) -> Vec<&'static SupportedProtocolVersion> {
+    [(TlsVersion::Tls12, &version::TLS12), (TlsVersion::Tls13, &version::TLS13)]
+        .into_iter()
+        .filter(|(v, _)| !matches!(tls_config.min_version, Some(min) if *v < min))
+        .filter(|(v, _)| !matches!(tls_config.max_version, Some(max) if *v > max))
+        .map(|(_, supported)| supported)
+        .collect()
+}
+
+/// Accepts any server certificate, for `TlsConfig::insecure_skip_verify`.
+#[cfg(feature = "tls-tokio")]
+struct SkipServerVerification;
+
+#[cfg(feature = "tls-tokio")]
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Checks the server certificate against `TlsConfig::pinned` after the usual verification.
+#[cfg(feature = "tls-tokio")]
+struct PinnedVerification {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<CertificatePin>,
+}
+
+#[cfg(feature = "tls-tokio")]
+impl ServerCertVerifier for PinnedVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        if !self.pins.iter().any(|pin| pin.matches(&end_entity.0)) {
+            return Err(tokio_rustls::rustls::Error::InvalidCertificateData(
+                "server certificate matches no pin".to_string(),
+            ));
+        }
+        Ok(verified)
+    }
+}
+
+/// The PEM `TlsConfig` holds in memory, or else reads from a file, with where it came from
+/// to name in errors.
+#[cfg(any(feature = "tls-tokio", feature = "tls-native"))]
+fn read_pem(pem: &Option<Pem>, path: &Option<String>) -> Result<Option<(Vec<u8>, String)>, Error> {
+    match (pem, path) {
+        (Some(pem), _) => Ok(Some((pem.as_bytes().to_vec(), "in-memory PEM".into()))),
+        (None, Some(path)) => Ok(Some((std::fs::read(path)?, path.clone()))),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(feature = "tls-tokio")]
+fn load_certs(pem: &[u8], source: &str) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut &*pem)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificate found in {}", source)).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+#[cfg(feature = "tls-tokio")]
+fn load_private_key(mut pem: &[u8], source: &str) -> Result<PrivateKey, Error> {
+    use rustls_pemfile::Item;
+
+    while let Some(item) = rustls_pemfile::read_one(&mut pem)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("no private key found in {}", source)).into())
 }
 
 #[cfg(feature = "tls-native")]
-async fn upgrade_tls<T>(domain: &str, inner: T, tls_config: &TlsConfig, nsq_codec: &mut NsqCodec)
-    // -> Result<TlsStream, Error>
-    -> Result<Box<dyn AsyncRead + AsyncWrite + Unpin>, Error>
-    where T: AsyncRead + AsyncWrite + Unpin,
+pub(crate) async fn upgrade_tls<T>(inner: T, tls_config: &TlsConfig)
+    -> Result<TlsStream<T>, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    let connector = native_tls::TlsConnector::new().unwrap();
-    let connector = TlsConnector::from(connector);
-    // TODO FIXME data from peer may have already read in the buffer
-    let tls_socket = connector.connect(domain, inner).await?;
-    if let NsqFramed::Response(RawResponse::ok) = read_response(&mut tls_socket, nsq_codec).await?{
-        Ok(Box::new(tls_socket))
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some((pem, source)) = read_pem(&tls_config.root_ca_pem, &tls_config.root_ca_file)? {
+        for cert in load_certs(&pem, &source)? {
+            builder.add_root_certificate(native_tls::Certificate::from_der(&cert)?);
+        }
+    }
+    let identity = (
+        read_pem(&tls_config.cert_pem, &tls_config.cert_file)?,
+        read_pem(&tls_config.key_pem, &tls_config.key_file)?,
+    );
+    if let (Some((cert, _)), Some((key, _))) = identity {
+        // native-tls only takes PKCS #8 keys
+        builder.identity(native_tls::Identity::from_pkcs8(&cert, &key)?);
+    }
+    if tls_config.insecure_skip_verify {
+        warn!("TLS certificate verification is disabled for {}", tls_config.domain);
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    // native-tls can neither require TLS 1.3 nor choose cipher suites
+    let unsupported = if tls_config.min_version == Some(TlsVersion::Tls13) {
+        Some("min_version 1.3")
+    } else if !tls_config.cipher_suites.is_empty() {
+        Some("cipher_suites")
     } else {
-        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        None
+    };
+    if let Some(setting) = unsupported {
+        return Err(Error::InvalidConfig {
+            field: "tls_v1",
+            reason: format!("{} is not supported by native-tls, use the tls-tokio feature", setting),
+        });
+    }
+    builder.min_protocol_version(tls_config.min_version.map(|_| native_tls::Protocol::Tlsv12));
+    builder.max_protocol_version(match tls_config.max_version {
+        Some(TlsVersion::Tls12) => Some(native_tls::Protocol::Tlsv12),
+        Some(TlsVersion::Tls13) | None => None,
+    });
+    let connector = TlsConnector::from(builder.build()?);
+    let stream = connector.connect(&tls_config.domain, inner).await?;
+    if !tls_config.pinned.is_empty() {
+        let cert = stream.get_ref().peer_certificate()?.map(|cert| cert.to_der()).transpose()?;
+        if !cert.is_some_and(|cert| tls_config.pinned.iter().any(|pin| pin.matches(&cert))) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "server certificate matches no pin").into());
+        }
+    }
+    Ok(stream)
+}
+
+#[cfg(feature = "tls-native")]
+fn load_certs(pem: &[u8], source: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let certs = rustls_pemfile::certs(&mut &*pem)?;
+    if certs.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("no certificate found in {}", source)).into());
+    }
+    Ok(certs)
+}
+
+#[cfg(all(test, feature = "tls-tokio"))]
+mod tests {
+    use tokio_rustls::rustls::ProtocolVersion;
+
+    use crate::config::{Config, Pem, TlsConfig, TlsVersion};
+    use crate::error::Error;
+    use super::{client_config, CertificatePin};
+
+    // Self-signed P-256 certificate for nsqd.test
+    const CERT: &str = "\
+        MIIBgDCCASWgAwIBAgIUcfkHqBOzoEf8rCdbJRGXeGQWPa8wCgYIKoZIzj0EAwIw\
+        FDESMBAGA1UEAwwJbnNxZC50ZXN0MCAXDTI2MTAxNjEwMDIyMVoYDzIxMjYwOTIy\
+        MTAwMjIxWjAUMRIwEAYDVQQDDAluc3FkLnRlc3QwWTATBgcqhkjOPQIBBggqhkjO\
+        PQMBBwNCAATzOCyVq+om26hnQ0J2QRJPgwRK08FdZbmm1SyHuQD5QO3X198AXzxZ\
+        oBDbReBJmfZjBwlSRptYSf+mVRFd5yito1MwUTAdBgNVHQ4EFgQUsCJ1vglCc8ZJ\
+        U5RVR31lEnO9be4wHwYDVR0jBBgwFoAUsCJ1vglCc8ZJU5RVR31lEnO9be4wDwYD\
+        VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEA9YsqwCIMrrtJ/Q/qnW3R\
+        yTCGw6jZ5yXDLx4GiGtmCnkCIQDFmk/nghIWWmKjwGr0u8yKNOEXP0SceZnAbssD\
+        GMxv1A==";
+
+    fn pem() -> String {
+        format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", CERT)
+    }
+
+    fn der() -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem().as_bytes()).unwrap().remove(0)
+    }
+
+    fn hex(hash: &str) -> [u8; 32] {
+        let mut out = [0; 32];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn pins_match_certificate_and_public_key() {
+        let cert = der();
+        // openssl x509 -outform DER | sha256sum, and the same for -pubkey
+        let cert_hash = hex("5656a6a89b8afef7f500c35df3021bd603fd1f9f28a0e2a7242107dacdf7af1f");
+        let spki_hash = hex("224ff67997186234ee8154c67ad34e17c0ed8133bccf186033a5bf5ef0498246");
+
+        assert_eq!(CertificatePin::certificate(&cert), CertificatePin::Certificate(cert_hash));
+        assert_eq!(CertificatePin::public_key_of(&cert), Some(CertificatePin::PublicKey(spki_hash)));
+        assert!(CertificatePin::PublicKey(spki_hash).matches(&cert));
+        assert!(!CertificatePin::PublicKey(cert_hash).matches(&cert));
+        assert_eq!(CertificatePin::public_key_of(&cert[..100]), None);
+    }
+
+    #[test]
+    fn versions_and_cipher_suites_are_restricted() {
+        let mut tls = TlsConfig::new("nsqd.test");
+        tls.min_version = Some(TlsVersion::Tls13);
+        tls.cipher_suites = vec!["TLS_AES_128_GCM_SHA256".into(), "TLS13_AES_256_GCM_SHA384".into()];
+        let config = client_config(&tls).unwrap();
+        assert!(config.supports_version(ProtocolVersion::TLSv1_3));
+        assert!(!config.supports_version(ProtocolVersion::TLSv1_2));
+
+        tls.cipher_suites = vec!["TLS_RSA_WITH_RC4_128_MD5".into()];
+        assert!(matches!(client_config(&tls), Err(Error::InvalidConfig { field: "tls_v1", .. })));
+
+        // Only a TLS 1.2 suite, with TLS 1.2 excluded
+        tls.cipher_suites = vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".into()];
+        assert!(client_config(&tls).is_err());
+    }
+
+    #[test]
+    fn root_ca_is_read_from_memory() {
+        let mut tls = TlsConfig::new("nsqd.test");
+        tls.root_ca_pem = Some(Pem::from(pem()));
+        assert!(client_config(&tls).is_ok());
+        assert_eq!(format!("{:?}", tls.root_ca_pem.unwrap()), format!("Pem({} bytes)", pem().len()));
+
+        tls.root_ca_pem = Some(Pem::new("not a certificate"));
+        match client_config(&tls) {
+            Err(Error::IoError(e)) => assert_eq!(e.to_string(), "no certificate found in in-memory PEM"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        tls.root_ca_file = Some("ca.pem".into());
+        let config = Config { tls_v1: Some(tls), ..Default::default() };
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig { field: "tls_v1", .. })));
     }
 }
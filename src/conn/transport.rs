@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::prelude::*;
+use tokio_util::codec::Framed;
+
+use crate::codec::{NsqCodec, NsqFramed};
+use crate::command::Command;
+use crate::config::Config;
+use crate::conn::{connection::{dial, negotiate, AuthResponse, IdentifyResponse}, BaseIo, ToAddrs};
+use crate::error::Error;
+
+/// A negotiated nsqd connection, sending `Command`s and yielding raw frames.
+///
+/// This is what `Connection` is built on, without its policies: IDENTIFY, TLS, compression
+/// and AUTH are done, but every frame nsqd sends comes out as is. Heartbeats included, which
+/// must be answered with `Command::Nop` or nsqd closes the connection. Meant for proxies,
+/// sniffers and the like.
+pub struct NsqTransport {
+    inner: Framed<BaseIo, NsqCodec>,
+    peer_addr: SocketAddr,
+    identify: IdentifyResponse,
+    auth: Option<AuthResponse>,
+}
+
+impl NsqTransport {
+    pub async fn connect<A: ToAddrs>(addr: A, config: &Config) -> Result<Self, Error> {
+        config.validate()?;
+        let tcp = dial(addr, config).await?;
+        let peer_addr = tcp.peer_addr()?;
+        let (inner, identify, auth) = negotiate(tcp, config).await?;
+        Ok(Self { inner, peer_addr, identify, auth })
+    }
+
+    /// The address of the nsqd this transport is connected to
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// What nsqd agreed to in IDENTIFY
+    pub fn identify(&self) -> &IdentifyResponse {
+        &self.identify
+    }
+
+    /// What nsqd answered to AUTH, if it required it
+    pub fn auth(&self) -> Option<&AuthResponse> {
+        self.auth.as_ref()
+    }
+}
+
+impl Stream for NsqTransport {
+    type Item = Result<NsqFramed, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Sink<Command> for NsqTransport {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Command) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+
+    use crate::codec::{NsqFramed, RawResponse};
+    use crate::command::Command;
+    use crate::config::Config;
+    use crate::testing;
+    use super::NsqTransport;
+
+    #[tokio::test]
+    async fn heartbeats_are_left_to_the_caller() {
+        let addr = testing::serve(|mut conn| async move {
+            conn.write_response(b"_heartbeat_").await?;
+            assert_eq!(conn.read_command().await?.unwrap().name, "NOP");
+            Ok(())
+        }).await;
+
+        let mut transport = NsqTransport::connect(addr, &Config::default()).await.unwrap();
+        assert_eq!(transport.identify().version, "1.2.1");
+        match transport.next().await.unwrap().unwrap() {
+            NsqFramed::Response(RawResponse::Heartbeat) => {}
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        transport.send(Command::Nop).await.unwrap();
+        assert!(transport.next().await.is_none());
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::codec::NsqMsg;
+
+/// Checks every message received before it is delivered, e.g. a checksum, length or schema
+/// of the body, see `Config::message_validator`.
+#[derive(Clone)]
+pub struct MessageValidator {
+    pub(crate) check: Arc<MessageCheck>,
+    pub(crate) on_invalid: OnInvalid,
+}
+
+type MessageCheck = dyn Fn(&NsqMsg) -> Result<(), String> + Send + Sync;
+
+/// What to do with a message its [`MessageValidator`] rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnInvalid {
+    /// Finish it, so nsqd drops it instead of redelivering it
+    Drop,
+    /// Requeue it, to be redelivered after the delay, e.g. to another consumer
+    Requeue(Duration),
+    /// Deliver it as `Response::Invalid`, or `Error::InvalidMessage` from a `MessageStream`,
+    /// without failing the connection. The message stays in flight and is up to the caller
+    /// to finish or requeue by id, or nsqd redelivers it after its timeout.
+    Error,
+}
+
+impl MessageValidator {
+    /// Reject messages for which `f` returns an error, handled as `on_invalid` says.
+    pub fn new<F>(on_invalid: OnInvalid, f: F) -> Self
+    where
+        F: Fn(&NsqMsg) -> Result<(), String> + Send + Sync + 'static,
+    {
+        MessageValidator { check: Arc::new(f), on_invalid }
+    }
+}
+
+impl fmt::Debug for MessageValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageValidator").field("on_invalid", &self.on_invalid).finish()
+    }
+}
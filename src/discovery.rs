@@ -0,0 +1,369 @@
+//! The nsqd nodes having a topic, kept up to date from nsqlookupd or another [`Discovery`]
+//! source: a static list, DNS or SRV records, or your own, e.g. the Kubernetes or Consul API.
+//!
+//! [`ConnectionPool::discovered`](crate::pool::ConnectionPool::discovered) and
+//! [`HedgedProducer::discovered`](crate::producer::HedgedProducer::discovered) connect to the
+//! nodes a source finds, [`DiscoveryPoller`] follows them as they change:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), nsq_in_rust::Error> {
+//! use futures::StreamExt;
+//! use nsq_in_rust::discovery::{DiscoveryEvent, DiscoveryPoller};
+//! use nsq_in_rust::{Config, Lookup};
+//!
+//! let lookup = Lookup::new("http://127.0.0.1:4161")?;
+//! let mut nodes = DiscoveryPoller::new(lookup, "events", &Config::default());
+//! while let Some(event) = nodes.next().await {
+//!     match event? {
+//!         DiscoveryEvent::NodeAdded(node) => println!("connect to {}", node.tcp_address()),
+//!         DiscoveryEvent::NodeRemoved(node) => println!("disconnect from {}", node.tcp_address()),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use futures::future::{self, BoxFuture};
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, Stream, StreamExt};
+
+use crate::config::Config;
+use crate::conn::Resolver;
+use crate::dns;
+use crate::error::{Error, Result};
+use crate::lookup::{join_host_port, poll_ticker, CachedLookup, Lookup, Producer};
+
+/// Where an nsqd listens.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NsqdAddr {
+    pub host: String,
+    pub tcp_port: u16,
+    pub http_port: u16,
+}
+
+impl NsqdAddr {
+    /// An nsqd at `host`, its HTTP API on the default port 4151.
+    pub fn new(host: impl Into<String>, tcp_port: u16) -> Self {
+        NsqdAddr { host: host.into(), tcp_port, http_port: 4151 }
+    }
+
+    pub fn with_http_port(mut self, http_port: u16) -> Self {
+        self.http_port = http_port;
+        self
+    }
+
+    /// `host:port` of the nsqd TCP protocol, with IPv6 literals in brackets
+    pub fn tcp_address(&self) -> String {
+        join_host_port(&self.host, self.tcp_port)
+    }
+
+    /// `host:port` of the nsqd HTTP API, with IPv6 literals in brackets
+    pub fn http_address(&self) -> String {
+        join_host_port(&self.host, self.http_port)
+    }
+}
+
+impl From<&Producer> for NsqdAddr {
+    fn from(producer: &Producer) -> Self {
+        NsqdAddr::new(producer.broadcast_address.clone(), producer.tcp_port).with_http_port(producer.http_port)
+    }
+}
+
+/// Parses the TCP address, `host:port` or `[ipv6]:port`.
+impl FromStr for NsqdAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidConfig { field: "nsqd_addr", reason: format!("{:?} isn't host:port", s) };
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(NsqdAddr::new(host, port.parse().map_err(|_| invalid())?))
+    }
+}
+
+impl fmt::Display for NsqdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.tcp_address())
+    }
+}
+
+/// A source of the nsqd nodes having a topic, polled by [`DiscoveryPoller`].
+///
+/// Implemented by [`Lookup`] and [`CachedLookup`], [`StaticDiscovery`], [`DnsDiscovery`] and
+/// [`SrvDiscovery`].
+/// Implement it to discover nodes from elsewhere.
+pub trait Discovery: Send + Sync {
+    fn discover<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>>;
+}
+
+impl Discovery for Lookup {
+    fn discover<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>> {
+        async move {
+            Ok(self.lookup(topic).await?.producers.iter().map(NsqdAddr::from).collect())
+        }.boxed()
+    }
+}
+
+impl Discovery for CachedLookup {
+    fn discover<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>> {
+        async move {
+            Ok(self.lookup(topic).await?.producers.iter().map(NsqdAddr::from).collect())
+        }.boxed()
+    }
+}
+
+/// The same nodes for every topic, e.g. a fixed set of nsqd without nsqlookupd.
+#[derive(Debug, Clone)]
+pub struct StaticDiscovery {
+    nodes: Vec<NsqdAddr>,
+}
+
+impl StaticDiscovery {
+    pub fn new(nodes: impl IntoIterator<Item = NsqdAddr>) -> Self {
+        StaticDiscovery { nodes: nodes.into_iter().collect() }
+    }
+
+    /// The nodes at these TCP addresses, `host:port` each.
+    pub fn from_addresses<I>(addrs: I) -> Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let nodes = addrs.into_iter().map(|addr| addr.as_ref().parse()).collect::<Result<_>>()?;
+        Ok(StaticDiscovery { nodes })
+    }
+}
+
+impl Discovery for StaticDiscovery {
+    fn discover<'a>(&'a self, _topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>> {
+        future::ready(Ok(self.nodes.clone())).boxed()
+    }
+}
+
+/// One node per address a DNS name resolves to, for every topic, e.g. a Kubernetes headless
+/// service in front of nsqd pods.
+#[derive(Debug, Clone)]
+pub struct DnsDiscovery {
+    name: String,
+    tcp_port: u16,
+    http_port: u16,
+    resolver: Resolver,
+}
+
+impl DnsDiscovery {
+    /// Resolve `name` with the system resolver, nsqd listening on `tcp_port` and the HTTP
+    /// API on 4151 at each address.
+    pub fn new(name: impl Into<String>, tcp_port: u16) -> Self {
+        DnsDiscovery { name: name.into(), tcp_port, http_port: 4151, resolver: Resolver::default() }
+    }
+
+    pub fn with_http_port(mut self, http_port: u16) -> Self {
+        self.http_port = http_port;
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+}
+
+impl Discovery for DnsDiscovery {
+    fn discover<'a>(&'a self, _topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>> {
+        async move {
+            let mut nodes = Vec::new();
+            for addr in self.resolver.resolve(&self.name, self.tcp_port).await? {
+                let node = NsqdAddr::new(addr.ip().to_string(), self.tcp_port).with_http_port(self.http_port);
+                if !nodes.contains(&node) {
+                    nodes.push(node);
+                }
+            }
+            Ok(nodes)
+        }.boxed()
+    }
+}
+
+/// The targets of an SRV record, for every topic, e.g. `_nsqd._tcp.nsq.svc.cluster.local`
+/// in DNS-SD or service mesh environments. Each target is an nsqd at the record's port, its
+/// HTTP API on 4151. See the [`dns`] module.
+#[derive(Debug, Clone)]
+pub struct SrvDiscovery {
+    name: String,
+    http_port: u16,
+    nameserver: SocketAddr,
+}
+
+impl SrvDiscovery {
+    /// Resolve `name` asking the first nameserver of `/etc/resolv.conf`.
+    pub fn new(name: impl Into<String>) -> Self {
+        SrvDiscovery { name: name.into(), http_port: 4151, nameserver: dns::system_nameserver() }
+    }
+
+    pub fn with_http_port(mut self, http_port: u16) -> Self {
+        self.http_port = http_port;
+        self
+    }
+
+    pub fn with_nameserver(mut self, nameserver: SocketAddr) -> Self {
+        self.nameserver = nameserver;
+        self
+    }
+}
+
+impl Discovery for SrvDiscovery {
+    fn discover<'a>(&'a self, _topic: &'a str) -> BoxFuture<'a, Result<Vec<NsqdAddr>>> {
+        async move {
+            let records = dns::resolve_srv(&self.name, self.nameserver).await?;
+            Ok(records.into_iter()
+                .map(|record| NsqdAddr::new(record.target, record.port).with_http_port(self.http_port))
+                .collect())
+        }.boxed()
+    }
+}
+
+/// A change in the nodes having the topic.
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    NodeAdded(NsqdAddr),
+    NodeRemoved(NsqdAddr),
+}
+
+/// Asks a [`Discovery`] source for the nodes having a topic every
+/// `Config::lookupd_poll_interval` and yields those which appeared or disappeared since the
+/// previous time, told apart by their TCP address.
+///
+/// A failed discovery is yielded as an error and changes nothing, so nodes aren't dropped
+/// while the source is unreachable. Polling goes on for as long as the stream is polled.
+pub struct DiscoveryPoller {
+    events: BoxStream<'static, Result<DiscoveryEvent>>,
+}
+
+impl DiscoveryPoller {
+    pub fn new<D>(discovery: D, topic: impl Into<String>, config: &Config) -> Self
+    where
+        D: Discovery + 'static,
+    {
+        let state = (discovery, topic.into(), Vec::new(), poll_ticker(config));
+        let events = stream::unfold(state, |(discovery, topic, mut known, mut ticker)| async move {
+            ticker.tick().await;
+            let events = match discovery.discover(&topic).await {
+                Ok(nodes) => changes(&mut known, nodes).into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            Some((stream::iter(events), (discovery, topic, known, ticker)))
+        }).flatten();
+        DiscoveryPoller { events: events.boxed() }
+    }
+}
+
+impl Stream for DiscoveryPoller {
+    type Item = Result<DiscoveryEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+/// Replace `known` with `current`, returning the nodes added and then those removed.
+fn changes(known: &mut Vec<NsqdAddr>, mut current: Vec<NsqdAddr>) -> Vec<DiscoveryEvent> {
+    // A source may list a node twice, e.g. registered with two nsqlookupd
+    let mut seen = HashSet::new();
+    current.retain(|node| seen.insert(node.tcp_address()));
+    let mut events: Vec<_> = current.iter()
+        .filter(|node| !known.iter().any(|k| k.tcp_address() == node.tcp_address()))
+        .cloned()
+        .map(DiscoveryEvent::NodeAdded)
+        .collect();
+    events.extend(known.drain(..)
+        .filter(|k| !current.iter().any(|node| node.tcp_address() == k.tcp_address()))
+        .map(DiscoveryEvent::NodeRemoved));
+    *known = current;
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use futures::future::{self, BoxFuture};
+    use futures::{FutureExt, StreamExt};
+
+    use crate::config::Config;
+    use crate::conn::{Resolve, Resolver};
+    use crate::lookup::Lookup;
+    use crate::testing;
+    use super::{changes, Discovery, DiscoveryEvent, DiscoveryPoller, DnsDiscovery, NsqdAddr, StaticDiscovery};
+
+    #[test]
+    fn duplicate_nodes_are_added_once() {
+        let mut known = vec![NsqdAddr::new("10.0.0.1", 4150)];
+        let current = vec![
+            NsqdAddr::new("10.0.0.1", 4150),
+            NsqdAddr::new("10.0.0.2", 4150),
+            NsqdAddr::new("10.0.0.2", 4150),
+        ];
+        let events = changes(&mut known, current);
+        assert!(matches!(&events[..], [DiscoveryEvent::NodeAdded(node)] if node.host == "10.0.0.2"));
+        assert_eq!(known.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn nodes_added_and_removed_are_reported() {
+        let url = testing::serve_lookupd(vec![
+            testing::lookup_response(&["10.0.0.1", "10.0.0.2"]),
+            "not json".to_string(),
+            testing::lookup_response(&["10.0.0.2", "10.0.0.3"]),
+        ]).await;
+        let config = Config::builder().lookupd_poll(Duration::from_millis(10), 0.0).build().unwrap();
+        let poller = DiscoveryPoller::new(Lookup::new(url.as_str()).unwrap(), "t", &config);
+
+        let events: Vec<_> = poller.take(5).collect().await;
+        let events: Vec<_> = events.iter().map(|event| match event {
+            Ok(DiscoveryEvent::NodeAdded(node)) => format!("+{}", node.host),
+            Ok(DiscoveryEvent::NodeRemoved(node)) => format!("-{}", node.host),
+            Err(_) => "error".to_string(),
+        }).collect();
+        assert_eq!(events, ["+10.0.0.1", "+10.0.0.2", "error", "+10.0.0.3", "-10.0.0.1"]);
+    }
+
+    struct FixedResolver;
+
+    impl Resolve for FixedResolver {
+        fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            assert_eq!(host, "nsqd.default.svc");
+            let ips = ["10.0.0.1", "::1", "10.0.0.1"];
+            future::ready(Ok(ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), port)).collect())).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn static_and_dns_sources_are_discovered() {
+        let nodes = StaticDiscovery::from_addresses(["10.0.0.1:4150", "[::1]:4250"]).unwrap()
+            .discover("t").await.unwrap();
+        assert_eq!(nodes, [NsqdAddr::new("10.0.0.1", 4150), NsqdAddr::new("::1", 4250)]);
+        assert_eq!(nodes[1].tcp_address(), "[::1]:4250");
+        assert!(StaticDiscovery::from_addresses(["10.0.0.1"]).is_err());
+        assert!(StaticDiscovery::from_addresses([":4150"]).is_err());
+
+        let dns = DnsDiscovery::new("nsqd.default.svc", 4150)
+            .with_http_port(4251)
+            .with_resolver(Resolver::new(FixedResolver));
+        let nodes = dns.discover("t").await.unwrap();
+        let addrs: Vec<_> = nodes.iter().map(|node| (node.tcp_address(), node.http_address())).collect();
+        assert_eq!(addrs, [
+            ("10.0.0.1:4150".to_string(), "10.0.0.1:4251".to_string()),
+            ("[::1]:4150".to_string(), "[::1]:4251".to_string()),
+        ]);
+    }
+}
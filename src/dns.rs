@@ -0,0 +1,318 @@
+//! DNS SRV lookups, to find nsqd or nsqlookupd from records like
+//! `_nsqd._tcp.nsq.svc.cluster.local`, see [`SrvDiscovery`](crate::discovery::SrvDiscovery)
+//! and [`Lookup::from_srv`](crate::Lookup::from_srv).
+//!
+//! A minimal stub resolver: one query to one nameserver, over UDP, sent again if unanswered,
+//! and over TCP when the answer is truncated. No caching, no search domains, the name is
+//! queried as given.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::debug;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+/// How long to wait for each UDP answer, the query being sent again after the first two
+const UDP_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+const UDP_ATTEMPTS: u32 = 3;
+
+/// One target of an SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Host name, without the trailing dot
+    pub target: String,
+}
+
+/// The first `nameserver` of `/etc/resolv.conf`, 127.0.0.1 if there is none.
+pub fn system_nameserver() -> SocketAddr {
+    let ip = std::fs::read_to_string("/etc/resolv.conf").ok().and_then(|conf| {
+        conf.lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|ip| ip.trim().parse::<IpAddr>().ok())
+    });
+    SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), 53)
+}
+
+/// The SRV records of `name`, by priority and then heaviest first, asking `nameserver`.
+///
+/// A name which doesn't exist fails with `io::ErrorKind::NotFound`, so a discovery polling it
+/// keeps the nodes it knows through a misconfigured or briefly missing record. Targets of
+/// `"."`, meaning the service isn't available there, are left out. Gives up after 5 seconds
+/// with an `io::ErrorKind::TimedOut` error.
+pub async fn resolve_srv(name: &str, nameserver: SocketAddr) -> io::Result<Vec<SrvRecord>> {
+    let query = encode_query(fastrand::u16(..), name)?;
+    let answer = async {
+        match query_udp(&query, nameserver).await? {
+            Answer::Truncated => query_tcp(&query, nameserver).await?.records(),
+            answer => answer.records(),
+        }
+    };
+    let mut records = tokio::time::timeout(crate::lookup::DEFAULT_TIMEOUT, answer).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("SRV lookup of {} timed out", name)))??;
+    records.sort_by_key(|record| (record.priority, u16::MAX - record.weight));
+    Ok(records)
+}
+
+enum Answer {
+    Records(Vec<SrvRecord>),
+    Truncated,
+}
+
+impl Answer {
+    fn records(self) -> io::Result<Vec<SrvRecord>> {
+        match self {
+            Answer::Records(records) => Ok(records),
+            Answer::Truncated => Err(invalid("truncated answer over TCP")),
+        }
+    }
+}
+
+async fn query_udp(query: &[u8], nameserver: SocketAddr) -> io::Result<Answer> {
+    let local: SocketAddr = if nameserver.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(nameserver).await?;
+    let mut buf = vec![0u8; 4096];
+    for attempt in 1..=UDP_ATTEMPTS {
+        socket.send(query).await?;
+        match tokio::time::timeout(UDP_ATTEMPT_TIMEOUT, recv_answer(&socket, &mut buf, query)).await {
+            Ok(answer) => return answer,
+            Err(_) => debug!("no answer from {} to attempt {} of the SRV query", nameserver, attempt),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from nameserver {}", nameserver)))
+}
+
+async fn recv_answer(socket: &UdpSocket, buf: &mut [u8], query: &[u8]) -> io::Result<Answer> {
+    loop {
+        let n = socket.recv(buf).await?;
+        // Ignore stray datagrams, e.g. late answers to an earlier query
+        if n >= 2 && buf[..2] == query[..2] {
+            return decode_answer(&buf[..n], &query[..2]);
+        }
+    }
+}
+
+async fn query_tcp(query: &[u8], nameserver: SocketAddr) -> io::Result<Answer> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    decode_answer(&buf, &query[..2])
+}
+
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid DNS name {:?}", name)));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+fn decode_answer(msg: &[u8], id: &[u8]) -> io::Result<Answer> {
+    if msg.len() < 12 || &msg[..2] != id || msg[2] & 0x80 == 0 {
+        return Err(invalid("not an answer to the query"));
+    }
+    if msg[2] & 0x02 != 0 {
+        return Ok(Answer::Truncated);
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "no such name (NXDOMAIN)")),
+        rcode => return Err(io::Error::other(format!("nameserver failed with rcode {}", rcode))),
+    }
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let (rtype, class, len) = (read_u16(msg, pos)?, read_u16(msg, pos + 2)?, read_u16(msg, pos + 8)? as usize);
+        let data = pos + 10;
+        if data + len > msg.len() {
+            return Err(invalid("record past the end of the answer"));
+        }
+        // Other records, e.g. the CNAME the name is an alias for, are skipped
+        if rtype == TYPE_SRV && class == CLASS_IN {
+            // Priority, weight, port and at least the root label of the target
+            if len < 7 {
+                return Err(invalid("SRV record too short"));
+            }
+            let (target, end) = read_name(msg, data + 6)?;
+            if end > data + len {
+                return Err(invalid("SRV target past the end of its record"));
+            }
+            // The root, "." in RFC 2782, says the service isn't available at this name
+            if !target.is_empty() {
+                records.push(SrvRecord {
+                    priority: read_u16(msg, data)?,
+                    weight: read_u16(msg, data + 2)?,
+                    port: read_u16(msg, data + 4)?,
+                    target,
+                });
+            }
+        }
+        pos = data + len;
+    }
+    Ok(Answer::Records(records))
+}
+
+/// The name at `pos`, following compression pointers, and where what follows it starts.
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Each pointer must go backwards, which rules out loops
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| invalid("name past the end of the answer"))? as usize;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let target = (read_u16(msg, pos)? & 0x3fff) as usize;
+                if target >= limit {
+                    return Err(invalid("name pointer doesn't go backwards"));
+                }
+                end.get_or_insert(pos + 2);
+                pos = target;
+                limit = target;
+            }
+            len if len <= 63 => {
+                let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(|| invalid("name past the end of the answer"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+            _ => return Err(invalid("invalid label")),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> io::Result<u16> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("answer too short"))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::discovery::{Discovery, NsqdAddr, SrvDiscovery};
+    use super::{resolve_srv, SrvRecord};
+
+    /// Answer the query with `answers`, a record each, their owner a pointer to the question.
+    fn answer(query: &[u8], rcode: u8, answers: &[&[u8]]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80 | rcode;
+        msg[7] = answers.len() as u8;
+        for rdata in answers {
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 30, 0, rdata.len() as u8]);
+            msg.extend_from_slice(rdata);
+        }
+        msg
+    }
+
+    // Priority 10, weight 5, port 4150, nsqd-0.nsqd.local
+    const NSQD_0: &[u8] = b"\0\x0a\0\x05\x10\x36\x06nsqd-0\x04nsqd\x05local\0";
+
+    #[tokio::test]
+    async fn srv_records_are_resolved() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            for rcode in [0, 0, 3, 0] {
+                let (n, peer) = server.recv_from(&mut buf).await?;
+                // Priority 10, weight 20, port 4150, nsqd-1 then a pointer to the `nsqd.local`
+                // of the first, after the query, its record header and 13 bytes of its data
+                let second = [&b"\0\x0a\0\x14\x10\x36\x06nsqd-1\xc0"[..], &[(n + 12 + 13) as u8]].concat();
+                // The service isn't available at the root target
+                let unavailable: &[u8] = b"\0\x01\0\x01\x10\x36\0";
+                let answers: &[&[u8]] = match buf[n - 6] {
+                    // The query for the name with a record too short to hold a target
+                    b't' => &[b"\0\x0a\0\x05\x10\x36"],
+                    _ if rcode == 0 => &[NSQD_0, &second, unavailable],
+                    _ => &[],
+                };
+                server.send_to(&answer(&buf[..n], rcode, answers), peer).await?;
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        let records = resolve_srv("_nsqd._tcp.nsq.local", nameserver).await.unwrap();
+        assert_eq!(records, [
+            SrvRecord { priority: 10, weight: 20, port: 4150, target: "nsqd-1.nsqd.local".into() },
+            SrvRecord { priority: 10, weight: 5, port: 4150, target: "nsqd-0.nsqd.local".into() },
+        ]);
+
+        let discovery = SrvDiscovery::new("_nsqd._tcp.nsq.local").with_nameserver(nameserver);
+        assert_eq!(discovery.discover("t").await.unwrap(), [
+            NsqdAddr::new("nsqd-1.nsqd.local", 4150),
+            NsqdAddr::new("nsqd-0.nsqd.local", 4150),
+        ]);
+
+        // The name doesn't exist
+        let err = resolve_srv("_nsqd._tcp.nsq.local", nameserver).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = resolve_srv("_nsqd._tcp.nsq.short", nameserver).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(resolve_srv("bad..name", nameserver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_queried_again_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(nameserver).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (n, peer) = udp.recv_from(&mut buf).await?;
+            let mut truncated = answer(&buf[..n], 0, &[]);
+            truncated[2] |= 0x02;
+            udp.send_to(&truncated, peer).await?;
+
+            let (mut stream, _) = tcp.accept().await?;
+            let len = stream.read_u16().await? as usize;
+            let mut query = vec![0u8; len];
+            stream.read_exact(&mut query).await?;
+            let full = answer(&query, 0, &[NSQD_0]);
+            stream.write_all(&(full.len() as u16).to_be_bytes()).await?;
+            stream.write_all(&full).await
+        });
+
+        let records = resolve_srv("_nsqd._tcp.nsq.local", nameserver).await.unwrap();
+        assert_eq!(records, [SrvRecord { priority: 10, weight: 5, port: 4150, target: "nsqd-0.nsqd.local".into() }]);
+    }
+}
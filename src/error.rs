@@ -1,4 +1,7 @@
 use std::io;
+use std::net::SocketAddr;
+
+use crate::command::MessageId;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 pub type UrlParseError = url::ParseError;
@@ -6,23 +9,61 @@ pub type UrlParseError = url::ParseError;
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
+    /// Every address the host resolved to failed to connect, with the error of each
+    ConnectFailed(Vec<(SocketAddr, io::Error)>),
     Utf8Error(std::str::Utf8Error),
     JsonError(serde_json::Error),
+    #[cfg(feature = "toml")]
+    TomlError(toml::de::Error),
     NsqError(NsqError),
+    /// An error response of nsqlookupd's HTTP API
+    LookupError(LookupError),
     #[cfg(feature = "tls-native")]
-    TlsError(native_tls::Error),
+    TlsError(tokio_native_tls::native_tls::Error),
     #[cfg(feature = "tls-tokio")]
     InvalidDnsNameError(tokio_rustls::rustls::client::InvalidDnsNameError),
+    #[cfg(feature = "tls-tokio")]
+    RustlsError(tokio_rustls::rustls::Error),
     SnapError(snap::Error),
     DeflateCompressError(flate2::CompressError),
     DeflateDecompressError(flate2::DecompressError),
+    #[cfg(feature = "http-reqwest")]
     HttpError(reqwest::Error),
     Auth(String),
+    /// nsqd sent something the protocol doesn't allow, e.g. a JSON response outside of
+    /// negotiation. With `Config::strict_protocol`, also frames which merely deviate from the
+    /// spec. Frames which can't be decoded at all, like one of an unknown type, arrive as
+    /// `MalformedFrame { source: ProtocolViolation(..), .. }` instead
+    ProtocolViolation(String),
+    /// A frame which couldn't be decoded, with where it went wrong
+    MalformedFrame {
+        frame_type: i32,
+        /// Including the size prefix
+        len: usize,
+        /// Hex of the first bytes after the size prefix, starting with the frame type
+        head: String,
+        source: Box<Error>,
+    },
+    /// A message body a [`PayloadCodec`](crate::payload::PayloadCodec) couldn't encode or decode
+    Payload(Box<dyn std::error::Error + Send + Sync>),
+    /// A message rejected by `Config::message_validator`, still in flight
+    InvalidMessage {
+        id: MessageId,
+        reason: String,
+    },
+    /// A message id which isn't 16 hex digits, see `MessageId::from_str`
+    InvalidMessageId(String),
+    /// A command nsqd would reject in the connection's current state, e.g. `PUB` after `SUB`
+    InvalidCommand(String),
+    InvalidConfig {
+        field: &'static str,
+        reason: String,
+    },
     UrlParseError(UrlParseError),
     UnknownError(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NsqError {
     code: String,
     description: String,
@@ -39,12 +80,30 @@ impl NsqError {
         }
     }
 
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
     pub fn is_fatal(&self) -> bool {
         match self.code.as_str() {
             "E_FIN_FAILED" | "E_REQ_FAILED" | "E_TOUCH_FAILED" => false,
             _ => true,
         }
     }
+
+    /// Whether the node refused the connection because it has too many of them.
+    ///
+    /// nsqd itself has no such limit, but proxies and patched servers in front of it answer
+    /// with `E_TOO_MANY_CONNECTIONS` or a similar description. Reconnecting to the same node
+    /// right away won't help, so callers should back off from it for longer than usual.
+    pub fn is_at_capacity(&self) -> bool {
+        self.code == "E_TOO_MANY_CONNECTIONS"
+            || self.description.to_ascii_lowercase().contains("too many connections")
+    }
 }
 
 impl std::error::Error for NsqError {}
@@ -55,6 +114,70 @@ impl std::fmt::Display for NsqError {
     }
 }
 
+/// What nsqlookupd answered with an error status, by its message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupError {
+    /// 404 `TOPIC_NOT_FOUND`: no nsqd has registered the topic, or not yet
+    TopicNotFound,
+    /// 404 `CHANNEL_NOT_FOUND`
+    ChannelNotFound,
+    /// 400 `MISSING_ARG_*`, with the argument, e.g. `TOPIC`
+    MissingArg(String),
+    /// 400 `INVALID_ARG_*`, with the argument
+    InvalidArg(String),
+    /// Any other error status, with the message if there is one
+    Other {
+        status: u16,
+        message: String,
+    },
+}
+
+impl LookupError {
+    /// Read an error response, `{"message": ...}` or, before nsqlookupd 1.0,
+    /// `{"status_txt": ...}`, falling back to the body as text.
+    pub fn from_response(status: u16, body: &[u8]) -> LookupError {
+        let json: Option<serde_json::Value> = serde_json::from_slice(body).ok();
+        let message = json.as_ref()
+            .and_then(|json| json.get("message").or_else(|| json.get("status_txt")))
+            .and_then(|message| message.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| String::from_utf8_lossy(body).trim().to_string());
+        match message.as_str() {
+            "TOPIC_NOT_FOUND" => LookupError::TopicNotFound,
+            "CHANNEL_NOT_FOUND" => LookupError::ChannelNotFound,
+            _ => match (message.strip_prefix("MISSING_ARG_"), message.strip_prefix("INVALID_ARG_")) {
+                (Some(arg), _) => LookupError::MissingArg(arg.to_string()),
+                (_, Some(arg)) => LookupError::InvalidArg(arg.to_string()),
+                _ => LookupError::Other { status, message },
+            },
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+impl std::fmt::Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::TopicNotFound => f.write_str("lookupd: topic not found"),
+            LookupError::ChannelNotFound => f.write_str("lookupd: channel not found"),
+            LookupError::MissingArg(arg) => write!(f, "lookupd: missing argument {}", arg),
+            LookupError::InvalidArg(arg) => write!(f, "lookupd: invalid argument {}", arg),
+            LookupError::Other { status, message } => write!(f, "lookupd: status {}: {}", status, message),
+        }
+    }
+}
+
+impl Error {
+    /// See [`NsqError::is_at_capacity`].
+    pub fn is_at_capacity(&self) -> bool {
+        match self {
+            Error::NsqError(e) => e.is_at_capacity(),
+            _ => false,
+        }
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         use Error::*;
@@ -62,16 +185,24 @@ impl std::error::Error for Error {
             IoError(e) => Some(e),
             Utf8Error(e) => Some(e),
             JsonError(e) => Some(e),
+            #[cfg(feature = "toml")]
+            TomlError(e) => Some(e),
             NsqError(e) => Some(e),
+            LookupError(e) => Some(e),
             #[cfg(feature = "tls-native")]
             TlsError(e) => Some(e),
             #[cfg(feature = "tls-tokio")]
             InvalidDnsNameError(e) => Some(e),
+            #[cfg(feature = "tls-tokio")]
+            RustlsError(e) => Some(e),
             SnapError(e) => Some(e),
             DeflateCompressError(e) => Some(e),
             DeflateDecompressError(e) => Some(e),
+            #[cfg(feature = "http-reqwest")]
             HttpError(e) => Some(e),
             UrlParseError(e) => Some(e),
+            MalformedFrame { source, .. } => Some(source),
+            Payload(e) => Some(e.as_ref()),
             _ => None,
         }
     }
@@ -84,15 +215,38 @@ impl std::fmt::Display for Error {
             IoError(e) => e.fmt(f),
             Utf8Error(e) => e.fmt(f),
             JsonError(e) => e.fmt(f),
+            #[cfg(feature = "toml")]
+            TomlError(e) => e.fmt(f),
             NsqError(e) => e.fmt(f),
+            LookupError(e) => e.fmt(f),
             #[cfg(feature = "tls-native")]
             TlsError(e) => e.fmt(f),
             #[cfg(feature = "tls-tokio")]
             InvalidDnsNameError(e) => e.fmt(f),
+            #[cfg(feature = "tls-tokio")]
+            RustlsError(e) => e.fmt(f),
             SnapError(e) => e.fmt(f),
             DeflateCompressError(e) => e.fmt(f),
             DeflateDecompressError(e) => e.fmt(f),
+            ConnectFailed(errors) if errors.is_empty() => write!(f, "Connect failed: no address resolved"),
+            ConnectFailed(errors) => {
+                write!(f, "Connect failed:")?;
+                for (addr, e) in errors {
+                    write!(f, " {}: {};", addr, e)?;
+                }
+                Ok(())
+            }
             Auth(e) => write!(f, "Auth Error: {}", e),
+            ProtocolViolation(e) => write!(f, "Protocol violation: {}", e),
+            MalformedFrame { frame_type, len, head, source } => {
+                write!(f, "Malformed frame of type {}, {} bytes starting {}: {}", frame_type, len, head, source)
+            }
+            Payload(e) => write!(f, "Payload error: {}", e),
+            InvalidMessage { id, reason } => write!(f, "Invalid message {}: {}", id, reason),
+            InvalidMessageId(e) => write!(f, "Invalid message id: {}", e),
+            InvalidCommand(e) => write!(f, "Invalid command: {}", e),
+            InvalidConfig { field, reason } => write!(f, "Invalid config `{}`: {}", field, reason),
+            #[cfg(feature = "http-reqwest")]
             HttpError(e) => e.fmt(f),
             UrlParseError(e) => e.fmt(f),
             UnknownError(e) => write!(f, "Known Error: {}", e),
@@ -112,6 +266,12 @@ impl From<NsqError> for Error {
     }
 }
 
+impl From<LookupError> for Error {
+    fn from(e: LookupError) -> Error {
+        Error::LookupError(e)
+    }
+}
+
 impl From<::std::str::Utf8Error> for Error {
     fn from(e: ::std::str::Utf8Error) -> Error {
         Error::Utf8Error(e)
@@ -124,9 +284,16 @@ impl From<::serde_json::Error> for Error {
     }
 }
 
+#[cfg(feature = "toml")]
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        Error::TomlError(e)
+    }
+}
+
 #[cfg(feature = "tls-native")]
-impl From<::native_tls::Error> for Error {
-    fn from(e: native_tls::Error) -> Error {
+impl From<tokio_native_tls::native_tls::Error> for Error {
+    fn from(e: tokio_native_tls::native_tls::Error) -> Error {
         Error::TlsError(e)
     }
 }
@@ -138,6 +305,13 @@ impl From<tokio_rustls::rustls::client::InvalidDnsNameError> for Error {
     }
 }
 
+#[cfg(feature = "tls-tokio")]
+impl From<tokio_rustls::rustls::Error> for Error {
+    fn from(e: tokio_rustls::rustls::Error) -> Error {
+        Error::RustlsError(e)
+    }
+}
+
 impl From<snap::Error> for Error {
     fn from(e: snap::Error) -> Error {
         Error::SnapError(e)
@@ -156,6 +330,7 @@ impl From<flate2::DecompressError> for Error {
     }
 }
 
+#[cfg(feature = "http-reqwest")]
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Error {
         Error::HttpError(e)
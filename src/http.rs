@@ -0,0 +1,328 @@
+//! The HTTP client behind `Lookup`, `NsqdStats` and `Producer::ensure_topic`.
+//!
+//! With the `http-reqwest` feature, on by default, it is reqwest. Without it, a minimal
+//! HTTP/1.1 client over plain TCP takes its place, one connection per request, so discovery
+//! doesn't pull in reqwest's dependency tree. It only speaks `http://`, sends
+//! `Connection: close` and reads the whole body into memory. It is built for tests either
+//! way, so it stays tested along with reqwest.
+
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use url::Url;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Method {
+    Get,
+    Post,
+}
+
+/// Sent with every request, e.g. to an authenticating proxy in front of nsqlookupd.
+#[derive(Clone)]
+pub(crate) enum Credentials {
+    Basic { username: String, password: Option<String> },
+    Bearer(String),
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub(crate) fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Whether `e` may go away by trying again: a timeout or a failure to connect.
+pub(crate) fn is_transient(e: &Error) -> bool {
+    match e {
+        #[cfg(feature = "http-reqwest")]
+        Error::HttpError(e) => e.is_timeout() || e.is_connect(),
+        Error::IoError(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn is_timeout(e: &Error) -> bool {
+    match e {
+        #[cfg(feature = "http-reqwest")]
+        Error::HttpError(e) => e.is_timeout(),
+        Error::IoError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "http-reqwest")]
+pub(crate) struct Client {
+    inner: reqwest::Client,
+}
+
+#[cfg(feature = "http-reqwest")]
+impl Client {
+    /// A client giving up on requests after `timeout`, trusting the CAs of `root_ca`, a PEM
+    /// bundle and where it was read from, on top of the system's.
+    pub(crate) fn new(timeout: Duration, root_ca: Option<(&[u8], &str)>) -> Result<Self> {
+        let mut builder = reqwest::Client::builder().timeout(timeout);
+        if let Some((pem, source)) = root_ca {
+            let certs = reqwest::Certificate::from_pem_bundle(pem)?;
+            if certs.is_empty() {
+                let reason = format!("no certificate found in {}", source);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, reason).into());
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(Client { inner: builder.build()? })
+    }
+
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        query: &[(&str, &str)],
+        credentials: Option<&Credentials>,
+    ) -> Result<Response> {
+        let method = match method {
+            Method::Get => reqwest::Method::GET,
+            Method::Post => reqwest::Method::POST,
+        };
+        let mut request = self.inner.request(method, url).query(query);
+        request = match credentials {
+            Some(Credentials::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+            Some(Credentials::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        };
+        let resp = request.send().await?;
+        let status = resp.status().as_u16();
+        let body = resp.bytes().await?.to_vec();
+        Ok(Response { status, body })
+    }
+}
+
+#[cfg(not(feature = "http-reqwest"))]
+pub(crate) struct Client {
+    timeout: Duration,
+}
+
+#[cfg(not(feature = "http-reqwest"))]
+impl Client {
+    /// A client giving up on requests after `timeout`. Without TLS there is no use for a
+    /// root CA, so one is an error.
+    pub(crate) fn new(timeout: Duration, root_ca: Option<(&[u8], &str)>) -> Result<Self> {
+        if root_ca.is_some() {
+            return Err(Error::InvalidConfig {
+                field: "root_ca",
+                reason: "HTTPS needs the http-reqwest feature".into(),
+            });
+        }
+        Ok(Client { timeout })
+    }
+
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        mut url: Url,
+        query: &[(&str, &str)],
+        credentials: Option<&Credentials>,
+    ) -> Result<Response> {
+        if url.scheme() != "http" {
+            return Err(Error::InvalidConfig {
+                field: "url",
+                reason: format!("{} needs the http-reqwest feature", url.scheme()),
+            });
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        match tokio::time::timeout(self.timeout, plain::send(method, &url, credentials)).await {
+            Ok(res) => res,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "HTTP request timed out").into()),
+        }
+    }
+}
+
+#[cfg(any(test, not(feature = "http-reqwest")))]
+mod plain {
+    use std::io;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use url::{Position, Url};
+
+    use super::{Credentials, Method, Response};
+    use crate::error::Result;
+
+    pub(super) async fn send(method: Method, url: &Url, credentials: Option<&Credentials>) -> Result<Response> {
+        let host = url.host_str().ok_or_else(|| invalid("URL without a host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let mut stream = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port)).await?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: */*\r\nConnection: close\r\n",
+            match method { Method::Get => "GET", Method::Post => "POST" },
+            &url[Position::BeforePath..Position::AfterQuery],
+            &url[Position::BeforeHost..Position::AfterPort],
+            crate::USER_AGENT,
+        );
+        if method == Method::Post {
+            request.push_str("Content-Length: 0\r\n");
+        }
+        match credentials {
+            Some(Credentials::Basic { username, password }) => {
+                let user_pass = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+                request.push_str(&format!("Authorization: Basic {}\r\n", base64(user_pass.as_bytes())));
+            }
+            Some(Credentials::Bearer(token)) => request.push_str(&format!("Authorization: Bearer {}\r\n", token)),
+            None => {}
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read until the body is complete, or the server closes the connection
+        let mut raw = Vec::new();
+        loop {
+            let eof = stream.read_buf(&mut raw).await? == 0;
+            if let Some(resp) = parse(&raw, eof)? {
+                return Ok(resp);
+            }
+        }
+    }
+
+    /// The status and body of a response, undoing chunked encoding, `None` while it isn't
+    /// complete. `eof` says no more is coming, so an incomplete response is an error.
+    pub(super) fn parse(raw: &[u8], eof: bool) -> Result<Option<Response>> {
+        let incomplete = |what| if eof { Err(invalid(what).into()) } else { Ok(None) };
+        let head_len = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(len) => len,
+            None => return incomplete("truncated HTTP response"),
+        };
+        let head = std::str::from_utf8(&raw[..head_len]).map_err(|_| invalid("HTTP response head isn't UTF-8"))?;
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid("malformed HTTP status line"))?;
+        let mut chunked = false;
+        let mut content_length = None;
+        for line in lines {
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse::<usize>().ok();
+            }
+        }
+
+        let rest = &raw[head_len + 4..];
+        let body = if chunked {
+            match dechunk(rest)? {
+                Some(body) => body,
+                None => return incomplete("truncated chunk"),
+            }
+        } else {
+            match content_length {
+                Some(len) if len > rest.len() => return incomplete("truncated HTTP body"),
+                Some(len) => rest[..len].to_vec(),
+                None if eof => rest.to_vec(),
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(Response { status, body }))
+    }
+
+    fn dechunk(mut rest: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut body = Vec::new();
+        loop {
+            let line_len = match rest.windows(2).position(|w| w == b"\r\n") {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let size = std::str::from_utf8(&rest[..line_len]).ok()
+                .map(|line| line.split(';').next().unwrap_or("").trim())
+                .and_then(|size| usize::from_str_radix(size, 16).ok())
+                .ok_or_else(|| invalid("malformed chunk size"))?;
+            rest = &rest[line_len + 2..];
+            if size == 0 {
+                return Ok(Some(body));
+            }
+            if rest.len() < size + 2 {
+                return Ok(None);
+            }
+            body.extend_from_slice(&rest[..size]);
+            rest = &rest[size + 2..];
+        }
+    }
+
+    fn base64(input: &[u8]) -> String {
+        const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+        for chunk in input.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    fn invalid(reason: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use url::Url;
+
+        use crate::http::{Credentials, Method};
+        use crate::testing;
+        use super::{base64, parse, send};
+
+        #[tokio::test]
+        async fn requests_are_sent() {
+            let url = testing::serve_lookupd_with(vec![(404, "TOPIC_NOT_FOUND".to_string())]).await;
+            let url = Url::parse(&url).unwrap().join("/lookup?topic=t").unwrap();
+            let credentials = Credentials::Basic { username: "user".into(), password: Some("pass".into()) };
+            let resp = send(Method::Get, &url, Some(&credentials)).await.unwrap();
+            assert_eq!((resp.status, resp.body.as_slice()), (404, &b"TOPIC_NOT_FOUND"[..]));
+        }
+
+        #[test]
+        fn responses_are_parsed() {
+            let resp = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK", false).unwrap().unwrap();
+            assert_eq!((resp.status, resp.body.as_slice()), (200, &b"OK"[..]));
+
+            let chunked = b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n";
+            let resp = parse(chunked, false).unwrap().unwrap();
+            assert_eq!((resp.status, resp.body.as_slice()), (404, &b"abcde"[..]));
+            assert!(parse(&chunked[..chunked.len() - 7], false).unwrap().is_none());
+
+            let truncated = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nOK";
+            assert!(parse(truncated, false).unwrap().is_none());
+            assert!(parse(truncated, true).is_err());
+            assert!(parse(b"HTTP/1.1 200 OK\r\n", true).is_err());
+            assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+            assert_eq!(base64(b"ab"), "YWI=");
+        }
+    }
+}
@@ -16,13 +16,26 @@ pub mod config;
 pub mod producer;
 mod consumer;
 pub mod lookup;
+pub mod discovery;
+pub mod dns;
+pub mod stats;
+pub mod backoff;
+pub mod pool;
+mod http;
 
 pub mod command;
 pub mod conn;
+pub mod protocol;
+pub mod payload;
+#[cfg(feature = "debug-capture")]
+pub mod capture;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub const USER_AGENT: &'static str = concat!("nsq-rust/", env!("CARGO_PKG_VERSION"));
 pub use conn::Connection;
 pub use error::Error;
-pub use config::Config;
+pub use config::{Config, ConsumerConfig, ProducerConfig};
 pub use producer::Producer;
 pub use lookup::Lookup;
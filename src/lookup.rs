@@ -1,24 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::Ipv6Addr;
+use std::sync::{Mutex, MutexGuard};
 use std::time::Duration;
 
-use crate::error::{UrlParseError, Error, Result};
+use crate::config::{BackoffConfig, Config, Pem, MIN_LOOKUPD_POLL_INTERVAL};
+use crate::dns;
+use crate::error::{LookupError, UrlParseError, Error, Result};
+use crate::http::{self, Client, Credentials, Method};
+use futures::{future, stream, Stream};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use reqwest::Url;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+use url::Url;
 
 pub static DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Lookup client
+/// Lookup client, of one or several nsqlookupd
 pub struct Lookup {
-    http_addr: Url,
-    client: reqwest::Client,
+    http_addrs: Vec<Url>,
+    client: Client,
+    credentials: Option<Credentials>,
+    retry: Option<BackoffConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Builds a [`Lookup`] for nsqlookupd behind HTTPS with a private CA, or behind
+/// authentication, see [`Lookup::builder`].
+///
+/// ```no_run
+/// # fn run() -> Result<(), nsq_in_rust::Error> {
+/// let lookup = nsq_in_rust::Lookup::builder()
+///     .address("https://lookupd-1.internal:4161")
+///     .address("https://lookupd-2.internal:4161")
+///     .root_ca_file("/etc/nsq/ca.pem")
+///     .bearer_auth("token")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct LookupBuilder {
+    addrs: Vec<std::result::Result<Url, UrlParseError>>,
+    root_ca_pem: Option<Pem>,
+    root_ca_file: Option<String>,
+    credentials: Option<Credentials>,
+    timeout: Duration,
+    retry: Option<BackoffConfig>,
+}
+
+impl Default for LookupBuilder {
+    fn default() -> Self {
+        LookupBuilder {
+            addrs: Vec::new(),
+            root_ca_pem: None,
+            root_ca_file: None,
+            credentials: None,
+            timeout: DEFAULT_TIMEOUT,
+            retry: None,
+        }
+    }
+}
+
+impl LookupBuilder {
+    /// Add an nsqlookupd, starting with `http://` or `https://`.
+    pub fn address<I: TryInto<Url>>(mut self, url: I) -> Self
+        where UrlParseError: From<<I as TryInto<Url>>::Error>
+    {
+        self.addrs.push(url.try_into().map_err(From::from));
+        self
+    }
+
+    /// How long each request may take, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retry requests failing transiently, by timing out, failing to connect or with a
+    /// server error, after the delays of `retry` and until its `max_attempts`. Requests
+    /// aren't retried by default.
+    pub fn retry(mut self, retry: BackoffConfig) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Trust the CAs in this PEM, on top of the system's, for `https://` addresses.
+    pub fn root_ca_pem(mut self, pem: impl Into<Pem>) -> Self {
+        self.root_ca_pem = Some(pem.into());
+        self
+    }
+
+    /// Like `root_ca_pem`, read from a file when building.
+    pub fn root_ca_file(mut self, path: impl Into<String>) -> Self {
+        self.root_ca_file = Some(path.into());
+        self
+    }
+
+    pub fn basic_auth(mut self, username: impl Into<String>, password: Option<String>) -> Self {
+        self.credentials = Some(Credentials::Basic { username: username.into(), password });
+        self
+    }
+
+    pub fn bearer_auth(mut self, token: impl Into<String>) -> Self {
+        self.credentials = Some(Credentials::Bearer(token.into()));
+        self
+    }
+
+    /// Fails if there is no address or one doesn't parse, the root CA can't be read, or the
+    /// retry policy is invalid.
+    pub fn build(self) -> Result<Lookup> {
+        let http_addrs = self.addrs.into_iter().collect::<std::result::Result<Vec<_>, _>>()?;
+        if http_addrs.is_empty() {
+            return Err(Error::InvalidConfig { field: "lookupd", reason: "no nsqlookupd address".into() });
+        }
+        if let Some(ref retry) = self.retry {
+            retry.validate()?;
+        }
+        let root_ca = match (self.root_ca_pem, self.root_ca_file) {
+            (Some(pem), _) => Some((pem.as_bytes().to_vec(), "in-memory PEM".to_string())),
+            (None, Some(path)) => Some((std::fs::read(&path)?, path)),
+            (None, None) => None,
+        };
+        let client = Client::new(self.timeout, root_ca.as_ref().map(|(pem, source)| (&pem[..], &source[..])))?;
+        Ok(Lookup {
+            http_addrs,
+            client,
+            credentials: self.credentials,
+            retry: self.retry,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct LookupResponse {
     pub channels: Vec<String>,
     pub producers: Vec<Producer>,
 }
 
-#[derive(Debug, Deserialize)]
+/// What every lookupd knows of some topics, merged, see [`Lookup::cluster`].
+#[derive(Debug, Default)]
+pub struct ClusterView {
+    /// The nsqd having each topic, each once keyed by its TCP address, `broadcast_address:port`
+    pub topics: HashMap<String, BTreeMap<String, Producer>>,
+    /// The requests which failed, by the lookupd they went to
+    pub errors: Vec<(Url, Error)>,
+}
+
+impl ClusterView {
+    /// The nsqd having `topic`, in the order of their TCP address
+    pub fn producers(&self, topic: &str) -> impl Iterator<Item = &Producer> {
+        self.topics.get(topic).into_iter().flat_map(BTreeMap::values)
+    }
+
+    /// Whether every lookupd answered, so no producer can be missing
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Producer {
     pub broadcast_address: String,
     pub hostname: String,
@@ -28,7 +168,19 @@ pub struct Producer {
     pub version: String,
 }
 
-#[derive(Debug, Deserialize)]
+impl Producer {
+    /// `host:port` of the nsqd TCP protocol, with IPv6 literals in brackets
+    pub fn tcp_address(&self) -> String {
+        join_host_port(&self.broadcast_address, self.tcp_port)
+    }
+
+    /// `host:port` of the nsqd HTTP API, with IPv6 literals in brackets
+    pub fn http_address(&self) -> String {
+        join_host_port(&self.broadcast_address, self.http_port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct TopicsResponse {
     pub topics: Vec<String>,
 }
@@ -37,12 +189,34 @@ pub struct TopicsResponse {
 pub struct ChannelsResponse {
     pub channels: Vec<String>,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct NodesResponse {
     pub producers: Vec<Node>,
 }
 
-#[derive(Debug, Deserialize)]
+impl NodesResponse {
+    /// The nodes with `topic`, tombstoned or not
+    pub fn with_topic<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.producers.iter().filter(move |node| node.has_topic(topic))
+    }
+
+    /// The nodes with `topic` which aren't tombstoned for it, those consumers should connect to
+    pub fn serving<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.with_topic(topic).filter(move |node| !node.is_tombstoned(topic))
+    }
+
+    /// The nodes tombstoned for `topic`
+    pub fn tombstoned<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.with_topic(topic).filter(move |node| node.is_tombstoned(topic))
+    }
+
+    /// The nodes running nsqd `min_version` or newer, see `Node::version_at_least`
+    pub fn at_least<'a>(&'a self, min_version: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.producers.iter().filter(move |node| node.version_at_least(min_version))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Node {
     pub broadcast_address: String,
     pub hostname: String,
@@ -54,6 +228,88 @@ pub struct Node {
     pub topics: Vec<String>,
 }
 
+impl Node {
+    /// `host:port` of the nsqd TCP protocol, with IPv6 literals in brackets
+    pub fn tcp_address(&self) -> String {
+        join_host_port(&self.broadcast_address, self.tcp_port)
+    }
+
+    /// `host:port` of the nsqd HTTP API, with IPv6 literals in brackets
+    pub fn http_address(&self) -> String {
+        join_host_port(&self.broadcast_address, self.http_port)
+    }
+
+    pub fn has_topic(&self, topic: &str) -> bool {
+        self.topics.iter().any(|t| t == topic)
+    }
+
+    /// Whether this node is tombstoned for `topic`, `tombstones` going along `topics`
+    pub fn is_tombstoned(&self, topic: &str) -> bool {
+        self.topics.iter().zip(&self.tombstones).any(|(t, &tombstoned)| t == topic && tombstoned)
+    }
+
+    /// Whether this node runs nsqd `min_version` or newer, comparing `major.minor.patch` and
+    /// ignoring any pre-release suffix. False if either version doesn't parse.
+    pub fn version_at_least(&self, min_version: &str) -> bool {
+        match (parse_version(&self.version), parse_version(min_version)) {
+            (Some(version), Some(min)) => version >= min,
+            _ => false,
+        }
+    }
+}
+
+/// `major.minor.patch` of a version like `1.2.1` or `1.3.0-alpha`, missing parts being 0.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let release = version.split(['-', '+']).next().unwrap_or_default();
+    let mut parts = release.split('.').map(|part| part.parse::<u64>());
+    let mut next = || parts.next().transpose().map(Option::unwrap_or_default);
+    let version = (next().ok()?, next().ok()?, next().ok()?);
+    parts.next().is_none().then_some(version)
+}
+
+/// Whether nsqd accepts `name` as a topic: 1 to 64 ASCII letters, digits, `.`, `_` or `-`,
+/// optionally ending in `#ephemeral`.
+pub fn is_valid_topic_name(name: &str) -> bool {
+    is_valid_name(name)
+}
+
+/// Whether nsqd accepts `name` as a channel, by the same rules as topics.
+pub fn is_valid_channel_name(name: &str) -> bool {
+    is_valid_name(name)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let base = name.strip_suffix("#ephemeral").unwrap_or(name);
+    name.len() <= 64
+        && !base.is_empty()
+        && base.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+/// Reject a topic name nsqd would, before it goes over the network.
+pub(crate) fn check_topic_name(name: &str) -> Result<()> {
+    if !is_valid_topic_name(name) {
+        return Err(Error::InvalidCommand(format!("invalid topic name {:?}", name)));
+    }
+    Ok(())
+}
+
+/// Reject a channel name nsqd would, before it goes over the network.
+pub(crate) fn check_channel_name(name: &str) -> Result<()> {
+    if !is_valid_channel_name(name) {
+        return Err(Error::InvalidCommand(format!("invalid channel name {:?}", name)));
+    }
+    Ok(())
+}
+
+/// Like Go's `net.JoinHostPort`, which nsqd uses to parse these back.
+pub(crate) fn join_host_port(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct InfoResponse {
     pub version: String,
@@ -64,123 +320,691 @@ impl Lookup {
     /// Create a new lookup client from a given http address.
     ///
     /// The `url` must be a valid http address, which means it must start with `http://` or `https://`.
-    pub fn new<I: TryInto<Url>>(url: I) -> std::result::Result<Self, UrlParseError>
+    pub fn new<I: TryInto<Url>>(url: I) -> Result<Self>
         where UrlParseError: From<<I as TryInto<Url>>::Error>
     {
-        let client = reqwest::Client::builder()
-            .timeout(DEFAULT_TIMEOUT)
-            .build().expect("Build HTTP Client error");
-        let url = url.try_into()?;
-        Ok(Self {
-            http_addr: url,
-            client,
-        })
+        Lookup::builder().address(url).build()
+    }
+
+    /// Create a lookup client querying several nsqlookupd, so one being down doesn't blind
+    /// discovery.
+    ///
+    /// Queries go to all of them at once and their answers are merged, failing only if none
+    /// answers. Registry changes, such as `create_topic`, must be accepted by all of them.
+    pub fn with_addresses<I>(urls: impl IntoIterator<Item = I>) -> Result<Self>
+        where I: TryInto<Url>,
+              UrlParseError: From<<I as TryInto<Url>>::Error>
+    {
+        urls.into_iter().fold(Lookup::builder(), LookupBuilder::address).build()
+    }
+
+    /// Configure a lookup client for HTTPS with a private CA or authentication.
+    pub fn builder() -> LookupBuilder {
+        LookupBuilder::default()
+    }
+
+    /// Create a lookup client querying the targets of an SRV record over plain HTTP, e.g.
+    /// `_nsqlookupd._tcp.nsq.svc.cluster.local`, asking the system nameserver.
+    ///
+    /// The record is resolved once, fails if it has no target. See the [`dns`] module.
+    pub async fn from_srv(name: &str) -> Result<Self> {
+        let records = dns::resolve_srv(name, dns::system_nameserver()).await?;
+        let urls: Vec<_> = records.iter()
+            .map(|record| format!("http://{}", join_host_port(&record.target, record.port)))
+            .collect();
+        Lookup::with_addresses(urls.iter().map(String::as_str))
     }
 
-    /// Returns a list of producers for a topic
+    /// Returns a list of producers for a topic, each nsqd once however many lookupd know it
     pub async fn lookup(&self, topic: impl AsRef<str>) -> Result<LookupResponse> {
-        self.client.get(self.url("/lookup")?)
-            .query(&[("topic", topic.as_ref())])
-            .send().await?
-            .json().await
-            .map_err(From::from)
+        check_topic_name(topic.as_ref())?;
+        let responses: Vec<LookupResponse> = self.get_all("/lookup", &[("topic", topic.as_ref())]).await?;
+        let mut merged = LookupResponse { channels: Vec::new(), producers: Vec::new() };
+        for resp in responses {
+            merge(&mut merged.channels, resp.channels, |channel| channel.clone());
+            merge(&mut merged.producers, resp.producers, Producer::tcp_address);
+        }
+        Ok(merged)
+    }
+
+    /// Look each of `topics` up on every lookupd at once, merging what they know.
+    ///
+    /// Unlike `lookup`, a failing lookupd doesn't fail the whole: its error is kept in
+    /// `ClusterView::errors` and the others' answers are used. A lookupd not knowing a topic
+    /// isn't an error. Only an invalid topic name fails, before any request.
+    pub async fn cluster<I>(&self, topics: I) -> Result<ClusterView>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let topics: Vec<String> = topics.into_iter().map(|topic| topic.as_ref().to_string()).collect();
+        for topic in &topics {
+            check_topic_name(topic)?;
+        }
+        let requests = self.http_addrs.iter().flat_map(|addr| topics.iter().map(move |topic| async move {
+            (addr, topic, self.get::<LookupResponse>(addr, "/lookup", &[("topic", topic)]).await)
+        }));
+
+        let mut view = ClusterView::default();
+        for topic in &topics {
+            view.topics.entry(topic.clone()).or_default();
+        }
+        for (addr, topic, res) in future::join_all(requests).await {
+            match res {
+                Ok(resp) => {
+                    let producers = view.topics.entry(topic.clone()).or_default();
+                    for producer in resp.producers {
+                        producers.entry(producer.tcp_address()).or_insert(producer);
+                    }
+                }
+                Err(Error::LookupError(LookupError::TopicNotFound)) => {}
+                Err(e) => {
+                    warn!("lookupd {} failed to look {} up: {}", addr, topic, e);
+                    view.errors.push((addr.clone(), e));
+                }
+            }
+        }
+        Ok(view)
+    }
+
+    /// Look `topic` up every `config.lookupd_poll_interval`, for as long as the stream is
+    /// polled.
+    ///
+    /// The first lookup is delayed by a random part of `config.lookupd_poll_jitter` of the
+    /// interval, so a fleet of clients started together spreads its queries. Failed lookups
+    /// are yielded too and polling goes on.
+    pub fn poll(&self, topic: impl Into<String>, config: &Config) -> impl Stream<Item = Result<LookupResponse>> + '_ {
+        stream::unfold((poll_ticker(config), topic.into()), move |(mut ticker, topic)| async move {
+            ticker.tick().await;
+            let res = self.lookup(&topic).await;
+            Some((res, (ticker, topic)))
+        })
     }
 
     /// Returns a list of all known topics
     pub async fn topics(&self) -> Result<TopicsResponse> {
-        self.client.get(self.url("/topics")?)
-            .send().await?
-            .json().await
-            .map_err(From::from)
+        let mut topics = Vec::new();
+        for resp in self.get_all::<TopicsResponse>("/topics", &[]).await? {
+            merge(&mut topics, resp.topics, |topic| topic.clone());
+        }
+        Ok(TopicsResponse { topics })
     }
 
     /// Returns a list of all known channels of a topic
     pub async fn channels(&self, topic: impl AsRef<str>) -> Result<ChannelsResponse> {
-        self.client.get(self.url("/channels")?)
-            .query(&[("topic", topic.as_ref())])
-            .send().await?
-            .json().await
-            .map_err(From::from)
+        check_topic_name(topic.as_ref())?;
+        let mut channels = Vec::new();
+        for resp in self.get_all::<ChannelsResponse>("/channels", &[("topic", topic.as_ref())]).await? {
+            merge(&mut channels, resp.channels, |channel| channel.clone());
+        }
+        Ok(ChannelsResponse { channels })
     }
 
     /// Returns a list of all known `nsqd`
     pub async fn nodes(&self) -> Result<NodesResponse> {
-        self.client.get(self.url("/nodes")?)
-            .send().await?
-            .json().await
-            .map_err(From::from)
+        let mut producers = Vec::new();
+        for resp in self.get_all::<NodesResponse>("/nodes", &[]).await? {
+            merge(&mut producers, resp.producers, Node::tcp_address);
+        }
+        Ok(NodesResponse { producers })
+    }
+
+    /// The nsqd serving `topic`, those which have it and aren't tombstoned for it
+    pub async fn nodes_for_topic(&self, topic: impl AsRef<str>) -> Result<Vec<Node>> {
+        check_topic_name(topic.as_ref())?;
+        Ok(self.nodes().await?.serving(topic.as_ref()).cloned().collect())
     }
 
     /// Add a topic to nsqlookupd’s registry
     pub async fn create_topic(&self, topic: impl AsRef<str>) -> Result<()> {
-        let _ = self.client.post(self.url("/topic/create")?)
-            .query(&[("topic", topic.as_ref())])
-            .send().await?;
-        Ok(())
+        check_topic_name(topic.as_ref())?;
+        self.post_all("/topic/create", &[("topic", topic.as_ref())]).await
     }
 
-    /// Deletes an existing topic
+    /// Deletes an existing topic, `LookupError::TopicNotFound` if there is none
     pub async fn delete_topic(&self, topic: impl AsRef<str>) -> Result<()> {
-        let _ = self.client.post(self.url("/topic/delete")?)
-            .query(&[("topic", topic.as_ref())])
-            .send().await?;
-        Ok(())
+        check_topic_name(topic.as_ref())?;
+        self.post_all("/topic/delete", &[("topic", topic.as_ref())]).await
     }
 
     /// Add a channel to nsqlookupd’s registry
     pub async fn create_channel(&self, topic: impl AsRef<str>, channel: impl AsRef<str>) -> Result<()> {
-        let _ = self.client.post(self.url("/topic/create")?)
-            .query(&[
-                ("topic", topic.as_ref()),
-                ("channel", channel.as_ref())
-            ])
-            .send().await?;
-        Ok(())
+        check_topic_name(topic.as_ref())?;
+        check_channel_name(channel.as_ref())?;
+        self.post_all("/channel/create", &[
+            ("topic", topic.as_ref()),
+            ("channel", channel.as_ref())
+        ]).await
     }
 
-    /// Deletes an existing channel of an existing topic
+    /// Deletes an existing channel of an existing topic, `LookupError::ChannelNotFound` if
+    /// there is none
     pub async fn delete_channel(&self, topic: impl AsRef<str>, channel: impl AsRef<str>) -> Result<()> {
-        let _ = self.client.post(self.url("/topic/delete")?)
-            .query(&[
-                ("topic", topic.as_ref()),
-                ("channel", channel.as_ref())
-            ])
-            .send().await?;
-        Ok(())
+        check_topic_name(topic.as_ref())?;
+        check_channel_name(channel.as_ref())?;
+        self.post_all("/channel/delete", &[
+            ("topic", topic.as_ref()),
+            ("channel", channel.as_ref())
+        ]).await
     }
 
     /// Tombstones a specific producer of an existing topic.
     ///
     /// See [deletion and tombstones](https://nsq.io/components/nsqlookupd.html#deletion_tombstones).
     pub async fn tombstone(&self, topic: impl AsRef<str>, node: &Node) -> Result<()> {
-        let _ = self.client.post(self.url("/topic/tombstone")?)
-            .query(&[
-                ("topic", topic.as_ref()),
-                ("node", format!("{}:{}", node.broadcast_address, node.http_port).as_ref())
-            ])
-            .send().await?;
-        Ok(())
+        check_topic_name(topic.as_ref())?;
+        self.post_all("/topic/tombstone", &[
+            ("topic", topic.as_ref()),
+            ("node", node.http_address().as_ref())
+        ]).await
     }
 
-    /// Monitoring endpoint, should return OK
+    /// Monitoring endpoint, should return OK from every lookupd
     pub async fn ping(&self) -> Result<()> {
-        let resp = self.client.get(self.url("/ping")?).send().await?;
-        if resp.status().is_success() {
-            Ok(())
-        } else {
-            Err(Error::UnknownError("Unknown ping error from lookupd".into()))
+        for addr in &self.http_addrs {
+            check_status(self.send(Method::Get, addr.join("/ping")?, &[]).await?)?;
         }
+        Ok(())
     }
 
-    /// Returns version information
+    /// Returns version information, of the first lookupd answering
     pub async fn info(&self) -> Result<InfoResponse> {
-        self.client.get(self.url("/info")?)
-            .send().await?
-            .json().await
-            .map_err(From::from)
+        let mut last_err = None;
+        for addr in &self.http_addrs {
+            match self.get(addr, "/info", &[]).await {
+                Ok(info) => return Ok(info),
+                Err(e) => {
+                    warn!("lookupd {} failed: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("a lookup client has at least one address"))
+    }
+
+    /// Send a request, again after the retry policy's delays while it fails transiently.
+    async fn send(&self, method: Method, url: Url, query: &[(&str, &str)]) -> Result<http::Response> {
+        let mut attempt = 0;
+        loop {
+            let res = self.client.send(method, url.clone(), query, self.credentials.as_ref()).await;
+            let transient = match res {
+                Ok(ref resp) => resp.status >= 500,
+                Err(ref e) => http::is_transient(e),
+            };
+            attempt += 1;
+            match self.retry.as_ref().filter(|_| transient).and_then(|retry| retry.delay(attempt)) {
+                Some(delay) => {
+                    debug!("lookupd {} failed, retry {} in {:?}", url, attempt, delay);
+                    time::sleep(delay).await;
+                }
+                None => return res,
+            }
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, addr: &Url, endpoint: &str, query: &[(&str, &str)]) -> Result<T> {
+        check_status(self.send(Method::Get, addr.join(endpoint)?, query).await?)?.json()
+    }
+
+    /// GET `endpoint` from every lookupd at once, the answers of those which did, or the last
+    /// error if none did.
+    async fn get_all<T: DeserializeOwned>(&self, endpoint: &str, query: &[(&str, &str)]) -> Result<Vec<T>> {
+        let results = future::join_all(self.http_addrs.iter().map(|addr| self.get(addr, endpoint, query))).await;
+        let mut answers = Vec::new();
+        let mut last_err = None;
+        for (addr, res) in self.http_addrs.iter().zip(results) {
+            match res {
+                Ok(answer) => answers.push(answer),
+                Err(e) => {
+                    warn!("lookupd {} failed: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        match last_err {
+            Some(e) if answers.is_empty() => Err(e),
+            _ => Ok(answers),
+        }
+    }
+
+    /// POST to `endpoint` of every lookupd at once, failing if any of them fails or answers
+    /// with an error status.
+    async fn post_all(&self, endpoint: &str, query: &[(&str, &str)]) -> Result<()> {
+        let requests = self.http_addrs.iter().map(|addr| async move {
+            check_status(self.send(Method::Post, addr.join(endpoint)?, query).await?)?;
+            Ok(())
+        });
+        future::try_join_all(requests).await.map(|_| ())
+    }
+}
+
+/// Ticks every `config.lookupd_poll_interval`, the first tick delayed by the jitter.
+///
+/// Out of range settings of a config never validated are clamped rather than panicking.
+pub(crate) fn poll_ticker(config: &Config) -> Interval {
+    let interval = config.lookupd_poll_interval.max(MIN_LOOKUPD_POLL_INTERVAL);
+    let fraction = match config.lookupd_poll_jitter {
+        jitter if jitter.is_nan() => 0.0,
+        jitter => jitter.clamp(0.0, 1.0),
+    };
+    let jitter = interval.mul_f64(fraction * fastrand::f64());
+    let mut ticker = time::interval_at(Instant::now() + jitter, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// A [`Lookup`] remembering answers for a while, to spare nsqlookupd the queries of many
+/// clients and to ride out its brief outages.
+///
+/// `lookup`, `topics` and `nodes` are answered from the cache for `ttl` after they were
+/// fetched. Past that they are fetched again, and if nsqlookupd can't be reached, times out
+/// or answers with a 5xx status the previous answer is still served while it is less than
+/// `max_stale` old, 1 minute by default. Any other error, e.g. `TOPIC_NOT_FOUND`, is
+/// returned and drops the cached answer.
+pub struct CachedLookup {
+    lookup: Lookup,
+    ttl: Duration,
+    max_stale: Duration,
+    lookups: Mutex<HashMap<String, Cached<LookupResponse>>>,
+    topics: Mutex<HashMap<String, Cached<TopicsResponse>>>,
+    nodes: Mutex<HashMap<String, Cached<NodesResponse>>>,
+}
+
+struct Cached<T> {
+    value: T,
+    fetched: Instant,
+}
+
+/// How old a cached answer may get when nsqlookupd can't be reached, by default
+pub const DEFAULT_MAX_STALE: Duration = Duration::from_secs(60);
+
+impl CachedLookup {
+    pub fn new(lookup: Lookup, ttl: Duration) -> Self {
+        CachedLookup {
+            lookup,
+            ttl,
+            max_stale: DEFAULT_MAX_STALE,
+            lookups: Mutex::default(),
+            topics: Mutex::default(),
+            nodes: Mutex::default(),
+        }
+    }
+
+    /// Serve answers up to `max_stale` old while nsqlookupd fails, zero never to.
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    /// The client underneath, for the queries which aren't cached.
+    pub fn inner(&self) -> &Lookup {
+        &self.lookup
+    }
+
+    pub async fn lookup(&self, topic: impl AsRef<str>) -> Result<LookupResponse> {
+        let topic = topic.as_ref();
+        self.cached(&self.lookups, topic, self.lookup.lookup(topic)).await
+    }
+
+    pub async fn topics(&self) -> Result<TopicsResponse> {
+        self.cached(&self.topics, "", self.lookup.topics()).await
+    }
+
+    pub async fn nodes(&self) -> Result<NodesResponse> {
+        self.cached(&self.nodes, "", self.lookup.nodes()).await
+    }
+
+    /// Forget every cached answer, e.g. after changing the registry.
+    pub fn invalidate(&self) {
+        lock(&self.lookups).clear();
+        lock(&self.topics).clear();
+        lock(&self.nodes).clear();
+    }
+
+    async fn cached<T: Clone>(
+        &self,
+        cache: &Mutex<HashMap<String, Cached<T>>>,
+        key: &str,
+        fetch: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        if let Some(fresh) = lock(cache).get(key).filter(|c| c.fetched.elapsed() < self.ttl) {
+            return Ok(fresh.value.clone());
+        }
+        let res = fetch.await;
+        let mut cache = lock(cache);
+        match res {
+            Ok(value) => {
+                cache.insert(key.to_string(), Cached { value: value.clone(), fetched: Instant::now() });
+                Ok(value)
+            }
+            Err(e) if !is_outage(&e) => {
+                cache.remove(key);
+                Err(e)
+            }
+            Err(e) => match cache.get(key) {
+                Some(stale) if stale.fetched.elapsed() < self.max_stale => {
+                    warn!("lookupd failed, serving an answer {:?} old: {}", stale.fetched.elapsed(), e);
+                    Ok(stale.value.clone())
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+/// Whether `e` says nsqlookupd is down rather than that the answer changed.
+fn is_outage(e: &Error) -> bool {
+    match e {
+        Error::LookupError(LookupError::Other { status, .. }) => *status >= 500,
+        e => http::is_transient(e),
+    }
+}
+
+/// Turn an error status into the `LookupError` it stands for.
+fn check_status(resp: http::Response) -> Result<http::Response> {
+    if resp.is_success() {
+        return Ok(resp);
+    }
+    Err(LookupError::from_response(resp.status, &resp.body).into())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Append the `items` whose key isn't in `merged` yet.
+fn merge<T, K: PartialEq>(merged: &mut Vec<T>, items: Vec<T>, key: impl Fn(&T) -> K) {
+    for item in items {
+        let k = key(&item);
+        if !merged.iter().any(|known| key(known) == k) {
+            merged.push(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use crate::config::{BackoffConfig, Config};
+    use crate::error::{Error, LookupError};
+    use crate::http;
+    use crate::testing;
+    use super::{is_valid_channel_name, is_valid_topic_name, join_host_port, CachedLookup, Lookup, Node};
+
+    #[tokio::test]
+    async fn lookups_are_polled() {
+        let url = testing::serve_lookupd(vec![testing::lookup_response(&[])]).await;
+        let lookup = Lookup::new(url.as_str()).unwrap();
+        let config = Config::builder().lookupd_poll(Duration::from_millis(10), 0.0).build().unwrap();
+        let polled: Vec<_> = lookup.poll("test", &config).take(3).collect().await;
+        assert_eq!(polled.len(), 3);
+        assert!(polled.iter().all(|res| matches!(res, Ok(resp) if resp.producers.is_empty())));
+
+        // Not validated, would panic in `interval` and `mul_f64` unclamped
+        let config = Config { lookupd_poll_interval: Duration::ZERO, lookupd_poll_jitter: f64::NAN, ..Config::default() };
+        assert_eq!(lookup.poll("test", &config).take(2).count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn lookupd_answers_are_merged() {
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let first = testing::serve_lookupd(vec![testing::lookup_response(&["10.0.0.1", "10.0.0.2"])]).await;
+        let second = testing::serve_lookupd(vec![testing::lookup_response(&["10.0.0.2", "10.0.0.3"])]).await;
+
+        let lookup = Lookup::with_addresses([down.as_str(), first.as_str(), second.as_str()]).unwrap();
+        let resp = lookup.lookup("t").await.unwrap();
+        let addrs: Vec<_> = resp.producers.iter().map(|p| p.tcp_address()).collect();
+        assert_eq!(addrs, ["10.0.0.1:4150", "10.0.0.2:4150", "10.0.0.3:4150"]);
+        assert_eq!(resp.channels, ["c"]);
+
+        assert!(Lookup::with_addresses([down.as_str()]).unwrap().lookup("t").await.is_err());
+        assert!(matches!(Lookup::with_addresses(Vec::<&str>::new()), Err(Error::InvalidConfig { .. })));
+    }
+
+    #[tokio::test]
+    async fn credentials_are_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut headers = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                headers.push(request.lines().find(|line| line.starts_with("authorization:")).map(str::to_string));
+                socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nOK").await.unwrap();
+            }
+            headers
+        });
+
+        Lookup::builder().address(url.as_str()).basic_auth("user", Some("pass".into())).build().unwrap().ping().await.unwrap();
+        Lookup::builder().address(url.as_str()).bearer_auth("token").build().unwrap().ping().await.unwrap();
+        let headers = server.await.unwrap();
+        assert_eq!(headers[0].as_deref(), Some("authorization: basic dxnlcjpwyxnz"));
+        assert_eq!(headers[1].as_deref(), Some("authorization: bearer token"));
+
+        assert!(matches!(Lookup::builder().build(), Err(Error::InvalidConfig { field: "lookupd", .. })));
+        assert!(Lookup::builder().address(url.as_str()).root_ca_pem("not a certificate".to_string()).build().is_err());
+        assert!(Lookup::builder().address(url.as_str()).root_ca_file("/nonexistent/ca.pem").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for status in ["503 Service Unavailable", "503 Service Unavailable", "200 OK"] {
+                let (mut socket, _) = listener.accept().await?;
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await?;
+                let resp = format!("HTTP/1.1 {}\r\ncontent-length: 2\r\nconnection: close\r\n\r\nOK", status);
+                socket.write_all(resp.as_bytes()).await?;
+            }
+            // Accepted but never answered
+            let (_socket, _) = listener.accept().await?;
+            std::future::pending::<()>().await;
+            Ok::<_, std::io::Error>(())
+        });
+
+        let retry = BackoffConfig { initial: Duration::from_millis(1), max_attempts: Some(2), ..Default::default() };
+        let lookup = Lookup::builder()
+            .address(url.as_str())
+            .timeout(Duration::from_millis(100))
+            .retry(BackoffConfig { max_attempts: Some(0), ..retry })
+            .build()
+            .unwrap();
+        assert!(lookup.ping().await.is_err());
+
+        let lookup = Lookup::builder().address(url.as_str()).retry(retry).build().unwrap();
+        lookup.ping().await.unwrap();
+
+        let lookup = Lookup::builder().address(url.as_str()).timeout(Duration::from_millis(100)).build().unwrap();
+        match lookup.ping().await {
+            Err(e) => assert!(http::is_timeout(&e), "{:?}", e),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(Lookup::builder().address("not a url").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn mutations_hit_their_endpoints_and_check_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let answers = [
+                "200 OK\r\ncontent-length: 2\r\n\r\nOK",
+                "200 OK\r\ncontent-length: 2\r\n\r\nOK",
+                "404 Not Found\r\ncontent-length: 31\r\n\r\n{\"message\":\"CHANNEL_NOT_FOUND\"}",
+                "404 Not Found\r\ncontent-length: 29\r\n\r\n{\"message\":\"TOPIC_NOT_FOUND\"}",
+                "500 Internal Server Error\r\ncontent-length: 14\r\n\r\nINTERNAL_ERROR",
+            ];
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or_default().to_string());
+                let resp = format!("HTTP/1.1 {}", answer.replacen("\r\n", "\r\nconnection: close\r\n", 1));
+                socket.write_all(resp.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let lookup = Lookup::new(url.as_str()).unwrap();
+        lookup.create_topic("events").await.unwrap();
+        lookup.create_channel("events", "archive").await.unwrap();
+        assert!(matches!(
+            lookup.delete_channel("events", "archive").await,
+            Err(Error::LookupError(LookupError::ChannelNotFound))
+        ));
+        assert!(matches!(lookup.delete_topic("events").await, Err(Error::LookupError(LookupError::TopicNotFound))));
+        match lookup.create_topic("events").await {
+            Err(Error::LookupError(LookupError::Other { status, message })) => {
+                assert_eq!((status, message.as_str()), (500, "INTERNAL_ERROR"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert_eq!(server.await.unwrap(), [
+            "POST /topic/create?topic=events HTTP/1.1",
+            "POST /channel/create?topic=events&channel=archive HTTP/1.1",
+            "POST /channel/delete?topic=events&channel=archive HTTP/1.1",
+            "POST /topic/delete?topic=events HTTP/1.1",
+            "POST /topic/create?topic=events HTTP/1.1",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn nodes_are_filtered() {
+        let node = |addr: &str, version: &str, topics: &[&str], tombstones: &[bool]| json!({
+            "broadcast_address": addr, "hostname": addr, "remote_address": format!("{}:50000", addr),
+            "tcp_port": 4150, "http_port": 4151, "version": version,
+            "topics": topics, "tombstones": tombstones,
+        });
+        let body = json!({"producers": [
+            node("10.0.0.1", "1.2.1", &["events", "audit"], &[false, true]),
+            node("10.0.0.2", "1.3.0-alpha", &["events"], &[true]),
+            node("10.0.0.3", "0.3.8", &["audit"], &[false]),
+            node("10.0.0.4", "unknown", &[], &[]),
+        ]}).to_string();
+        let url = testing::serve_lookupd(vec![body]).await;
+        let lookup = Lookup::new(url.as_str()).unwrap();
+        let nodes = lookup.nodes().await.unwrap();
+
+        let addrs = |nodes: Vec<&Node>| nodes.iter().map(|node| node.broadcast_address.clone()).collect::<Vec<_>>();
+        assert_eq!(addrs(nodes.with_topic("events").collect()), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(addrs(nodes.serving("events").collect()), ["10.0.0.1"]);
+        assert_eq!(addrs(nodes.serving("audit").collect()), ["10.0.0.3"]);
+        assert_eq!(addrs(nodes.tombstoned("audit").collect()), ["10.0.0.1"]);
+        assert_eq!(addrs(nodes.at_least("1.2").collect()), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(addrs(nodes.at_least("1.3.0").collect()), ["10.0.0.2"]);
+        assert!(nodes.at_least("not a version").next().is_none());
+
+        let serving = lookup.nodes_for_topic("events").await.unwrap();
+        assert_eq!(addrs(serving.iter().collect()), ["10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn cluster_view_merges_lookupds_and_reports_failures() {
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let first = testing::serve_lookupd(vec![testing::lookup_response(&["10.0.0.1", "10.0.0.2"])]).await;
+        let second = testing::serve_lookupd(vec![testing::lookup_response(&["10.0.0.2", "10.0.0.3"])]).await;
+        let unaware = testing::serve_lookupd_with(vec![(404, r#"{"message":"TOPIC_NOT_FOUND"}"#.to_string())]).await;
+
+        let lookup = Lookup::with_addresses([&down, &first, &second, &unaware].map(String::as_str)).unwrap();
+        let view = lookup.cluster(["events", "audit"]).await.unwrap();
+        for topic in ["events", "audit"] {
+            let addrs: Vec<_> = view.producers(topic).map(|p| p.tcp_address()).collect();
+            assert_eq!(addrs, ["10.0.0.1:4150", "10.0.0.2:4150", "10.0.0.3:4150"]);
+        }
+        assert_eq!(view.producers("other").count(), 0);
+        assert!(!view.is_complete());
+        assert_eq!(view.errors.len(), 2);
+        assert!(view.errors.iter().all(|(url, _)| url.as_str().trim_end_matches('/') == down));
+
+        assert!(matches!(lookup.cluster(["bad topic"]).await, Err(Error::InvalidCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_names_are_rejected() {
+        for name in ["events", "events.v2_raw-1", "events#ephemeral", &"a".repeat(64)] {
+            assert!(is_valid_topic_name(name) && is_valid_channel_name(name), "{}", name);
+        }
+        for name in ["", "#ephemeral", "bad topic", "bad/topic", "events#other", "évents", &"a".repeat(65)] {
+            assert!(!is_valid_topic_name(name) && !is_valid_channel_name(name), "{}", name);
+        }
+
+        // Nothing listens there, a request would fail differently
+        let lookup = Lookup::new("http://127.0.0.1:1").unwrap();
+        assert!(matches!(lookup.lookup("bad topic").await, Err(Error::InvalidCommand(_))));
+        assert!(matches!(lookup.create_channel("events", "").await, Err(Error::InvalidCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn cached_answers_are_served_while_lookupd_fails() {
+        let url = testing::serve_lookupd_with(vec![
+            (200, testing::lookup_response(&["10.0.0.1"])),
+            (200, testing::lookup_response(&["10.0.0.2"])),
+            (503, "SERVICE_UNAVAILABLE".to_string()),
+            (503, "SERVICE_UNAVAILABLE".to_string()),
+            (404, r#"{"message":"TOPIC_NOT_FOUND"}"#.to_string()),
+            (503, "SERVICE_UNAVAILABLE".to_string()),
+        ]).await;
+        let lookup = CachedLookup::new(Lookup::new(url.as_str()).unwrap(), Duration::from_millis(50));
+        let producer = |resp: super::LookupResponse| resp.producers[0].broadcast_address.clone();
+
+        assert_eq!(producer(lookup.lookup("t").await.unwrap()), "10.0.0.1");
+        assert_eq!(producer(lookup.lookup("t").await.unwrap()), "10.0.0.1");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(producer(lookup.lookup("t").await.unwrap()), "10.0.0.2");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(producer(lookup.lookup("t").await.unwrap()), "10.0.0.2");
+
+        let lookup = lookup.with_max_stale(Duration::ZERO);
+        assert!(lookup.lookup("t").await.is_err());
+
+        // Not an outage, the topic is gone: the cached answer goes with it
+        let lookup = lookup.with_max_stale(Duration::from_secs(60));
+        assert!(matches!(lookup.lookup("t").await, Err(Error::LookupError(LookupError::TopicNotFound))));
+        assert!(lookup.lookup("t").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_typed() {
+        let url = testing::serve_lookupd_with(vec![
+            (404, r#"{"message":"TOPIC_NOT_FOUND"}"#.to_string()),
+            (400, r#"{"status_code":400,"status_txt":"MISSING_ARG_TOPIC","data":null}"#.to_string()),
+            (500, "INTERNAL_ERROR".to_string()),
+        ]).await;
+        let lookup = Lookup::new(url.as_str()).unwrap();
+        for expected in [
+            LookupError::TopicNotFound,
+            LookupError::MissingArg("TOPIC".into()),
+            LookupError::Other { status: 500, message: "INTERNAL_ERROR".into() },
+        ] {
+            match lookup.lookup("t").await {
+                Err(Error::LookupError(e)) => assert_eq!(e, expected),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
     }
 
-    fn url(&self, endpoint: &str) -> std::result::Result<Url, UrlParseError> {
-        self.http_addr.join(endpoint)
+    #[test]
+    fn ipv6_literals_are_bracketed() {
+        assert_eq!(join_host_port("nsqd.local", 4150), "nsqd.local:4150");
+        assert_eq!(join_host_port("10.0.0.1", 4150), "10.0.0.1:4150");
+        assert_eq!(join_host_port("fe80::1", 4150), "[fe80::1]:4150");
     }
 }
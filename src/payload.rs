@@ -0,0 +1,120 @@
+//! Message body formats, so producers and consumers of a topic agree on one encoding.
+//!
+//! ```no_run
+//! # async fn run(mut producer: nsq_in_rust::Producer, msg: nsq_in_rust::protocol::NsqMsg) -> Result<(), nsq_in_rust::Error> {
+//! use nsq_in_rust::payload::Json;
+//!
+//! producer.publish_as("scores", &Json, &vec![3u32, 1, 2]).await?;
+//! let scores: Vec<u32> = msg.decode_as(&Json)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! MessagePack and Protobuf are behind the `msgpack` and `protobuf` features.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::command::MessageBody;
+use crate::error::Error;
+
+/// Turns values of `T` into message bodies and back.
+pub trait PayloadCodec<T> {
+    fn encode(&self, value: &T) -> Result<MessageBody, Error>;
+
+    fn decode(&self, body: &[u8]) -> Result<T, Error>;
+}
+
+/// JSON through serde.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> PayloadCodec<T> for Json {
+    fn encode(&self, value: &T) -> Result<MessageBody, Error> {
+        serde_json::to_vec(value).map_err(|e| Error::Payload(e.into()))
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+/// MessagePack through serde, structs encoded as maps so fields can be added and reordered.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl<T: Serialize + DeserializeOwned> PayloadCodec<T> for MessagePack {
+    fn encode(&self, value: &T) -> Result<MessageBody, Error> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::Payload(e.into()))
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<T, Error> {
+        rmp_serde::from_slice(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+/// Protobuf messages generated by prost.
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Protobuf;
+
+#[cfg(feature = "protobuf")]
+impl<T: prost::Message + Default> PayloadCodec<T> for Protobuf {
+    fn encode(&self, value: &T) -> Result<MessageBody, Error> {
+        Ok(value.encode_to_vec())
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<T, Error> {
+        T::decode(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::{Json, PayloadCodec};
+    use crate::error::Error;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        id: u64,
+        kind: String,
+    }
+
+    fn round_trip<C: PayloadCodec<Event>>(codec: &C) {
+        let event = Event { id: 7, kind: "created".into() };
+        let body = codec.encode(&event).unwrap();
+        assert_eq!(codec.decode(&body).unwrap(), event);
+        assert!(matches!(codec.decode(&body[..body.len() - 1]), Err(Error::Payload(_))));
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trip(&Json);
+        assert_eq!(Json.encode(&Event { id: 1, kind: "a".into() }).unwrap(), br#"{"id":1,"kind":"a"}"#);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        round_trip(&super::MessagePack);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trips() {
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Ping {
+            #[prost(uint64, tag = "1")]
+            seq: u64,
+        }
+
+        let body = super::Protobuf.encode(&Ping { seq: 300 }).unwrap();
+        assert_eq!(body, [0x08, 0xac, 0x02]);
+        let ping: Ping = super::Protobuf.decode(&body).unwrap();
+        assert_eq!(ping, Ping { seq: 300 });
+    }
+}
@@ -0,0 +1,321 @@
+//! A pool of connections to a single nsqd, or to the nodes a [`Discovery`] source finds.
+//!
+//! A connection does one thing at a time, so publishers that want more throughput than one
+//! connection gives can check several out of a [`ConnectionPool`] and use them in parallel.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::{Duration, Instant};
+
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::conn::{Connection, ToAddrs, AT_CAPACITY_DELAY};
+use crate::discovery::Discovery;
+use crate::error::{Error, NsqError};
+
+/// The longest the pool backs off from a node at capacity.
+const MAX_AT_CAPACITY_DELAY: Duration = Duration::from_secs(300);
+
+type Connect = Box<dyn Fn() -> BoxFuture<'static, Result<Connection, Error>> + Send + Sync>;
+
+/// Pool of up to `size` connections to one nsqd, see the module docs.
+///
+/// Clones share the same pool.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connect: Connect,
+    idle: Mutex<Vec<Connection>>,
+    // One permit per connection, checked out or about to be
+    permits: Arc<Semaphore>,
+    capacity: Mutex<Capacity>,
+    health_check_interval: Option<Duration>,
+    // Started by the first checkout, so a pool can be created outside of a runtime
+    health_check: Once,
+}
+
+/// Backing off from the node after it refused connections for being at capacity.
+#[derive(Default)]
+struct Capacity {
+    // Refusals in a row, each doubling the delay
+    strikes: u32,
+    // Until when, and the refusal to fail checkouts with meanwhile
+    until: Option<(Instant, NsqError)>,
+}
+
+/// A connection checked out of a [`ConnectionPool`], returned to it on drop.
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectionPool {
+    /// Create a pool of up to `size` connections to `addr`, connecting lazily.
+    ///
+    /// Idle connections are checked every half heartbeat interval, from the first checkout on:
+    /// this answers nsqd's heartbeats, which would otherwise go unanswered until checkout and
+    /// get the connection closed, and drops those which died. With heartbeats disabled they
+    /// are only checked on checkout.
+    pub fn new<A>(addr: A, config: Config, size: usize) -> Self
+    where
+        A: ToAddrs + Clone + Send + Sync + 'static,
+    {
+        let health_check_interval = config.effective_heartbeat_interval().map(|interval| interval / 2);
+        Self::with_connect(Box::new(move || {
+            let addr = addr.clone();
+            let config = config.clone();
+            async move { Connection::connect(addr, &config).await }.boxed()
+        }), health_check_interval, size)
+    }
+
+    /// Create a pool of up to `size` connections to the nodes `discovery` finds having
+    /// `topic`, connecting lazily.
+    ///
+    /// Each new connection asks `discovery` again and goes to one of the nodes at random,
+    /// trying the others if that fails, so connections spread over the nodes and follow them
+    /// as they come and go. Backing off from a node at capacity holds for the whole pool.
+    pub fn discovered<D>(discovery: D, topic: impl Into<String>, config: Config, size: usize) -> Self
+    where
+        D: Discovery + 'static,
+    {
+        let health_check_interval = config.effective_heartbeat_interval().map(|interval| interval / 2);
+        let discovery = Arc::new(discovery);
+        let topic = topic.into();
+        Self::with_connect(Box::new(move || {
+            let (discovery, topic, config) = (discovery.clone(), topic.clone(), config.clone());
+            async move { connect_discovered(&*discovery, &topic, &config).await }.boxed()
+        }), health_check_interval, size)
+    }
+
+    fn with_connect(connect: Connect, health_check_interval: Option<Duration>, size: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                connect,
+                idle: Mutex::new(Vec::new()),
+                permits: Arc::new(Semaphore::new(size.max(1))),
+                capacity: Mutex::new(Capacity::default()),
+                health_check_interval,
+                health_check: Once::new(),
+            }),
+        }
+    }
+
+    /// Check a connection out, waiting for one to be returned if all are in use.
+    ///
+    /// After nsqd refused a new connection for being at capacity, see
+    /// [`Error::is_at_capacity`], idle connections are still handed out but none is opened:
+    /// checkouts fail right away with that refusal for `AT_CAPACITY_DELAY`, doubling with each
+    /// refusal in a row up to 5 minutes.
+    pub async fn get(&self) -> Result<PooledConnection, Error> {
+        if let Some(interval) = self.inner.health_check_interval {
+            self.inner.health_check.call_once(|| {
+                tokio::spawn(health_check(Arc::downgrade(&self.inner), interval));
+            });
+        }
+        let permit = self.inner.permits.clone().acquire_owned().await
+            .expect("pool semaphore is never closed");
+        loop {
+            let idle = self.inner.idle.lock().unwrap().pop();
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => break,
+            };
+            if is_healthy(&mut conn) {
+                return Ok(PooledConnection { conn: Some(conn), pool: self.inner.clone(), _permit: permit });
+            }
+        }
+        if let Some(refusal) = self.inner.at_capacity() {
+            return Err(Error::NsqError(refusal));
+        }
+        let conn = match (self.inner.connect)().await {
+            Ok(conn) => conn,
+            Err(Error::NsqError(e)) if e.is_at_capacity() => {
+                let mut capacity = self.inner.capacity.lock().unwrap();
+                capacity.strikes += 1;
+                let delay = AT_CAPACITY_DELAY.saturating_mul(1 << (capacity.strikes - 1).min(16)).min(MAX_AT_CAPACITY_DELAY);
+                warn!("nsqd at capacity, not connecting for {:?}: {}", delay, e);
+                capacity.until = Some((Instant::now() + delay, e.clone()));
+                return Err(Error::NsqError(e));
+            }
+            Err(e) => return Err(e),
+        };
+        *self.inner.capacity.lock().unwrap() = Capacity::default();
+        Ok(PooledConnection { conn: Some(conn), pool: self.inner.clone(), _permit: permit })
+    }
+
+    /// Number of idle connections.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Whether the pool is backing off from the node for being at capacity, see `get`.
+    pub fn is_at_capacity(&self) -> bool {
+        self.inner.at_capacity().is_some()
+    }
+}
+
+impl Inner {
+    /// The refusal to fail checkouts with, while backing off from a node at capacity.
+    fn at_capacity(&self) -> Option<NsqError> {
+        match self.capacity.lock().unwrap().until {
+            Some((until, ref refusal)) if Instant::now() < until => Some(refusal.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl PooledConnection {
+    /// Close the connection instead of returning it to the pool, e.g. after an error left
+    /// it in an unknown state.
+    pub fn discard(mut self) {
+        self.conn = None;
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// Connect to one of the nodes `discovery` finds having `topic` at random, trying the others
+/// if that fails.
+async fn connect_discovered(discovery: &dyn Discovery, topic: &str, config: &Config) -> Result<Connection, Error> {
+    let mut nodes = discovery.discover(topic).await?;
+    fastrand::shuffle(&mut nodes);
+    let mut last_err = None;
+    for node in nodes {
+        match Connection::connect((node.host.as_str(), node.tcp_port), config).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                debug!("failed to connect to {}: {}", node.tcp_address(), e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no nsqd has topic {}", topic)).into()
+    }))
+}
+
+/// Poll an idle connection without waiting, which also answers pending heartbeats.
+///
+/// Anything other than silence means it can't be handed out: it was closed, failed, or has
+/// a response left over from a request whose caller went away.
+fn is_healthy(conn: &mut Connection) -> bool {
+    match conn.next().now_or_never() {
+        None => true,
+        Some(res) => {
+            debug!("dropping idle connection to {}: {:?}", conn.peer_addr(), res);
+            false
+        }
+    }
+}
+
+async fn health_check(pool: Weak<Inner>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        pool.idle.lock().unwrap().retain_mut(is_healthy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::discovery::{NsqdAddr, StaticDiscovery};
+    use crate::testing;
+    use super::ConnectionPool;
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        // The mock accepts a single connection, a second one would fail
+        let addr = testing::serve(|mut conn| async move {
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let pool = ConnectionPool::new(addr, Config::default(), 1);
+        let first = pool.get().await.unwrap().peer_addr();
+        assert_eq!(pool.idle(), 1);
+        let second = pool.get().await.unwrap().peer_addr();
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn nodes_at_capacity_are_backed_off() {
+        // The mock accepts a single connection, a second attempt would fail differently
+        let addr = testing::serve_raw(|mut conn| async move {
+            conn.read_magic().await?;
+            conn.read_command().await?;
+            conn.write_error("E_TOO_MANY_CONNECTIONS max 100 connections reached").await
+        }).await;
+
+        let pool = ConnectionPool::new(addr, Config::default(), 1);
+        assert!(!pool.is_at_capacity());
+        assert!(pool.get().await.err().unwrap().is_at_capacity());
+        assert!(pool.is_at_capacity());
+        assert!(pool.get().await.err().unwrap().is_at_capacity());
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_not_handed_out() {
+        let addr = testing::serve(|_conn| async move { Ok(()) }).await;
+
+        let pool = ConnectionPool::new(addr, Config::default(), 1);
+        drop(pool.get().await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        // The idle connection was closed by nsqd and reconnecting is refused
+        assert!(pool.get().await.is_err());
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn discovered_nodes_are_connected_to() {
+        let addr = testing::serve(|mut conn| async move {
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let discovery = StaticDiscovery::new([NsqdAddr::new("127.0.0.1", addr.port())]);
+        let pool = ConnectionPool::discovered(discovery, "events", Config::default(), 1);
+        assert_eq!(pool.get().await.unwrap().peer_addr(), addr);
+
+        let pool = ConnectionPool::discovered(StaticDiscovery::new([]), "events", Config::default(), 1);
+        assert!(pool.get().await.is_err());
+    }
+
+    #[test]
+    fn pools_are_created_outside_of_a_runtime() {
+        let pool = ConnectionPool::new("127.0.0.1:4150", Config::default(), 1);
+        assert_eq!(pool.idle(), 0);
+    }
+}
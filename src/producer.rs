@@ -2,43 +2,128 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::net::SocketAddr;
+use std::time::Duration;
 use futures::{
     ready,
     prelude::*,
-    channel::oneshot::Receiver,
+    channel::{mpsc, oneshot::Receiver},
 };
-use tracing::debug;
+use tracing::{debug, Instrument};
+use url::Url;
 
-use crate::config::Config;
-use crate::error::Error;
+use crate::config::ProducerConfig;
+use crate::error::{Error, NsqError};
 use crate::command::{Command, MessageBody};
-use crate::conn::{Connection, Response, connection::ConnSink};
+use crate::http::{Client, Method};
+use crate::payload::PayloadCodec;
+use crate::stats::NsqdStats;
+use crate::conn::{BaseIo, Connection, Heartbeat, Response, ToAddrs, connection::{ConnSink, CloseReport}};
+use crate::discovery::Discovery;
 
 pub struct Producer {
     conn: Connection,
+
+    // Number of responses the server still owes us for commands whose futures were
+    // dropped before the response was read. They must be consumed before the next
+    // command's response, otherwise a stale `OK` would be taken as its acknowledgement.
+    pending_responses: usize,
+
+    // HTTP port of the connected nsqd, used by `ensure_topic`
+    http_port: u16,
 }
 
+/// Default port of the nsqd HTTP API
+pub const DEFAULT_HTTP_PORT: u16 = 4151;
+
 pub struct SinkProducer {
     topic: String,
     sink: ConnSink,
     state: Option<Receiver<Error>>,
+    seq: Seq,
+}
+
+/// Sequence number of a message published through a [`SinkProducer`].
+pub type Seq = u64;
+
+/// Stream of publish receipts, see [`Producer::into_sink_with_receipts`].
+pub struct Receipts {
+    rx: mpsc::UnboundedReceiver<(Seq, Result<(), NsqError>)>,
+}
+
+impl Stream for Receipts {
+    type Item = (Seq, Result<(), NsqError>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
 }
 
 
 impl Producer {
-    pub async fn connect<A: Into<SocketAddr>>(addr: A, config: &Config) -> Result<Self, Error> {
-        let conn = Connection::connect(addr, config).await?;
-        Ok(Self { conn })
+    /// Connect with a `&Config`, or a `ProducerConfig` for the producer's own settings.
+    pub async fn connect<A: ToAddrs>(addr: A, config: impl Into<ProducerConfig>) -> Result<Self, Error> {
+        let config = config.into();
+        let conn = Connection::connect(addr, &config.shared).await?;
+        Ok(Self::from_connection(conn).with_http_port(config.http_port))
     }
 
     pub(crate) fn from_connection(conn: Connection) -> Self {
-        Self { conn }
+        Self { conn, pending_responses: 0, http_port: DEFAULT_HTTP_PORT }
+    }
+
+    /// Set the HTTP port of the connected nsqd, if it is not the default 4151.
+    pub fn with_http_port(mut self, http_port: u16) -> Self {
+        self.http_port = http_port;
+        self
+    }
+
+    /// Make sure `topic` exists on the connected nsqd.
+    ///
+    /// nsqd creates topics lazily on the first publish, so consumers subscribing before that
+    /// may see `E_BAD_TOPIC`. Calling this first creates the topic through the HTTP API of
+    /// the node this producer is connected to. It's a no-op if the topic already exists.
+    pub async fn ensure_topic(&self, topic: impl AsRef<str>) -> Result<(), Error> {
+        let http_addr = SocketAddr::new(self.conn.peer_addr().ip(), self.http_port);
+        let url = Url::parse(&format!("http://{}/topic/create", http_addr))?;
+        let client = Client::new(crate::lookup::DEFAULT_TIMEOUT, None)?;
+        let resp = client.send(Method::Post, url, &[("topic", topic.as_ref())], None).await?;
+        if resp.is_success() {
+            Ok(())
+        } else {
+            Err(Error::UnknownError(format!("create topic {} failed: {}", topic.as_ref(), resp.status)))
+        }
+    }
+
+    /// Stats of the connected nsqd, through its HTTP API, e.g. to check a topic's backlog.
+    pub async fn stats(&self) -> Result<NsqdStats, Error> {
+        NsqdStats::fetch(SocketAddr::new(self.conn.peer_addr().ip(), self.http_port).to_string()).await
     }
 
     /// Publish a message to a topic
+    ///
+    /// This method is cancellation safe: if the returned future is dropped after the
+    /// command has been written, its response is consumed by the next call instead of
+    /// being mistaken for that call's acknowledgement.
     pub async fn publish(&mut self, topic: impl Into<String>, msg: impl Into<MessageBody>) -> Result<(), Error> {
-        self.conn.send(Command::Pub(topic.into(), msg.into())).await?;
-        self.response().await
+        self.request(Command::Pub(topic.into(), msg.into())).await
+    }
+
+    /// Publish `value` to a topic, encoded with `codec`.
+    pub async fn publish_as<T, C: PayloadCodec<T>>(&mut self, topic: impl Into<String>, codec: &C, value: &T) -> Result<(), Error> {
+        let body = codec.encode(value)?;
+        self.publish(topic, body).await
+    }
+
+    /// Publish a message to a topic, giving up if it is not acknowledged within `timeout`.
+    ///
+    /// Returns an `io::ErrorKind::TimedOut` error when the deadline elapses. The message
+    /// may still have been published in that case, and the late response will be discarded
+    /// by the next command on this producer.
+    pub async fn publish_timeout(&mut self, topic: impl Into<String>, msg: impl Into<MessageBody>, timeout: Duration) -> Result<(), Error> {
+        match tokio::time::timeout(timeout, self.publish(topic, msg)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "publish timed out").into()),
+        }
     }
 
     /// Publish multiple messages to a topic (atomically):
@@ -46,22 +131,35 @@ impl Producer {
     /// NOTE: available in nsqd v0.2.16+
     pub async fn multi_publish(&mut self, topic: impl Into<String>, msgs: Vec<impl Into<MessageBody>>) -> Result<(), Error> {
         let msgs = msgs.into_iter().map(|s| s.into()).collect();
-        self.conn.send(Command::Mpub(topic.into(), msgs)).await?;
-        self.response().await
+        self.request(Command::Mpub(topic.into(), msgs)).await
+    }
+
+    /// Like `multi_publish`, encoding the messages as they are iterated instead of
+    /// collecting them first, so a large batch is only held once, encoded.
+    pub async fn multi_publish_iter<I, B>(&mut self, topic: impl AsRef<str>, msgs: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        let span = self.conn.span().clone();
+        async {
+            self.conn.check_publish(topic.as_ref())?;
+            self.send_request(|transport| transport.start_send_mpub(topic.as_ref(), msgs)).await
+        }.instrument(span).await
     }
 
     /// Publish a deferred message to a topic:
     ///
     /// NOTE: available in nsqd v0.3.6+
     pub async fn deferred_publish(&mut self, topic: impl Into<String>, defer: u64, msg: impl Into<MessageBody>) -> Result<(), Error> {
-        self.conn.send(Command::Dpub(topic.into(), defer, msg.into())).await?;
-        self.response().await
+        self.request(Command::Dpub(topic.into(), defer, msg.into())).await
     }
 
     /// Ping causes the Producer to connect to it's configured nsqd (if not already
     /// connected) and send a `Nop` command, returning any error that might occur.
     ///
-    /// TODO reconnect
+    /// The producer doesn't reconnect, see [`reconnecting`](crate::conn::reconnecting) for
+    /// a connection which does.
     ///
     /// This method can be used to verify that a newly-created Producer instance is
     /// configured correctly, rather than relying on the lazy "connect on Publish"
@@ -71,52 +169,153 @@ impl Producer {
         Ok(())
     }
 
+    /// Close the producer, aborting the connection if nsqd doesn't acknowledge within `timeout`.
+    ///
+    /// The report's `unacknowledged_publishes` counts publishes whose futures were dropped
+    /// and whose response never arrived.
+    pub async fn close_timeout(self, timeout: Duration) -> Result<CloseReport, Error> {
+        self.conn.shutdown(timeout, self.pending_responses).await
+    }
+
+    /// Send a command which expects a response and wait for it.
+    async fn request(&mut self, cmd: Command) -> Result<(), Error> {
+        let span = self.conn.span().clone();
+        self.request_in_span(cmd).instrument(span).await
+    }
+
+    async fn request_in_span(&mut self, cmd: Command) -> Result<(), Error> {
+        self.conn.check_command(&cmd)?;
+        self.send_request(|transport| Pin::new(transport).start_send(cmd)).await
+    }
+
+    /// Write a command with `start_send` once the transport is ready and wait for its response.
+    async fn send_request<F>(&mut self, start_send: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Heartbeat<BaseIo>) -> Result<(), Error>,
+    {
+        self.drain_pending().await?;
+
+        // Once the command is in the write buffer it will reach the server, even if we
+        // are dropped while flushing, so account for its response right away.
+        future::poll_fn(|cx| Pin::new(&mut self.conn.transport).poll_ready(cx)).await?;
+        start_send(&mut self.conn.transport)?;
+        self.pending_responses += 1;
+
+        future::poll_fn(|cx| Pin::new(&mut self.conn.transport).poll_flush(cx)).await?;
+        self.response().await
+    }
+
+    /// Consume responses left over by cancelled requests.
+    async fn drain_pending(&mut self) -> Result<(), Error> {
+        while self.pending_responses > 0 {
+            match self.response().await {
+                Ok(()) => debug!("discard stale response"),
+                Err(Error::NsqError(e)) if !e.is_fatal() => {
+                    debug!("discard stale error response: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
     async fn response(&mut self) -> Result<(), Error> {
-        match self.conn.receive().await? {
+        let response = self.conn.receive().await;
+        self.pending_responses = self.pending_responses.saturating_sub(1);
+        match response? {
             Response::Ok => Ok(()),
             Response::Err(e) => Err(e.into()),
-            Response::Msg(_) => unreachable!(),
+            Response::Msg(_) | Response::Invalid { .. } => unreachable!(),
+            Response::CloseWait => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
     }
 
     pub fn into_sink(self, topic: impl Into<String>) -> (SinkProducer, tokio::task::JoinHandle<()>) {
+        self.spawn_sink(topic.into(), None)
+    }
+
+    /// Like `into_sink`, but also returns a stream of publish receipts.
+    ///
+    /// Every message sent through the sink is numbered, starting from 0 (see
+    /// [`SinkProducer::next_seq`]). nsqd answers publishes in order, so each response is
+    /// yielded as `(seq, result)` for the message it acknowledges.
+    pub fn into_sink_with_receipts(self, topic: impl Into<String>) -> (SinkProducer, Receipts, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, handler) = self.spawn_sink(topic.into(), Some(tx));
+        (sink, Receipts { rx }, handler)
+    }
+
+    fn spawn_sink(self, topic: String, receipts: Option<mpsc::UnboundedSender<(Seq, Result<(), NsqError>)>>)
+        -> (SinkProducer, tokio::task::JoinHandle<()>)
+    {
         let (tx, rx) = futures::channel::oneshot::channel();
+        // Responses of cancelled requests arrive before those of the sink's messages
+        let mut stale = self.pending_responses;
+        let span = self.conn.span().clone();
         let (sink, mut stream) = self.conn.split();
         let handler = tokio::spawn(async move {
             debug!("read loop");
+            let mut seq: Seq = 0;
             while let Some(res) = stream.next().await {
-                match res {
+                let (receipt, error) = match res {
                     Ok(Response::Ok) => {
                         debug!("Response Ok");
-                        continue;
+                        (Ok(()), None)
                     }
-                    Ok(Response::Msg(_)) => {
+                    Ok(Response::Msg(_)) | Ok(Response::Invalid { .. }) => {
                         unreachable!();
                     }
+                    Ok(Response::CloseWait) => {
+                        debug!("closed by nsqd");
+                        let _ = tx.send(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                        break;
+                    }
                     Ok(Response::Err(e)) => {
                         debug!("Response err: {:?}", e);
-                        let _ = tx.send(e.into());
-                        break;
+                        (Err(e.clone()), Some(e.into()))
+                    }
+                    Err(Error::NsqError(e)) => {
+                        debug!("Response err: {:?}", e);
+                        (Err(e.clone()), Some(Error::NsqError(e)))
                     }
                     Err(e) => {
                         debug!("rx err: {:?}", e);
                         let _ = tx.send(e);
                         break;
                     }
+                };
+                if stale > 0 {
+                    debug!("discard stale response");
+                    stale -= 1;
+                    continue;
+                }
+                if let Some(ref receipts) = receipts {
+                    let _ = receipts.unbounded_send((seq, receipt));
+                }
+                seq += 1;
+                if let Some(e) = error {
+                    let _ = tx.send(e);
+                    break;
                 }
             }
             debug!("exit read loop");
-        });
+        }.instrument(span));
 
         (SinkProducer {
-            topic: topic.into(),
+            topic,
             sink,
             state: Some(rx),
+            seq: 0,
         }, handler)
     }
 }
 
 impl SinkProducer {
+    /// The sequence number the next message sent through this sink will get.
+    pub fn next_seq(&self) -> Seq {
+        self.seq
+    }
+
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
         match self.state {
             None => return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())),
@@ -145,7 +344,9 @@ where
     fn start_send(mut self: Pin<&mut Self>, item: S) -> Result<(), Self::Error> {
         let topic = self.topic.clone();
         let item = Command::Pub(topic, item.into());
-        Pin::new(&mut self.sink).start_send(item)
+        Pin::new(&mut self.sink).start_send(item)?;
+        self.seq += 1;
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
@@ -159,6 +360,91 @@ where
     }
 }
 
+/// A producer which hedges slow publishes onto a second nsqd.
+///
+/// Every message is first published to the primary node. If it is not acknowledged within
+/// `hedge_after`, the same message is also published to the secondary node and whichever
+/// acknowledges first wins.
+///
+/// This trades delivery guarantees for latency: a hedged message is very likely to be
+/// delivered twice (once per node), so consumers must be idempotent. Only use it for
+/// latency-sensitive producers that can tolerate duplicates.
+pub struct HedgedProducer {
+    primary: Producer,
+    secondary: Producer,
+    hedge_after: Duration,
+}
+
+impl HedgedProducer {
+    pub fn new(primary: Producer, secondary: Producer, hedge_after: Duration) -> Self {
+        Self { primary, secondary, hedge_after }
+    }
+
+    /// Connect to two of the nodes `discovery` finds having `topic`, picked at random, the
+    /// first as the primary. Nodes which fail to connect are skipped, it fails unless two do.
+    pub async fn discovered<D: Discovery>(
+        discovery: &D,
+        topic: &str,
+        config: impl Into<ProducerConfig>,
+        hedge_after: Duration,
+    ) -> Result<Self, Error> {
+        let config = config.into();
+        let mut nodes = discovery.discover(topic).await?;
+        fastrand::shuffle(&mut nodes);
+        let mut connected = Vec::with_capacity(2);
+        let mut last_err = None;
+        for node in nodes {
+            let config = ProducerConfig { http_port: node.http_port, ..config.clone() };
+            match Producer::connect((node.host.as_str(), node.tcp_port), config).await {
+                Ok(producer) => connected.push(producer),
+                Err(e) => {
+                    debug!("failed to connect to {}: {}", node.tcp_address(), e);
+                    last_err = Some(e);
+                }
+            }
+            if connected.len() == 2 {
+                break;
+            }
+        }
+        match (connected.pop(), connected.pop()) {
+            (Some(secondary), Some(primary)) => Ok(Self::new(primary, secondary, hedge_after)),
+            _ => Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("fewer than two nsqd have topic {}", topic)).into()
+            })),
+        }
+    }
+
+    /// Publish a message to a topic, hedging on the secondary node if the primary is slow.
+    ///
+    /// An error is only returned if both nodes fail.
+    pub async fn publish(&mut self, topic: impl Into<String>, msg: impl Into<MessageBody>) -> Result<(), Error> {
+        let topic = topic.into();
+        let msg = msg.into();
+
+        let primary = self.primary.publish(topic.clone(), msg.clone());
+        futures::pin_mut!(primary);
+        if let Ok(res) = tokio::time::timeout(self.hedge_after, &mut primary).await {
+            return res;
+        }
+
+        debug!("publish not acknowledged within {:?}, hedging on the secondary node", self.hedge_after);
+        let secondary = self.secondary.publish(topic, msg);
+        futures::pin_mut!(secondary);
+        // The loser is dropped, its late response is discarded by the next publish
+        match future::select(primary, secondary).await {
+            future::Either::Left((Ok(()), _)) | future::Either::Right((Ok(()), _)) => Ok(()),
+            future::Either::Left((Err(e), other)) | future::Either::Right((Err(e), other)) => {
+                debug!("hedged publish failed on one node: {}", e);
+                other.await
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> (Producer, Producer) {
+        (self.primary, self.secondary)
+    }
+}
+
 pub struct PublishProducer {
     inner: Connection,
 }
@@ -173,7 +459,7 @@ impl Stream for PublishProducer {
     type Item = Result<Response, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.inner.0).poll_next(cx)
+        Pin::new(&mut self.inner).poll_next(cx)
     }
 }
 
@@ -181,18 +467,184 @@ impl Sink<(String, MessageBody)> for PublishProducer {
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.inner.0).poll_ready(cx)
+        Pin::new(&mut self.inner.transport).poll_ready(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, (topic, msg): (String, MessageBody)) -> Result<(), Self::Error> {
-        Pin::new(&mut self.inner.0).start_send(Command::Pub(topic, msg))
+        self.inner.check_publish(&topic)?;
+        Pin::new(&mut self.inner.transport).start_send(Command::Pub(topic, msg))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.inner.0).poll_flush(cx)
+        Pin::new(&mut self.inner.transport).poll_flush(cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.inner.0).poll_close(cx)
+        Pin::new(&mut self.inner.transport).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use futures::prelude::*;
+    use tokio::sync::oneshot;
+
+    use crate::config::Config;
+    use crate::discovery::{NsqdAddr, StaticDiscovery};
+    use crate::error::Error;
+    use crate::testing;
+    use crate::command::Command;
+    use crate::conn::{Connection, Response};
+    use super::{Producer, HedgedProducer, PublishProducer};
+
+    fn is_timeout(e: &Error) -> bool {
+        matches!(e, Error::IoError(e) if e.kind() == io::ErrorKind::TimedOut)
+    }
+
+    #[tokio::test]
+    async fn multi_publish_iter_sends_one_mpub() {
+        let addr = testing::serve(|mut conn| async move {
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!((cmd.name.as_str(), cmd.params.as_slice()), ("MPUB", &["test".to_string()][..]));
+            assert_eq!(cmd.body.as_deref(), Some(&b"\0\0\0\x02\0\0\0\x01a\0\0\0\x02bc"[..]));
+            conn.write_ok().await
+        }).await;
+
+        let mut producer = Producer::connect(addr, &Config::default()).await.unwrap();
+        let msgs = [&b"a"[..], b"bc"].into_iter().map(bytes::Bytes::from_static);
+        producer.multi_publish_iter("test", msgs).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_producer_refuses_subscribed_connections() {
+        let addr = testing::serve(|mut conn| async move {
+            assert_eq!(conn.read_command().await?.unwrap().name, "SUB");
+            conn.write_ok().await?;
+            assert!(conn.read_command().await?.is_none());
+            Ok(())
+        }).await;
+
+        let mut conn = Connection::connect(addr, &Config::default()).await.unwrap();
+        conn.send(Command::Sub("test".into(), "channel".into())).await.unwrap();
+        assert!(matches!(conn.receive().await.unwrap(), Response::Ok));
+        let mut producer = PublishProducer::from(conn);
+        let res = producer.send(("test".to_string(), b"msg".to_vec())).await;
+        assert!(matches!(res, Err(Error::InvalidCommand(_))), "{:?}", res);
+    }
+
+    #[tokio::test]
+    async fn cancelled_publish_response_is_not_reused() {
+        let (release_tx, release_rx) = oneshot::channel();
+        let addr = testing::serve(|mut conn| async move {
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!((cmd.name.as_str(), cmd.params.as_slice()), ("PUB", &["test".to_string()][..]));
+            assert_eq!(cmd.body.as_deref(), Some(&b"first"[..]));
+            // Only acknowledge the first publish after the client gave up on it
+            release_rx.await.unwrap();
+            conn.write_ok().await?;
+
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!(cmd.body.as_deref(), Some(&b"second"[..]));
+            conn.write_error("E_PUB_FAILED publish failed").await
+        }).await;
+
+        let mut producer = Producer::connect(addr, &Config::default()).await.unwrap();
+        let err = producer.publish_timeout("test", "first", Duration::from_millis(50)).await.unwrap_err();
+        assert!(is_timeout(&err), "{:?}", err);
+        release_tx.send(()).unwrap();
+
+        // The stale OK of the first publish must not acknowledge the second one
+        let err = producer.publish("test", "second").await.unwrap_err();
+        assert!(matches!(err, Error::NsqError(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn publish_stays_in_step_after_cancellation() {
+        let addr = testing::serve(|mut conn| async move {
+            let mut bodies = vec![];
+            while let Some(cmd) = conn.read_command().await? {
+                bodies.push(cmd.body.unwrap());
+                if bodies.len() == 1 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                conn.write_ok().await?;
+            }
+            assert_eq!(bodies, vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+            Ok(())
+        }).await;
+
+        let mut producer = Producer::connect(addr, &Config::default()).await.unwrap();
+        let err = producer.publish_timeout("test", "first", Duration::from_millis(10)).await.unwrap_err();
+        assert!(is_timeout(&err), "{:?}", err);
+
+        producer.publish("test", "second").await.unwrap();
+        assert_eq!(producer.pending_responses, 0);
+        producer.publish("test", "third").await.unwrap();
+        assert_eq!(producer.pending_responses, 0);
+    }
+
+    #[tokio::test]
+    async fn hedged_publish_falls_back_to_secondary() {
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let slow = testing::serve(|mut conn| async move {
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!(cmd.body.as_deref(), Some(&b"hedged"[..]));
+            // Never acknowledge until the test is done
+            let _ = done_rx.await;
+            Ok(())
+        }).await;
+        let fast = testing::serve(|mut conn| async move {
+            let cmd = conn.read_command().await?.unwrap();
+            assert_eq!(cmd.body.as_deref(), Some(&b"hedged"[..]));
+            conn.write_ok().await
+        }).await;
+
+        let config = Config::default();
+        let primary = Producer::connect(slow, &config).await.unwrap();
+        let secondary = Producer::connect(fast, &config).await.unwrap();
+        let mut producer = HedgedProducer::new(primary, secondary, Duration::from_millis(20));
+        producer.publish("test", "hedged").await.unwrap();
+        let _ = done_tx.send(());
+
+        // Or both nodes found by discovery
+        let mut nodes = Vec::new();
+        for _ in 0..2 {
+            let addr = testing::serve(|mut conn| async move {
+                assert!(conn.read_command().await?.is_none());
+                Ok(())
+            }).await;
+            nodes.push(NsqdAddr::new("127.0.0.1", addr.port()));
+        }
+        let discovery = StaticDiscovery::new([]);
+        assert!(HedgedProducer::discovered(&discovery, "test", &config, Duration::from_millis(20)).await.is_err());
+        let discovery = StaticDiscovery::new(nodes);
+        HedgedProducer::discovered(&discovery, "test", &config, Duration::from_millis(20)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sink_receipts_are_numbered_in_order() {
+        let addr = testing::serve(|mut conn| async move {
+            for _ in 0..2 {
+                conn.read_command().await?.unwrap();
+                conn.write_ok().await?;
+            }
+            conn.read_command().await?.unwrap();
+            conn.write_error("E_BAD_MESSAGE PUB invalid message body size 0").await
+        }).await;
+
+        let producer = Producer::connect(addr, &Config::default()).await.unwrap();
+        let (mut sink, receipts, handler) = producer.into_sink_with_receipts("test");
+        for msg in ["one", "two", "three"] {
+            sink.feed(msg).await.unwrap();
+        }
+        assert_eq!(sink.next_seq(), 3);
+        futures::SinkExt::<&str>::flush(&mut sink).await.unwrap();
+
+        let receipts = receipts.collect::<Vec<_>>().await;
+        handler.await.unwrap();
+        let receipts = receipts.into_iter().map(|(seq, res)| (seq, res.is_ok())).collect::<Vec<_>>();
+        assert_eq!(receipts, vec![(0, true), (1, true), (2, false)]);
     }
 }
@@ -0,0 +1,20 @@
+//! The NSQ wire protocol on its own, for proxies, traffic replayers and other tooling.
+//!
+//! [`NsqCodec`] encodes [`Command`]s and decodes frames as nsqd sends them, typically used
+//! through `tokio_util::codec::Framed` on a TCP stream. Unlike [`Connection`](crate::Connection),
+//! nothing here negotiates features or answers heartbeats: `Command::Version` has to be
+//! sent first, and each `_heartbeat_` response answered with `Command::Nop`.
+//!
+//! Frames are laid out as a 4 byte size, a 4 byte frame type, one of the `FRAME_TYPE_*`
+//! constants, and the data. See the [protocol spec](https://nsq.io/clients/tcp_protocol_spec.html).
+
+pub use crate::codec::{
+    NsqCodec,
+    NsqFramed,
+    NsqMsg,
+    RawResponse,
+    FRAME_TYPE_RESPONSE,
+    FRAME_TYPE_ERROR,
+    FRAME_TYPE_MESSAGE,
+};
+pub use crate::command::{Command, MessageBody, MessageId};
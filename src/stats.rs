@@ -0,0 +1,179 @@
+//! nsqd's `/stats` endpoint, typed, to watch backlogs from code.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), nsq_in_rust::Error> {
+//! let stats = nsq_in_rust::stats::NsqdStats::fetch("127.0.0.1:4151").await?;
+//! for topic in &stats.topics {
+//!     for channel in &topic.channels {
+//!         println!("{}/{}: {} queued", topic.topic_name, channel.channel_name, channel.depth);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Fields missing from older nsqd versions take their defaults.
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::http::{Client, Method};
+
+/// What `/stats?format=json` returns.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NsqdStats {
+    pub version: String,
+    pub health: String,
+    /// Unix time in seconds
+    pub start_time: i64,
+    pub topics: Vec<TopicStats>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TopicStats {
+    pub topic_name: String,
+    pub channels: Vec<ChannelStats>,
+    /// Messages queued, in memory and on disk
+    pub depth: i64,
+    /// Messages queued on disk
+    pub backend_depth: i64,
+    pub message_count: u64,
+    pub message_bytes: u64,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChannelStats {
+    pub channel_name: String,
+    /// Messages queued, in memory and on disk
+    pub depth: i64,
+    /// Messages queued on disk
+    pub backend_depth: i64,
+    pub in_flight_count: u64,
+    pub deferred_count: u64,
+    pub message_count: u64,
+    pub requeue_count: u64,
+    pub timeout_count: u64,
+    pub client_count: u64,
+    pub clients: Vec<ClientStats>,
+    pub paused: bool,
+}
+
+/// A consumer connected to a channel.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientStats {
+    pub client_id: String,
+    pub hostname: String,
+    pub version: String,
+    pub remote_address: String,
+    pub user_agent: String,
+    pub state: i32,
+    pub ready_count: i64,
+    pub in_flight_count: u64,
+    pub message_count: u64,
+    pub finish_count: u64,
+    pub requeue_count: u64,
+    /// Unix time in seconds
+    pub connect_ts: i64,
+    pub sample_rate: i32,
+    pub deflate: bool,
+    pub snappy: bool,
+    pub tls: bool,
+    pub authed: bool,
+}
+
+impl NsqdStats {
+    /// Fetch the stats of every topic from the nsqd HTTP API at `http_addr`, e.g.
+    /// `"127.0.0.1:4151"`.
+    pub async fn fetch(http_addr: impl AsRef<str>) -> Result<NsqdStats> {
+        Self::get(http_addr.as_ref(), &[]).await
+    }
+
+    /// Fetch the stats of `topic` alone.
+    pub async fn fetch_topic(http_addr: impl AsRef<str>, topic: impl AsRef<str>) -> Result<NsqdStats> {
+        Self::get(http_addr.as_ref(), &[("topic", topic.as_ref())]).await
+    }
+
+    async fn get(http_addr: &str, query: &[(&str, &str)]) -> Result<NsqdStats> {
+        let client = Client::new(crate::lookup::DEFAULT_TIMEOUT, None)?;
+        let url = Url::parse(&format!("http://{}/stats?format=json", http_addr))?;
+        let resp = client.send(Method::Get, url, query, None).await?;
+        if !resp.is_success() {
+            return Err(Error::UnknownError(format!("stats of {} failed: {}", http_addr, resp.status)));
+        }
+        Self::parse(&resp.body)
+    }
+
+    /// Parse a `/stats?format=json` body, also in the `{"data": ...}` envelope of nsqd
+    /// before 1.0.
+    pub fn parse(body: &[u8]) -> Result<NsqdStats> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Body {
+            Enveloped { data: NsqdStats },
+            Bare(NsqdStats),
+        }
+        match serde_json::from_slice(body)? {
+            Body::Enveloped { data } | Body::Bare(data) => Ok(data),
+        }
+    }
+
+    pub fn topic(&self, name: &str) -> Option<&TopicStats> {
+        self.topics.iter().find(|topic| topic.topic_name == name)
+    }
+}
+
+impl TopicStats {
+    pub fn channel(&self, name: &str) -> Option<&ChannelStats> {
+        self.channels.iter().find(|channel| channel.channel_name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NsqdStats;
+
+    // Trimmed from nsqd 1.2.1
+    const STATS: &str = r#"{
+        "version": "1.2.1", "health": "OK", "start_time": 1700000000,
+        "topics": [{
+            "topic_name": "events", "depth": 12, "backend_depth": 2, "message_count": 100,
+            "message_bytes": 4096, "paused": false, "e2e_processing_latency": {"count": 0, "percentiles": null},
+            "channels": [{
+                "channel_name": "billing", "depth": 7, "backend_depth": 0, "in_flight_count": 3,
+                "deferred_count": 1, "message_count": 90, "requeue_count": 4, "timeout_count": 2,
+                "client_count": 1, "paused": false,
+                "clients": [{
+                    "client_id": "web-3:4120", "hostname": "web-3", "version": "V2",
+                    "remote_address": "10.0.0.3:50000", "state": 3, "ready_count": 10,
+                    "in_flight_count": 3, "message_count": 90, "finish_count": 83, "requeue_count": 4,
+                    "connect_ts": 1700000100, "sample_rate": 0, "deflate": false, "snappy": true,
+                    "user_agent": "nsq-rust/0.1.0", "tls": true, "tls_cipher_suite": "TLS_AES_128_GCM_SHA256"
+                }]
+            }]
+        }],
+        "memory": {"heap_objects": 1000}
+    }"#;
+
+    #[test]
+    fn stats_are_parsed() {
+        let stats = NsqdStats::parse(STATS.as_bytes()).unwrap();
+        let topic = stats.topic("events").unwrap();
+        assert_eq!((topic.depth, topic.backend_depth, topic.message_count), (12, 2, 100));
+        let channel = topic.channel("billing").unwrap();
+        assert_eq!((channel.depth, channel.in_flight_count, channel.deferred_count), (7, 3, 1));
+        assert_eq!((channel.requeue_count, channel.timeout_count), (4, 2));
+        let client = &channel.clients[0];
+        assert_eq!((client.client_id.as_str(), client.ready_count, client.finish_count), ("web-3:4120", 10, 83));
+        assert!(client.snappy && client.tls && !client.authed);
+
+        let enveloped = format!(r#"{{"status_code": 200, "status_txt": "OK", "data": {}}}"#, STATS);
+        assert_eq!(NsqdStats::parse(enveloped.as_bytes()).unwrap().topics[0].channels[0].depth, 7);
+        assert!(NsqdStats::parse(br#"{"topics": 3}"#).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Reproducible message traffic for tests and benchmarks.
+//!
+//! A [`MessageGenerator`] seeded with the same value always yields the same topics and
+//! bodies, so runs can be compared. A [`LoadDriver`] paces a generator into a stream, which
+//! can drive a `Producer` or be written to a mock nsqd connection to benchmark consumers.
+
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use futures::stream::{self, Stream};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+/// How big generated message bodies are.
+#[derive(Debug, Clone)]
+pub enum MessageSize {
+    Fixed(usize),
+    Uniform(RangeInclusive<usize>),
+    /// Sizes picked with the given relative weights
+    Weighted(Vec<(usize, u32)>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedMessage {
+    /// Position of the message in the generated sequence, starting from 0
+    pub seq: u64,
+    pub topic: String,
+    pub body: Vec<u8>,
+}
+
+/// Deterministic generator of messages, see the module docs.
+#[derive(Debug, Clone)]
+pub struct MessageGenerator {
+    rng: fastrand::Rng,
+    size: MessageSize,
+    topics: Vec<String>,
+    seq: u64,
+}
+
+impl MessageGenerator {
+    /// 128 byte messages to the `test` topic.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: fastrand::Rng::with_seed(seed),
+            size: MessageSize::Fixed(128),
+            topics: vec!["test".to_string()],
+            seq: 0,
+        }
+    }
+
+    pub fn with_size(mut self, size: MessageSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Spread messages evenly over `topics`, which must not be empty.
+    pub fn with_topics<I, S>(mut self, topics: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.topics = topics.into_iter().map(Into::into).collect();
+        assert!(!self.topics.is_empty(), "no topics to generate messages for");
+        self
+    }
+
+    fn next_size(&mut self) -> usize {
+        match self.size {
+            MessageSize::Fixed(size) => size,
+            MessageSize::Uniform(ref range) => self.rng.usize(range.clone()),
+            MessageSize::Weighted(ref sizes) => {
+                let total: u32 = sizes.iter().map(|(_, weight)| weight).sum();
+                let mut pick = self.rng.u32(0..total.max(1));
+                for &(size, weight) in sizes {
+                    if pick < weight {
+                        return size;
+                    }
+                    pick -= weight;
+                }
+                0
+            }
+        }
+    }
+}
+
+impl Iterator for MessageGenerator {
+    type Item = GeneratedMessage;
+
+    fn next(&mut self) -> Option<GeneratedMessage> {
+        let size = self.next_size();
+        let topic = self.topics[self.rng.usize(..self.topics.len())].clone();
+        // Printable, so bodies can be read in logs and compress like typical payloads
+        let body = (0..size).map(|_| self.rng.alphanumeric() as u8).collect();
+        let seq = self.seq;
+        self.seq += 1;
+        Some(GeneratedMessage { seq, topic, body })
+    }
+}
+
+/// Paces a [`MessageGenerator`] at a fixed rate.
+#[derive(Debug)]
+pub struct LoadDriver {
+    generator: MessageGenerator,
+    count: usize,
+    rate: Option<u32>,
+}
+
+impl LoadDriver {
+    /// Drive `count` messages as fast as they are consumed.
+    pub fn new(generator: MessageGenerator, count: usize) -> Self {
+        Self { generator, count, rate: None }
+    }
+
+    /// Yield at most `per_second` messages per second on average. Late messages are sent in
+    /// a burst to catch up instead of lowering the rate.
+    pub fn with_rate(mut self, per_second: u32) -> Self {
+        self.rate = Some(per_second.max(1));
+        self
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = GeneratedMessage> {
+        let interval = self.rate.map(|rate| {
+            let mut interval = time::interval_at(Instant::now(), Duration::from_secs(1) / rate);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+            interval
+        });
+        let messages = self.generator.take(self.count);
+        stream::unfold((messages, interval), |(mut messages, mut interval): (_, Option<Interval>)| async move {
+            let msg = messages.next()?;
+            if let Some(ref mut interval) = interval {
+                interval.tick().await;
+            }
+            Some((msg, (messages, interval)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::StreamExt;
+    use super::{LoadDriver, MessageGenerator, MessageSize};
+
+    #[test]
+    fn same_seed_same_messages() {
+        let generator = MessageGenerator::new(42)
+            .with_size(MessageSize::Weighted(vec![(16, 3), (1024, 1)]))
+            .with_topics(["a", "b", "c"]);
+        let first = generator.clone().take(100).collect::<Vec<_>>();
+        assert_eq!(first, generator.take(100).collect::<Vec<_>>());
+        assert!(first.iter().all(|m| m.body.len() == 16 || m.body.len() == 1024));
+        assert_eq!(first.last().unwrap().seq, 99);
+    }
+
+    #[tokio::test]
+    async fn driver_paces_messages() {
+        let start = Instant::now();
+        let sent = LoadDriver::new(MessageGenerator::new(1), 11)
+            .with_rate(500)
+            .into_stream()
+            .count()
+            .await;
+        assert_eq!(sent, 11);
+        // The first message goes out right away, the other ten 2ms apart
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
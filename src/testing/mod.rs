@@ -0,0 +1,238 @@
+//! Test support, enabled by the `testing` feature.
+//!
+//! A scriptable in-process stand-in for nsqd: `serve` binds a local listener, accepts a
+//! single client, answers the `V2` magic and `IDENTIFY`, then hands the connection over to
+//! the test's handler. The [`load`] module generates reproducible traffic to drive
+//! producers or feed the mock, and [`vectors`] has the protocol's golden bytes.
+//! `serve_lookupd` stands in for nsqlookupd's HTTP API.
+
+use std::io;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::{BufMut, BytesMut};
+use serde_json::{json, Value as JsonValue};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}};
+
+pub mod load;
+pub mod vectors;
+
+pub const FRAME_TYPE_RESPONSE: i32 = 0;
+pub const FRAME_TYPE_ERROR:    i32 = 1;
+pub const FRAME_TYPE_MESSAGE:  i32 = 2;
+
+/// A command as received by the mock server.
+#[derive(Debug)]
+pub struct MockCommand {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+pub struct MockConn {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+/// The IDENTIFY response of a plain, uncompressed nsqd without auth.
+pub fn identify_response() -> JsonValue {
+    json!({
+        "max_rdy_count": 2500,
+        "version": "1.2.1",
+        "max_msg_timeout": 900000,
+        "msg_timeout": 60000,
+        "tls_v1": false,
+        "deflate": false,
+        "deflate_level": 6,
+        "max_deflate_level": 6,
+        "snappy": false,
+        "sample_rate": 0,
+        "auth_required": false,
+        "output_buffer_size": 16384,
+        "output_buffer_timeout": 250,
+    })
+}
+
+/// Serve a single connection with the default IDENTIFY response.
+pub async fn serve<F, Fut>(handler: F) -> SocketAddr
+where
+    F: FnOnce(MockConn) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<()>> + Send,
+{
+    serve_with(identify_response(), handler).await
+}
+
+/// Serve a single connection, answering IDENTIFY with `identify`.
+pub async fn serve_with<F, Fut>(identify: JsonValue, handler: F) -> SocketAddr
+where
+    F: FnOnce(MockConn) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<()>> + Send,
+{
+    serve_raw(|mut conn| async move {
+        conn.handshake(&identify).await?;
+        handler(conn).await
+    }).await
+}
+
+/// Serve a single connection, leaving the `V2` magic and IDENTIFY to the handler.
+pub async fn serve_raw<F, Fut>(handler: F) -> SocketAddr
+where
+    F: FnOnce(MockConn) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<()>> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        handler(MockConn::new(socket)).await
+    });
+    addr
+}
+
+/// Serve nsqlookupd's HTTP API at the returned base URL, answering each request with the
+/// next of `answers`, the last one repeated.
+pub async fn serve_lookupd(answers: Vec<String>) -> String {
+    serve_lookupd_with(answers.into_iter().map(|body| (200, body)).collect()).await
+}
+
+/// Like `serve_lookupd`, with the status of each answer.
+pub async fn serve_lookupd_with(answers: Vec<(u16, String)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let answers = Arc::new(answers);
+    let served = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let (answers, served) = (answers.clone(), served.clone());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                // One request per read is enough for the small requests of the HTTP client
+                while socket.read(&mut buf).await? > 0 {
+                    let n = served.fetch_add(1, Ordering::SeqCst).min(answers.len() - 1);
+                    let (status, ref body) = answers[n];
+                    let resp = format!("HTTP/1.1 {} -\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body);
+                    socket.write_all(resp.as_bytes()).await?;
+                }
+                Ok::<_, io::Error>(())
+            });
+        }
+    });
+    url
+}
+
+/// A `/lookup` answer listing an nsqd, on the default ports, at each of `addrs`.
+pub fn lookup_response(addrs: &[&str]) -> String {
+    let producers: Vec<_> = addrs.iter().map(|addr| json!({
+        "broadcast_address": addr,
+        "hostname": addr,
+        "remote_address": format!("{}:50000", addr),
+        "tcp_port": 4150,
+        "http_port": 4151,
+        "version": "1.2.1",
+    })).collect();
+    json!({"channels": ["c"], "producers": producers}).to_string()
+}
+
+/// Encode a frame the way nsqd puts it on the wire.
+pub fn frame(frame_type: i32, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(data.len() + 8);
+    buf.put_u32(data.len() as u32 + 4);
+    buf.put_i32(frame_type);
+    buf.put(data);
+    buf
+}
+
+/// Encode a message frame.
+pub fn message_frame(id: &[u8; 16], attempts: u16, body: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(body.len() + 26);
+    buf.put_u64(0);
+    buf.put_u16(attempts);
+    buf.put(&id[..]);
+    buf.put(body);
+    frame(FRAME_TYPE_MESSAGE, &buf)
+}
+
+impl MockConn {
+    fn new(socket: TcpStream) -> Self {
+        let (reader, writer) = socket.into_split();
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    async fn handshake(&mut self, identify: &JsonValue) -> io::Result<()> {
+        self.read_magic().await?;
+        let cmd = self.read_command().await?.expect("IDENTIFY");
+        assert_eq!(cmd.name, "IDENTIFY");
+        self.write_response(identify.to_string().as_bytes()).await
+    }
+
+    pub async fn read_magic(&mut self) -> io::Result<()> {
+        let mut magic = [0u8; 4];
+        self.reader.read_exact(&mut magic).await?;
+        assert_eq!(&magic, b"  V2");
+        Ok(())
+    }
+
+    /// Read the next command, `None` on EOF.
+    pub async fn read_command(&mut self) -> io::Result<Option<MockCommand>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.trim_end().split(' ').map(String::from);
+        let name = parts.next().unwrap_or_default();
+        let params = parts.collect();
+        let body = match name.as_str() {
+            "IDENTIFY" | "PUB" | "DPUB" | "MPUB" | "AUTH" => {
+                let len = self.reader.read_u32().await? as usize;
+                let mut body = vec![0u8; len];
+                self.reader.read_exact(&mut body).await?;
+                Some(body)
+            }
+            _ => None,
+        };
+        Ok(Some(MockCommand { name, params, body }))
+    }
+
+    /// Read whatever raw bytes arrive next, 0 on EOF.
+    pub async fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf).await
+    }
+
+    /// Read raw bytes until the client disconnects.
+    pub async fn read_to_end(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.reader.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Write `data` as is, in a single write.
+    pub async fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data).await
+    }
+
+    pub async fn write_frame(&mut self, frame_type: i32, data: &[u8]) -> io::Result<()> {
+        self.write_raw(&frame(frame_type, data)).await
+    }
+
+    pub async fn write_response(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(FRAME_TYPE_RESPONSE, data).await
+    }
+
+    pub async fn write_ok(&mut self) -> io::Result<()> {
+        self.write_response(b"OK").await
+    }
+
+    pub async fn write_error(&mut self, error: &str) -> io::Result<()> {
+        self.write_frame(FRAME_TYPE_ERROR, error.as_bytes()).await
+    }
+
+    pub async fn write_message(&mut self, id: &[u8; 16], attempts: u16, body: &[u8]) -> io::Result<()> {
+        self.write_raw(&message_frame(id, attempts, body)).await
+    }
+}
@@ -0,0 +1,134 @@
+//! The TCP protocol byte for byte, see the
+//! [spec](https://nsq.io/clients/tcp_protocol_spec.html).
+//!
+//! [`commands`] and [`frames`] are golden vectors, written out by hand from the spec rather
+//! than produced by this crate, for other implementations to check against too.
+//! [`decode_command`] reads commands the way nsqd does, to check what a client sends.
+
+use bytes::{Buf, BytesMut};
+use serde_json::json;
+
+use crate::command::{Command, MessageBody};
+
+/// Every command with its exact encoding.
+pub fn commands() -> Vec<(Command, &'static [u8])> {
+    let id = || "0123456789abcdef".parse().unwrap();
+    vec![
+        (Command::Version, b"  V2"),
+        (Command::Identify(json!({"client_id": "c"})), b"IDENTIFY\n\0\0\0\x11{\"client_id\":\"c\"}"),
+        (Command::Sub("t".into(), "c#ephemeral".into()), b"SUB t c#ephemeral\n"),
+        (Command::Pub("t".into(), b"abc".to_vec()), b"PUB t\n\0\0\0\x03abc"),
+        (Command::Pub("t".into(), vec![]), b"PUB t\n\0\0\0\0"),
+        // Body size 4 + (4 + 2) + (4 + 3) = 17, then the count and each sized message
+        (
+            Command::Mpub("t".into(), vec![b"ab".to_vec(), b"cde".to_vec()]),
+            b"MPUB t\n\0\0\0\x11\0\0\0\x02\0\0\0\x02ab\0\0\0\x03cde",
+        ),
+        (Command::Mpub("t".into(), vec![]), b"MPUB t\n\0\0\0\x04\0\0\0\0"),
+        (Command::Dpub("t".into(), 100, b"abc".to_vec()), b"DPUB t 100\n\0\0\0\x03abc"),
+        (Command::Rdy(2500), b"RDY 2500\n"),
+        (Command::Fin(id()), b"FIN 0123456789abcdef\n"),
+        (Command::Req(id(), 5000), b"REQ 0123456789abcdef 5000\n"),
+        (Command::Touch(id()), b"TOUCH 0123456789abcdef\n"),
+        (Command::Close, b"CLS\n"),
+        (Command::Nop, b"NOP\n"),
+        (Command::Auth("secret".into()), b"AUTH\n\0\0\0\x06secret"),
+    ]
+}
+
+/// Frames nsqd sends, named after what they hold.
+pub fn frames() -> Vec<(&'static str, &'static [u8])> {
+    vec![
+        ("OK", b"\0\0\0\x06\0\0\0\0OK"),
+        ("_heartbeat_", b"\0\0\0\x0f\0\0\0\0_heartbeat_"),
+        ("CLOSE_WAIT", b"\0\0\0\x0e\0\0\0\0CLOSE_WAIT"),
+        ("{\"max_rdy_count\":2500}", b"\0\0\0\x1a\0\0\0\0{\"max_rdy_count\":2500}"),
+        ("E_INVALID cannot FIN", b"\0\0\0\x18\0\0\0\x01E_INVALID cannot FIN"),
+        // Timestamp 1.5s after the epoch in nanoseconds, 2 attempts
+        (
+            "message 0123456789abcdef hi",
+            b"\0\0\0\x20\0\0\0\x02\0\0\0\0\x59\x68\x2f\0\0\x020123456789abcdefhi",
+        ),
+    ]
+}
+
+/// Decode the next command from `buf`, `Ok(None)` if it isn't complete yet.
+///
+/// Errors are what nsqd would reject: an unknown command, wrong parameters, or a body whose
+/// size doesn't match its content.
+pub fn decode_command(buf: &mut BytesMut) -> Result<Option<Command>, String> {
+    if buf.starts_with(b"  V2") {
+        buf.advance(4);
+        return Ok(Some(Command::Version));
+    }
+    let line_len = match buf.iter().position(|&b| b == b'\n') {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let line = std::str::from_utf8(&buf[..line_len]).map_err(|e| e.to_string())?.to_string();
+    let mut params = line.split(' ');
+    let name = params.next().unwrap_or_default();
+    let params: Vec<&str> = params.collect();
+
+    let has_body = matches!(name, "IDENTIFY" | "PUB" | "MPUB" | "DPUB" | "AUTH");
+    let body = if has_body {
+        let rest = &buf[line_len + 1..];
+        if rest.len() < 4 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + size {
+            return Ok(None);
+        }
+        let body = rest[4..4 + size].to_vec();
+        buf.advance(line_len + 1 + 4 + size);
+        body
+    } else {
+        buf.advance(line_len + 1);
+        vec![]
+    };
+
+    let param = |i: usize| params.get(i).map(|p| p.to_string()).ok_or(format!("{} without parameter {}", name, i));
+    let number = |i: usize| param(i)?.parse::<u64>().map_err(|e| e.to_string());
+    let id = || param(0)?.parse().map_err(|e: crate::Error| e.to_string());
+    let cmd = match (name, params.len()) {
+        ("IDENTIFY", 0) => Command::Identify(serde_json::from_slice(&body).map_err(|e| e.to_string())?),
+        ("SUB", 2) => Command::Sub(param(0)?, param(1)?),
+        ("PUB", 1) => Command::Pub(param(0)?, body),
+        ("MPUB", 1) => Command::Mpub(param(0)?, decode_messages(&body)?),
+        ("DPUB", 2) => Command::Dpub(param(0)?, number(1)?, body),
+        ("RDY", 1) => Command::Rdy(number(0)?),
+        ("FIN", 1) => Command::Fin(id()?),
+        ("REQ", 2) => Command::Req(id()?, number(1)?),
+        ("TOUCH", 1) => Command::Touch(id()?),
+        ("CLS", 0) => Command::Close,
+        ("NOP", 0) => Command::Nop,
+        ("AUTH", 0) => Command::Auth(String::from_utf8(body).map_err(|e| e.to_string())?),
+        _ => return Err(format!("invalid command: {:?}", line)),
+    };
+    Ok(Some(cmd))
+}
+
+/// The messages of an MPUB body, after its size.
+fn decode_messages(mut body: &[u8]) -> Result<Vec<MessageBody>, String> {
+    if body.len() < 4 {
+        return Err("MPUB body without a message count".into());
+    }
+    let count = body.get_u32();
+    let mut msgs = Vec::new();
+    for _ in 0..count {
+        if body.len() < 4 {
+            return Err("MPUB message without a size".into());
+        }
+        let len = body.get_u32() as usize;
+        if body.len() < len {
+            return Err(format!("MPUB message of {} bytes with {} left", len, body.len()));
+        }
+        msgs.push(body[..len].to_vec());
+        body.advance(len);
+    }
+    if !body.is_empty() {
+        return Err(format!("MPUB body has {} bytes after its messages", body.len()));
+    }
+    Ok(msgs)
+}